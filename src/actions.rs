@@ -0,0 +1,589 @@
+use std::{collections::BTreeMap, fmt, fs, path::Path};
+
+use anyhow::Result;
+use bevy::{
+    input::{mouse::MouseWheel, InputSystem},
+    prelude::*,
+    utils::HashSet,
+};
+use bevy_egui::{egui, EguiContext};
+use serde::{Deserialize, Serialize};
+
+use crate::simulation::GameSpeed;
+
+// TODO
+// * gamepad support
+// * per context bindings, for example the same key could do something else in a menu
+
+const INPUT_CONFIG_PATH: &str = "config/input.ron";
+
+pub struct ActionsPlugin;
+
+impl Plugin for ActionsPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app.insert_resource(InputMap::load_or_default(INPUT_CONFIG_PATH))
+            .insert_resource(ActionState::default())
+            .insert_resource(Rebinding(None))
+            .insert_resource(KeyBindingsWindow { open: false })
+            .add_system_to_stage(
+                CoreStage::PreUpdate,
+                update_action_state
+                    .system()
+                    .label(ActionLabel::UpdateActions)
+                    .after(InputSystem),
+            )
+            .add_system_to_stage(
+                CoreStage::PreUpdate,
+                capture_binding.system().after(ActionLabel::UpdateActions),
+            )
+            .add_system(key_bindings_window.system());
+    }
+}
+
+#[derive(SystemLabel, Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ActionLabel {
+    /// Updates `ActionState` from the raw inputs, it runs before every system in `CoreStage::Update`
+    UpdateActions,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum Action {
+    CameraUp,
+    CameraDown,
+    CameraLeft,
+    CameraRight,
+    CameraFast,
+    /// Moves the camera with the cursor while held
+    DragCamera,
+    ZoomIn,
+    ZoomOut,
+    ZLevelUp,
+    ZLevelDown,
+    /// Moves to the z-level of the surface under the cursor
+    JumpToSurface,
+    RotateClockwise,
+    RotateCounterClockwise,
+    ToggleRenderMode,
+    Select,
+    ClearSelection,
+    /// Extends the selection across z-levels while held
+    ExtendSelection,
+    /// Applies the current tool to the selection again
+    Designate,
+    Pause,
+    SetSpeed(GameSpeed),
+    ToggleKeyBindings,
+    /// Shows the labors of every dwarf
+    ToggleLabors,
+    SaveBookmark(u8),
+    RecallBookmark(u8),
+    /// Picks the start or the goal of a path drawn by the debug overlay
+    DebugPath,
+    Quit,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum Modifier {
+    Shift,
+    Control,
+    Alt,
+}
+
+impl Modifier {
+    fn keys(&self) -> [KeyCode; 2] {
+        match self {
+            Modifier::Shift => [KeyCode::LShift, KeyCode::RShift],
+            Modifier::Control => [KeyCode::LControl, KeyCode::RControl],
+            Modifier::Alt => [KeyCode::LAlt, KeyCode::RAlt],
+        }
+    }
+
+    fn from_key(key: KeyCode) -> Option<Modifier> {
+        [Modifier::Shift, Modifier::Control, Modifier::Alt]
+            .iter()
+            .find(|modifier| modifier.keys().contains(&key))
+            .copied()
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Button {
+    Key(KeyCode),
+    Mouse(MouseButton),
+    WheelUp,
+    WheelDown,
+}
+
+/// A button with the modifiers that need to be held, like Ctrl+WheelUp
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Binding {
+    #[serde(default)]
+    pub modifiers: Vec<Modifier>,
+    pub button: Button,
+}
+
+impl Binding {
+    pub fn new(button: Button) -> Self {
+        Self {
+            modifiers: vec![],
+            button,
+        }
+    }
+
+    pub fn with_modifiers(button: Button, modifiers: &[Modifier]) -> Self {
+        let mut binding = Self {
+            modifiers: modifiers.to_vec(),
+            button,
+        };
+        binding.normalize();
+        binding
+    }
+
+    /// Sorts the modifiers so equal bindings compare equal
+    fn normalize(&mut self) {
+        self.modifiers.sort();
+        self.modifiers.dedup();
+    }
+
+    fn has_modifiers(&self, modifiers: &HashSet<Modifier>) -> bool {
+        self.modifiers
+            .iter()
+            .all(|modifier| modifiers.contains(modifier))
+    }
+}
+
+impl fmt::Display for Binding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for modifier in self.modifiers.iter() {
+            write!(f, "{:?}+", modifier)?;
+        }
+        match self.button {
+            Button::Key(key) => write!(f, "{:?}", key),
+            Button::Mouse(button) => write!(f, "Mouse {:?}", button),
+            Button::WheelUp => write!(f, "Wheel up"),
+            Button::WheelDown => write!(f, "Wheel down"),
+        }
+    }
+}
+
+/// Bindings of every action, it's loaded from the user's config file
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct InputMap {
+    pub bindings: BTreeMap<Action, Vec<Binding>>,
+}
+
+impl Default for InputMap {
+    fn default() -> Self {
+        use Action::*;
+        let key = |key| Binding::new(Button::Key(key));
+        let mouse = |button| Binding::new(Button::Mouse(button));
+        let bindings = vec![
+            (CameraUp, key(KeyCode::W)),
+            (CameraDown, key(KeyCode::S)),
+            (CameraLeft, key(KeyCode::A)),
+            (CameraRight, key(KeyCode::D)),
+            (CameraFast, key(KeyCode::LControl)),
+            (DragCamera, mouse(MouseButton::Middle)),
+            (ZoomIn, Binding::new(Button::WheelUp)),
+            (ZoomOut, Binding::new(Button::WheelDown)),
+            (
+                ZLevelUp,
+                Binding::with_modifiers(Button::WheelDown, &[Modifier::Control]),
+            ),
+            // the keys of < and > like in dwarf fortress, shift is optional
+            (ZLevelUp, key(KeyCode::Comma)),
+            (
+                ZLevelDown,
+                Binding::with_modifiers(Button::WheelUp, &[Modifier::Control]),
+            ),
+            (ZLevelDown, key(KeyCode::Period)),
+            (JumpToSurface, key(KeyCode::Home)),
+            (RotateClockwise, key(KeyCode::E)),
+            (RotateCounterClockwise, key(KeyCode::Q)),
+            (ToggleRenderMode, key(KeyCode::Tab)),
+            (Select, mouse(MouseButton::Left)),
+            (ClearSelection, mouse(MouseButton::Right)),
+            (ExtendSelection, key(KeyCode::LShift)),
+            (Designate, key(KeyCode::Return)),
+            (Pause, key(KeyCode::Space)),
+            (SetSpeed(GameSpeed::Normal), key(KeyCode::Key1)),
+            (SetSpeed(GameSpeed::Fast), key(KeyCode::Key2)),
+            (SetSpeed(GameSpeed::Faster), key(KeyCode::Key3)),
+            (SetSpeed(GameSpeed::Max), key(KeyCode::Key4)),
+            (ToggleKeyBindings, key(KeyCode::F10)),
+            (ToggleLabors, key(KeyCode::L)),
+            (DebugPath, key(KeyCode::P)),
+            (Quit, key(KeyCode::Escape)),
+        ];
+
+        let mut input_map = InputMap {
+            bindings: BTreeMap::new(),
+        };
+        let function_keys = [
+            KeyCode::F1,
+            KeyCode::F2,
+            KeyCode::F3,
+            KeyCode::F4,
+            KeyCode::F5,
+            KeyCode::F6,
+            KeyCode::F7,
+            KeyCode::F8,
+        ];
+        let bookmarks = function_keys.iter().enumerate().flat_map(|(i, key)| {
+            vec![
+                (
+                    SaveBookmark(i as u8),
+                    Binding::with_modifiers(Button::Key(*key), &[Modifier::Control]),
+                ),
+                (RecallBookmark(i as u8), Binding::new(Button::Key(*key))),
+            ]
+        });
+        for (action, binding) in bindings.into_iter().chain(bookmarks) {
+            input_map.bindings.entry(action).or_default().push(binding);
+        }
+        input_map
+    }
+}
+
+impl InputMap {
+    /// Loads the bindings from the config file, the default bindings are used and saved if it
+    /// doesn't exist yet
+    pub fn load_or_default(path: &str) -> Self {
+        if !Path::new(path).exists() {
+            let input_map = InputMap::default();
+            if let Err(err) = input_map.save(path) {
+                warn!("failed to save default input map: {:?}", err);
+            }
+            return input_map;
+        }
+        match InputMap::load(path) {
+            Ok(input_map) => {
+                for (a, b, binding) in input_map.conflicts() {
+                    warn!("{} is bound to both {:?} and {:?}", binding, a, b);
+                }
+                input_map
+            }
+            Err(err) => {
+                warn!(
+                    "failed to load input map, using default bindings: {:?}",
+                    err
+                );
+                InputMap::default()
+            }
+        }
+    }
+
+    /// Actions added since the file was saved get their default bindings, an action left
+    /// without bindings in the file stays unbound
+    pub fn load(path: &str) -> Result<Self> {
+        let mut input_map: InputMap = ron::de::from_str(&fs::read_to_string(path)?)?;
+        for binding in input_map.bindings.values_mut().flatten() {
+            binding.normalize();
+        }
+        for (action, bindings) in InputMap::default().bindings {
+            input_map.bindings.entry(action).or_insert_with(|| {
+                info!("using the default bindings of {:?}", action);
+                bindings
+            });
+        }
+        Ok(input_map)
+    }
+
+    pub fn save(&self, path: &str) -> Result<()> {
+        if let Some(parent) = Path::new(path).parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let config = ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default())?;
+        fs::write(path, config)?;
+        Ok(())
+    }
+
+    /// Every binding used by more than one action
+    pub fn conflicts(&self) -> Vec<(Action, Action, Binding)> {
+        let mut conflicts = vec![];
+        for (action, bindings) in self.bindings.iter() {
+            for (other_action, other_bindings) in self.bindings.range(action..).skip(1) {
+                for binding in bindings.iter() {
+                    if other_bindings.contains(binding) {
+                        conflicts.push((*action, *other_action, binding.clone()));
+                    }
+                }
+            }
+        }
+        conflicts
+    }
+}
+
+#[derive(Default)]
+pub struct ActionState {
+    pressed: HashSet<Action>,
+    just_pressed: HashSet<Action>,
+    just_released: HashSet<Action>,
+    /// Buttons held while the inputs were used elsewhere, they are ignored until released so
+    /// they don't trigger their action afterwards
+    ignored: HashSet<Button>,
+}
+
+impl ActionState {
+    pub fn pressed(&self, action: Action) -> bool {
+        self.pressed.contains(&action)
+    }
+
+    pub fn just_pressed(&self, action: Action) -> bool {
+        self.just_pressed.contains(&action)
+    }
+
+    pub fn just_released(&self, action: Action) -> bool {
+        self.just_released.contains(&action)
+    }
+
+    fn clear(&mut self) {
+        self.just_pressed.clear();
+        self.just_released = self.pressed.drain().collect();
+    }
+}
+
+/// Action waiting for a new binding from the key bindings window
+pub struct Rebinding(pub Option<Action>);
+
+pub struct KeyBindingsWindow {
+    pub open: bool,
+}
+
+fn held_modifiers(keyboard_input: &Input<KeyCode>) -> HashSet<Modifier> {
+    [Modifier::Shift, Modifier::Control, Modifier::Alt]
+        .iter()
+        .filter(|modifier| {
+            modifier
+                .keys()
+                .iter()
+                .any(|key| keyboard_input.pressed(*key))
+        })
+        .copied()
+        .collect()
+}
+
+/// A binding is suppressed when another active binding uses the same button with more
+/// modifiers, this way Ctrl+WheelUp doesn't also trigger WheelUp.
+/// The wheel has no held state, each frame it scrolls presses its actions again
+fn update_action_state(
+    input_map: Res<InputMap>,
+    keyboard_input: Res<Input<KeyCode>>,
+    mouse_button_input: Res<Input<MouseButton>>,
+    mut mouse_wheel_events: EventReader<MouseWheel>,
+    egui_context: Res<EguiContext>,
+    rebinding: Res<Rebinding>,
+    mut action_state: ResMut<ActionState>,
+) {
+    let wheel = mouse_wheel_events
+        .iter()
+        .fold(0.0, |acc, event| acc + event.y);
+    let is_button_held = |button: Button| match button {
+        Button::Key(key) => keyboard_input.pressed(key),
+        Button::Mouse(button) => mouse_button_input.pressed(button),
+        Button::WheelUp => wheel > 0.0,
+        Button::WheelDown => wheel < 0.0,
+    };
+    // the inputs are used by the key bindings window or by an egui text field
+    if rebinding.0.is_some() || egui_context.ctx().wants_keyboard_input() {
+        let held = keyboard_input
+            .get_pressed()
+            .map(|key| Button::Key(*key))
+            .chain(
+                mouse_button_input
+                    .get_pressed()
+                    .map(|button| Button::Mouse(*button)),
+            );
+        action_state.ignored.extend(held);
+        action_state.clear();
+        return;
+    }
+    action_state
+        .ignored
+        .retain(|button| is_button_held(*button));
+
+    let modifiers = held_modifiers(&keyboard_input);
+    let is_button_pressed =
+        |button: Button| is_button_held(button) && !action_state.ignored.contains(&button);
+    let active_bindings = input_map
+        .bindings
+        .values()
+        .flatten()
+        .filter(|binding| is_button_pressed(binding.button) && binding.has_modifiers(&modifiers))
+        .collect::<Vec<_>>();
+    let is_suppressed = |binding: &Binding| {
+        active_bindings.iter().any(|other| {
+            other.button == binding.button && other.modifiers.len() > binding.modifiers.len()
+        })
+    };
+
+    let is_active =
+        |binding: &Binding| active_bindings.contains(&binding) && !is_suppressed(binding);
+
+    let pressed = input_map
+        .bindings
+        .iter()
+        .filter(|(_, bindings)| bindings.iter().any(&is_active))
+        .map(|(action, _)| *action)
+        .collect::<HashSet<_>>();
+    let scrolled = input_map
+        .bindings
+        .iter()
+        .filter(|(_, bindings)| {
+            bindings.iter().any(|binding| {
+                matches!(binding.button, Button::WheelUp | Button::WheelDown) && is_active(binding)
+            })
+        })
+        .map(|(action, _)| *action);
+
+    let action_state = &mut *action_state;
+    action_state.just_pressed = pressed.difference(&action_state.pressed).copied().collect();
+    action_state.just_pressed.extend(scrolled);
+    action_state.just_released = action_state.pressed.difference(&pressed).copied().collect();
+    action_state.pressed = pressed;
+}
+
+/// Waits for the next input and binds it to the action being rebound.
+/// Modifier keys are only bound on release, otherwise they couldn't be used in a chord
+fn capture_binding(
+    keyboard_input: Res<Input<KeyCode>>,
+    mouse_button_input: Res<Input<MouseButton>>,
+    mut mouse_wheel_events: EventReader<MouseWheel>,
+    egui_context: Res<EguiContext>,
+    mut rebinding: ResMut<Rebinding>,
+    mut input_map: ResMut<InputMap>,
+) {
+    let action = match rebinding.0 {
+        Some(action) => action,
+        None => return,
+    };
+    if keyboard_input.just_pressed(KeyCode::Escape) {
+        rebinding.0 = None;
+        return;
+    }
+
+    let modifiers = held_modifiers(&keyboard_input)
+        .into_iter()
+        .collect::<Vec<_>>();
+    let wheel = mouse_wheel_events
+        .iter()
+        .fold(0.0, |acc, event| acc + event.y);
+    let button = if let Some(key) = keyboard_input
+        .get_just_pressed()
+        .find(|key| Modifier::from_key(**key).is_none())
+    {
+        Some(Button::Key(*key))
+    } else if let Some(button) = mouse_button_input.get_just_pressed().next() {
+        // clicks on the window are used to cancel or pick another action
+        if egui_context.ctx().wants_pointer_input() {
+            None
+        } else {
+            Some(Button::Mouse(*button))
+        }
+    } else if wheel > 0.0 {
+        Some(Button::WheelUp)
+    } else if wheel < 0.0 {
+        Some(Button::WheelDown)
+    } else {
+        None
+    };
+
+    let binding = match button {
+        Some(button) => Binding::with_modifiers(button, &modifiers),
+        None => match keyboard_input.get_just_released().next() {
+            Some(key) if Modifier::from_key(*key).is_some() => Binding::new(Button::Key(*key)),
+            _ => return,
+        },
+    };
+    info!("binding {} to {:?}", binding, action);
+    let bindings = input_map.bindings.entry(action).or_default();
+    if !bindings.contains(&binding) {
+        bindings.push(binding);
+    }
+    rebinding.0 = None;
+}
+
+fn key_bindings_window(
+    egui_context: Res<EguiContext>,
+    action_state: Res<ActionState>,
+    mut window: ResMut<KeyBindingsWindow>,
+    mut rebinding: ResMut<Rebinding>,
+    mut input_map: ResMut<InputMap>,
+) {
+    if action_state.just_pressed(Action::ToggleKeyBindings) {
+        window.open = !window.open;
+    }
+    if !window.open {
+        return;
+    }
+
+    let conflicts = input_map.conflicts();
+    let mut new_input_map = input_map.clone();
+    let mut open = window.open;
+    egui::Window::new("Key bindings")
+        .open(&mut open)
+        .show(egui_context.ctx(), |ui| {
+            egui::Grid::new("key_bindings_grid")
+                .striped(true)
+                .show(ui, |ui| {
+                    for (action, bindings) in new_input_map.bindings.iter_mut() {
+                        ui.label(format!("{:?}", action));
+                        ui.horizontal(|ui| {
+                            let mut removed = None;
+                            for (i, binding) in bindings.iter().enumerate() {
+                                let is_conflict =
+                                    conflicts.iter().any(|(_, _, conflict)| conflict == binding);
+                                if is_conflict {
+                                    ui.colored_label(egui::Color32::RED, binding);
+                                } else {
+                                    ui.label(binding.to_string());
+                                }
+                                if ui.small_button("x").clicked() {
+                                    removed = Some(i);
+                                }
+                            }
+                            if let Some(i) = removed {
+                                bindings.remove(i);
+                            }
+
+                            if rebinding.0 == Some(*action) {
+                                if ui.button("press a key...").clicked() {
+                                    rebinding.0 = None;
+                                }
+                            } else if ui.small_button("+").clicked() {
+                                rebinding.0 = Some(*action);
+                            }
+                        });
+                        ui.end_row();
+                    }
+                });
+
+            for (a, b, binding) in conflicts.iter() {
+                ui.colored_label(
+                    egui::Color32::RED,
+                    format!("{} is bound to both {:?} and {:?}", binding, a, b),
+                );
+            }
+
+            ui.horizontal(|ui| {
+                if ui.button("Reset to defaults").clicked() {
+                    new_input_map = InputMap::default();
+                }
+                if ui.button("Save").clicked() {
+                    match new_input_map.save(INPUT_CONFIG_PATH) {
+                        Ok(()) => info!("saved input map to {}", INPUT_CONFIG_PATH),
+                        Err(err) => warn!("failed to save input map: {:?}", err),
+                    }
+                }
+            });
+        });
+
+    // only touch the resources when needed
+    if new_input_map.bindings != input_map.bindings {
+        *input_map = new_input_map;
+    }
+    if open != window.open {
+        window.open = open;
+        rebinding.0 = None;
+    }
+}
@@ -1,15 +1,44 @@
-use bevy::{prelude::*, render::camera::Camera};
+use bevy::{math::Vec3Swizzles, prelude::*, render::camera::Camera};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    map::{RenderMode, ViewRotation, HEIGHT, WIDTH, Z_LEVELS},
+    utils::{cursor_to_world, lerp, tile_to_world},
+};
+
+pub const BOOKMARK_COUNT: usize = 8;
 
 pub struct CameraData {
+    /// Current zoom level, it moves towards `target_scale` every frame
     pub scale: f32,
+    pub target_scale: f32,
     pub direction: Vec3,
     pub movement_strength: f32,
+    /// Current movement, it moves towards `direction * movement_strength` every frame
+    pub velocity: Vec3,
 }
 
 pub struct MainCamera;
 
+/// Saved camera position, the position is in map coordinates so it stays on the same part of the
+/// map in every render mode and rotation
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct Bookmark {
+    pub position: Vec2,
+    pub scale: f32,
+    pub z_level: u16,
+}
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct Bookmarks(pub [Option<Bookmark>; BOOKMARK_COUNT]);
+
 pub const SCALE: f32 = 1.;
 
+/// How fast the zoom level reaches the target, higher is faster
+const ZOOM_SPEED: f32 = 10.0;
+/// How fast the camera reaches its target velocity, higher is faster
+const PAN_ACCELERATION: f32 = 8.0;
+
 pub struct CameraControlPlugin;
 
 impl Plugin for CameraControlPlugin {
@@ -18,8 +47,10 @@ impl Plugin for CameraControlPlugin {
             .add_system(update.system())
             .insert_resource(CameraData {
                 scale: 3.0,
+                target_scale: 3.0,
                 direction: Vec3::ZERO,
                 movement_strength: 500.,
+                velocity: Vec3::ZERO,
             });
     }
 }
@@ -31,16 +62,65 @@ fn startup(mut commands: Commands) {
     commands.spawn_bundle(camera_bundle).insert(MainCamera);
 }
 
+/// World coordinates of the corners of the map, including the offset of the highest z-level
+fn map_bounds(render_mode: RenderMode, rotation: ViewRotation) -> (Vec2, Vec2) {
+    let (max_x, max_y) = (WIDTH as u32 - 1, HEIGHT as u32 - 1);
+    let mut min = Vec2::splat(f32::MAX);
+    let mut max = Vec2::splat(f32::MIN);
+    for z in [0, Z_LEVELS as u32 - 1].iter() {
+        for corner in [(0, 0), (max_x, 0), (0, max_y), (max_x, max_y)].iter() {
+            let pos = tile_to_world(UVec3::new(corner.0, corner.1, *z), render_mode, rotation);
+            min = min.min(pos);
+            max = max.max(pos);
+        }
+    }
+    (min, max)
+}
+
 pub fn update(
-    camera: Res<CameraData>,
-    mut query: Query<&mut Transform, With<Camera>>,
+    mut camera: ResMut<CameraData>,
+    mut query: Query<(&mut Transform, &Camera), With<MainCamera>>,
+    windows: Res<Windows>,
+    render_mode: Res<RenderMode>,
+    rotation: Res<ViewRotation>,
     time: Res<Time>,
 ) {
-    for mut transform in query.iter_mut() {
-        transform.scale = Vec3::splat(camera.scale);
-        if transform.scale.x < 1.0 {
-            transform.scale = Vec3::ONE;
+    let target_scale = camera.target_scale.max(1.0);
+    let new_scale = lerp(
+        camera.scale,
+        target_scale,
+        (ZOOM_SPEED * time.delta_seconds()).min(1.0),
+    );
+    let window = windows.get_primary().expect("primary window not found");
+    let target_velocity = camera.direction * camera.movement_strength;
+    let velocity = camera.velocity.lerp(
+        target_velocity,
+        (PAN_ACCELERATION * time.delta_seconds()).min(1.0),
+    );
+    let (min, max) = map_bounds(*render_mode, *rotation);
+
+    for (mut transform, main_camera) in query.iter_mut() {
+        // Keep the point under the cursor at the same place while zooming
+        // The global transform is only updated later, without a parent it matches the transform
+        let cursor_world = |transform: &Transform| {
+            cursor_to_world(window, main_camera, &GlobalTransform::from(*transform))
+        };
+        let before = cursor_world(&transform);
+        transform.scale = Vec3::splat(new_scale);
+        if let (Some(before), Some(after)) = (before, cursor_world(&transform)) {
+            transform.translation += (before - after).extend(0.0);
         }
-        transform.translation += time.delta_seconds() * camera.direction * camera.movement_strength;
+        transform.translation += time.delta_seconds() * velocity;
+        let clamped = transform.translation.xy().max(min).min(max);
+        transform.translation = clamped.extend(transform.translation.z);
+    }
+
+    if camera.velocity != velocity {
+        camera.velocity = velocity;
+    }
+
+    // avoid triggering change detection when nothing moved
+    if camera.scale != new_scale {
+        camera.scale = new_scale;
     }
 }
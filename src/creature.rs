@@ -0,0 +1,653 @@
+use std::collections::VecDeque;
+
+use bevy::{
+    prelude::*,
+    utils::{HashMap, HashSet},
+};
+use bevy_egui::{egui, EguiContext};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    actions::{Action, ActionState},
+    designation::Tool,
+    items::{Item, ItemIndex},
+    jobs::{drop_item, JobBoard, JobLabel, Worker},
+    map::{
+        CurrentZLevel, MapData, MapGeneratedEvent, RenderMode, TilePos, ViewRotation, HEIGHT,
+        WIDTH, Z_LEVELS,
+    },
+    needs::Needs,
+    pathfinding::{is_walkable, successors, NavGraph},
+    regions::Regions,
+    selector::SelectionCommittedEvent,
+    simulation::{SimulationStage, SimulationTime},
+    utils::{floor_translation, is_floor_visible, squirrel_noise},
+};
+
+// TODO
+// * sprites for the dwarves
+// * other creatures
+// * fall damage
+
+const DWARF_NAMES: [&str; 7] = [
+    "Urist", "Bomrek", "Kadol", "Litast", "Zasit", "Rigoth", "Tobul",
+];
+const DWARF_SEED: u32 = 7;
+const DWARF_WIDTH: f32 = 10.0;
+const DWARF_HEIGHT: f32 = 18.0;
+/// Tiles per tick of a creature with an agility of 5
+const BASE_SPEED: f32 = 0.25;
+/// Highest skill level of the dwarves at embark
+const MAX_EMBARK_SKILL: u32 = 5;
+const MAX_SKILL_LEVEL: u32 = 15;
+/// Experience needed for the first level, each level needs this much more than the previous one
+const EXPERIENCE_PER_LEVEL: u32 = 100;
+/// Chance for an idle dwarf to start wandering each tick, in percent
+const WANDER_CHANCE: u32 = 2;
+const WANDER_RADIUS: i32 = 8;
+const WANDER_SEED: u32 = 1337;
+/// How fast the sprites catch up with the creatures, higher is snappier
+const SPRITE_SMOOTHING: f32 = 20.0;
+pub const MAX_HEALTH: u32 = 100;
+
+pub struct CreaturePlugin;
+
+impl Plugin for CreaturePlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app.add_startup_system(creature_setup.system())
+            .insert_resource(SelectedCreature(None))
+            .add_system(spawn_dwarves.system())
+            .add_system_to_stage(
+                SimulationStage,
+                wander
+                    .system()
+                    .after(JobLabel::Claim)
+                    .before(CreatureLabel::Move),
+            )
+            .add_system_to_stage(
+                SimulationStage,
+                move_creatures.system().label(CreatureLabel::Move),
+            )
+            .add_system_to_stage(
+                SimulationStage,
+                kill_creatures.system().after(CreatureLabel::Move),
+            )
+            .add_system(update_creature_sprites.system())
+            .add_system(select_creature.system())
+            .insert_resource(LaborsWindow { open: false })
+            .add_system(creature_window.system())
+            .add_system(labors_window.system());
+    }
+}
+
+#[derive(SystemLabel, Debug, Clone, PartialEq, Eq, Hash)]
+pub enum CreatureLabel {
+    /// Moves the creatures along their path, paths need to be set before it to start this tick
+    Move,
+}
+
+/// Anything that lives on the map, its position is in `TilePos`
+pub struct Creature;
+
+pub struct Dwarf;
+
+/// Attributes of a creature, from 1 to 10
+#[derive(Copy, Clone, Debug)]
+pub struct Stats {
+    pub strength: u32,
+    pub agility: u32,
+    pub toughness: u32,
+}
+
+impl Stats {
+    /// Tiles per tick
+    pub fn speed(&self) -> f32 {
+        BASE_SPEED * (0.5 + self.agility as f32 / 10.0)
+    }
+
+    /// Attribute that helps with the labor, heavy work needs strength and fine work agility
+    pub fn for_labor(&self, labor: Labor) -> u32 {
+        match labor {
+            Labor::Mining
+            | Labor::Woodcutting
+            | Labor::Hauling
+            | Labor::Construction
+            | Labor::Masonry => self.strength,
+            Labor::Carpentry | Labor::Smelting | Labor::Cooking => self.agility,
+        }
+    }
+
+    /// Work speed multiplier for the labor, 1 with an attribute of 5
+    pub fn work_speed(&self, labor: Labor) -> f32 {
+        0.5 + self.for_labor(labor) as f32 / 10.0
+    }
+}
+
+/// Hit points of a creature, it dies when they reach 0
+#[derive(Copy, Clone, Debug)]
+pub struct Health(pub u32);
+
+impl Health {
+    pub fn damage(&mut self, amount: u32) {
+        self.0 = self.0.saturating_sub(amount);
+    }
+
+    pub fn is_dead(&self) -> bool {
+        self.0 == 0
+    }
+}
+
+/// Kinds of work, each one has its own skill
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Labor {
+    Mining,
+    Woodcutting,
+    Hauling,
+    Construction,
+    Carpentry,
+    Masonry,
+    Smelting,
+    Cooking,
+}
+
+pub const LABORS: [Labor; 8] = [
+    Labor::Mining,
+    Labor::Woodcutting,
+    Labor::Hauling,
+    Labor::Construction,
+    Labor::Carpentry,
+    Labor::Masonry,
+    Labor::Smelting,
+    Labor::Cooking,
+];
+
+/// Skill level of each labor, a missing labor is at level 0
+#[derive(Default, Clone, Debug)]
+pub struct Skills {
+    pub levels: HashMap<Labor, u32>,
+    /// Experience towards the next level
+    pub experience: HashMap<Labor, u32>,
+}
+
+impl Skills {
+    pub fn level(&self, labor: Labor) -> u32 {
+        self.levels.get(&labor).copied().unwrap_or(0)
+    }
+
+    pub fn experience(&self, labor: Labor) -> u32 {
+        self.experience.get(&labor).copied().unwrap_or(0)
+    }
+
+    /// Experience needed to go from the current level to the next one
+    pub fn next_level_experience(&self, labor: Labor) -> u32 {
+        EXPERIENCE_PER_LEVEL * (self.level(labor) + 1)
+    }
+
+    /// Returns true when the skill reaches a new level
+    pub fn gain_experience(&mut self, labor: Labor, amount: u32) -> bool {
+        if self.level(labor) >= MAX_SKILL_LEVEL {
+            return false;
+        }
+        let needed = self.next_level_experience(labor);
+        let experience = self.experience.entry(labor).or_insert(0);
+        *experience += amount;
+        if *experience < needed {
+            return false;
+        }
+        *experience -= needed;
+        *self.levels.entry(labor).or_insert(0) += 1;
+        true
+    }
+}
+
+/// Labors a dwarf is allowed to do, every labor is enabled at first
+#[derive(Clone, Debug)]
+pub struct Labors(pub HashSet<Labor>);
+
+impl Default for Labors {
+    fn default() -> Self {
+        Labors(LABORS.iter().copied().collect())
+    }
+}
+
+impl Labors {
+    pub fn is_enabled(&self, labor: Labor) -> bool {
+        self.0.contains(&labor)
+    }
+
+    pub fn set(&mut self, labor: Labor, enabled: bool) {
+        if enabled {
+            self.0.insert(labor);
+        } else {
+            self.0.remove(&labor);
+        }
+    }
+}
+
+/// Path followed by a creature, the creature is between its `TilePos` and the front of the path
+#[derive(Default)]
+pub struct Movement {
+    pub path: VecDeque<UVec3>,
+    /// Progress towards the next tile of the path, from 0 to 1
+    pub progress: f32,
+}
+
+impl Movement {
+    pub fn is_idle(&self) -> bool {
+        self.path.is_empty()
+    }
+
+    /// Starts following a path that starts at the current position of the creature
+    pub fn follow(&mut self, path: Vec<UVec3>) {
+        self.path = path.into_iter().skip(1).collect();
+        self.progress = 0.0;
+    }
+
+    pub fn stop(&mut self) {
+        self.path.clear();
+        self.progress = 0.0;
+    }
+}
+
+/// Creature shown in the details window
+pub struct SelectedCreature(pub Option<Entity>);
+
+pub struct LaborsWindow {
+    pub open: bool,
+}
+
+struct CreatureMaterials {
+    dwarf: Handle<ColorMaterial>,
+}
+
+fn creature_setup(mut commands: Commands, mut materials: ResMut<Assets<ColorMaterial>>) {
+    commands.insert_resource(CreatureMaterials {
+        dwarf: materials.add(Color::rgb(0.8, 0.35, 0.2).into()),
+    });
+}
+
+/// Finds the walkable surface tile closest to the center of the map
+fn find_embark_tile(map_data: &MapData) -> Option<UVec3> {
+    let center = IVec2::new(WIDTH as i32 / 2, HEIGHT as i32 / 2);
+    let max = IVec2::new(WIDTH as i32 - 1, HEIGHT as i32 - 1);
+    for radius in 0..WIDTH.max(HEIGHT) as i32 / 2 {
+        for y in -radius..=radius {
+            for x in -radius..=radius {
+                // only the ring of this radius, the inside was already checked
+                if x.abs() != radius && y.abs() != radius {
+                    continue;
+                }
+                let pos = (center + IVec2::new(x, y)).max(IVec2::ZERO).min(max);
+                let surface = match map_data.find_top_tile(pos.as_u32(), Z_LEVELS - 1) {
+                    Some(surface) => surface + UVec3::Z,
+                    None => continue,
+                };
+                if is_walkable(map_data, surface) {
+                    return Some(surface);
+                }
+            }
+        }
+    }
+    None
+}
+
+fn spawn_dwarves(
+    mut commands: Commands,
+    mut events: EventReader<MapGeneratedEvent>,
+    map_data: Res<MapData>,
+    materials: Res<CreatureMaterials>,
+    render_mode: Res<RenderMode>,
+    rotation: Res<ViewRotation>,
+    mut selected_creature: ResMut<SelectedCreature>,
+    query: Query<Entity, With<Creature>>,
+) {
+    if events.iter().count() == 0 {
+        return;
+    }
+    for entity in query.iter() {
+        commands.entity(entity).despawn();
+    }
+    selected_creature.0 = None;
+
+    let embark = match find_embark_tile(&map_data) {
+        Some(embark) => embark,
+        None => {
+            warn!("no walkable tile to embark on");
+            return;
+        }
+    };
+
+    // the dwarves are spread on the closest tiles around the embark tile
+    let mut tiles = vec![embark];
+    let mut visited = HashSet::default();
+    visited.insert(embark);
+    let mut i = 0;
+    while tiles.len() < DWARF_NAMES.len() && i < tiles.len() {
+        for (successor, _) in successors(&map_data, tiles[i]) {
+            if visited.insert(successor) {
+                tiles.push(successor);
+            }
+        }
+        i += 1;
+    }
+
+    for (i, (name, pos)) in DWARF_NAMES.iter().zip(tiles).enumerate() {
+        let stat = |seed: u32| squirrel_noise(i as i32, DWARF_SEED + seed) % 10 + 1;
+        commands
+            .spawn_bundle(SpriteBundle {
+                material: materials.dwarf.clone(),
+                sprite: Sprite::new(Vec2::new(DWARF_WIDTH, DWARF_HEIGHT)),
+                transform: Transform::from_translation(floor_translation(
+                    pos,
+                    DWARF_HEIGHT,
+                    *render_mode,
+                    *rotation,
+                )),
+                ..Default::default()
+            })
+            .insert(Creature)
+            .insert(Dwarf)
+            .insert(Name::new(*name))
+            .insert(TilePos(pos))
+            .insert(Stats {
+                strength: stat(0),
+                agility: stat(1),
+                toughness: stat(2),
+            })
+            .insert(Skills {
+                levels: LABORS
+                    .iter()
+                    .enumerate()
+                    .map(|(j, labor)| {
+                        let level = squirrel_noise(i as i32, DWARF_SEED + 3 + j as u32)
+                            % (MAX_EMBARK_SKILL + 1);
+                        (*labor, level)
+                    })
+                    .collect(),
+                experience: HashMap::default(),
+            })
+            .insert(Labors::default())
+            .insert(Health(MAX_HEALTH))
+            .insert(Needs::default())
+            .insert(Movement::default())
+            .insert(Worker::default());
+    }
+    info!("embarked at {}", embark);
+}
+
+/// Idle dwarves sometimes walk to a random tile around them
+fn wander(
+    simulation_time: Res<SimulationTime>,
+    map_data: Res<MapData>,
+    nav_graph: Res<NavGraph>,
+    regions: Res<Regions>,
+    mut query: Query<(Entity, &TilePos, &mut Movement, &Worker), With<Dwarf>>,
+) {
+    let seed = WANDER_SEED ^ simulation_time.tick as u32;
+    for (entity, pos, mut movement, worker) in query.iter_mut() {
+        let noise = |offset: u32| squirrel_noise(entity.id() as i32, seed.wrapping_add(offset));
+        if !movement.is_idle() || worker.job.is_some() || noise(0) % 100 >= WANDER_CHANCE {
+            continue;
+        }
+        let offset = IVec3::new(
+            (noise(1) % (WANDER_RADIUS as u32 * 2 + 1)) as i32 - WANDER_RADIUS,
+            (noise(2) % (WANDER_RADIUS as u32 * 2 + 1)) as i32 - WANDER_RADIUS,
+            0,
+        );
+        let max = IVec3::new(WIDTH as i32 - 1, HEIGHT as i32 - 1, Z_LEVELS as i32 - 1);
+        let target = (pos.0.as_i32() + offset).max(IVec3::ZERO).min(max).as_u32();
+        if !regions.is_reachable(pos.0, target) {
+            continue;
+        }
+        if let Some(path) = nav_graph.find_path(&map_data, pos.0, target) {
+            movement.follow(path);
+        }
+    }
+}
+
+fn move_creatures(
+    map_data: Res<MapData>,
+    mut query: Query<(&mut TilePos, &mut Movement, &Stats), With<Creature>>,
+) {
+    for (mut pos, mut movement, stats) in query.iter_mut() {
+        // creatures fall one z-level per tick when their floor is removed
+        if !is_walkable(&map_data, pos.0) && pos.0.z > 0 {
+            let below = pos.0 - UVec3::Z;
+            if map_data
+                .get_tile(below)
+                .map_or(false, |tile| tile.value.is_open())
+            {
+                pos.0 = below;
+                movement.stop();
+                continue;
+            }
+        }
+        let next = match movement.path.front() {
+            Some(next) => *next,
+            None => continue,
+        };
+        // the map changed since the path was found
+        if !is_walkable(&map_data, next) {
+            movement.stop();
+            continue;
+        }
+        movement.progress += stats.speed();
+        while movement.progress >= 1.0 {
+            match movement.path.pop_front() {
+                Some(next) => {
+                    pos.0 = next;
+                    movement.progress -= 1.0;
+                }
+                None => break,
+            }
+        }
+        if movement.is_idle() {
+            movement.progress = 0.0;
+        }
+    }
+}
+
+/// Dead creatures give up their job and drop what they carry
+fn kill_creatures(
+    mut commands: Commands,
+    mut job_board: ResMut<JobBoard>,
+    mut item_index: ResMut<ItemIndex>,
+    mut query: Query<(Entity, &Name, &TilePos, &Health, &mut Worker), Without<Item>>,
+    mut items: Query<(&mut TilePos, &mut Item)>,
+) {
+    for (entity, name, pos, health, mut worker) in query.iter_mut() {
+        if !health.is_dead() {
+            continue;
+        }
+        if let Some(id) = worker.job.take() {
+            job_board.unclaim(id);
+        }
+        drop_item(&mut item_index, &mut items, &mut worker, pos.0);
+        commands.entity(entity).despawn();
+        info!("{} died at {}", name.as_str(), pos.0);
+    }
+}
+
+/// The sprites are interpolated between the tiles of the path
+fn update_creature_sprites(
+    time: Res<Time>,
+    map_data: Res<MapData>,
+    current_z_level: Res<CurrentZLevel>,
+    render_mode: Res<RenderMode>,
+    rotation: Res<ViewRotation>,
+    mut query: Query<(&TilePos, &Movement, &mut Transform, &mut Visible), With<Creature>>,
+) {
+    // the sprites would slide across the screen when the view changes
+    let snap = render_mode.is_changed() || rotation.is_changed();
+    let smoothing = (SPRITE_SMOOTHING * time.delta_seconds()).min(1.0);
+    for (pos, movement, mut transform, mut visible) in query.iter_mut() {
+        let current = floor_translation(pos.0, DWARF_HEIGHT, *render_mode, *rotation);
+        let target = match movement.path.front() {
+            Some(next) => current.lerp(
+                floor_translation(*next, DWARF_HEIGHT, *render_mode, *rotation),
+                movement.progress,
+            ),
+            None => current,
+        };
+        if snap {
+            transform.translation = target;
+        } else if transform.translation != target {
+            transform.translation = transform.translation.lerp(target, smoothing);
+        }
+
+        let is_visible = is_floor_visible(&map_data, pos.0, current_z_level.0, *render_mode);
+        if visible.is_visible != is_visible {
+            visible.is_visible = is_visible;
+        }
+    }
+}
+
+/// Selecting the tile of a creature, or the floor below it, shows its details
+fn select_creature(
+    mut events: EventReader<SelectionCommittedEvent>,
+    tool: Res<Tool>,
+    query: Query<(Entity, &TilePos), With<Creature>>,
+    mut selected_creature: ResMut<SelectedCreature>,
+) {
+    for event in events.iter() {
+        if *tool != Tool::Select {
+            continue;
+        }
+        let tiles = event.tiles.iter().collect::<HashSet<_>>();
+        let picked = query
+            .iter()
+            .find(|(_, pos)| {
+                tiles.contains(&pos.0) || (pos.0.z > 0 && tiles.contains(&(pos.0 - UVec3::Z)))
+            })
+            .map(|(entity, _)| entity);
+        if picked != selected_creature.0 {
+            selected_creature.0 = picked;
+        }
+    }
+}
+
+fn creature_window(
+    egui_context: Res<EguiContext>,
+    job_board: Res<JobBoard>,
+    mut selected_creature: ResMut<SelectedCreature>,
+    query: Query<(
+        &Name,
+        &TilePos,
+        &Stats,
+        &Skills,
+        &Health,
+        &Needs,
+        &Movement,
+        &Worker,
+    )>,
+) {
+    let entity = match selected_creature.0 {
+        Some(entity) => entity,
+        None => return,
+    };
+    let (name, pos, stats, skills, health, needs, movement, worker) = match query.get(entity) {
+        Ok(creature) => creature,
+        Err(_) => {
+            selected_creature.0 = None;
+            return;
+        }
+    };
+
+    let mut open = true;
+    egui::Window::new("Creature")
+        .open(&mut open)
+        .resizable(false)
+        .show(egui_context.ctx(), |ui| {
+            ui.heading(name.as_str());
+            ui.label(format!("x: {} y: {} z: {}", pos.0.x, pos.0.y, pos.0.z));
+            ui.label(format!("health: {}/{}", health.0, MAX_HEALTH));
+            for (need, fill, state) in needs.describe().iter() {
+                ui.add(egui::ProgressBar::new(*fill).text(format!("{}: {}", need, state)));
+            }
+            ui.label(format!("strength: {}", stats.strength));
+            ui.label(format!("agility: {}", stats.agility));
+            ui.label(format!("toughness: {}", stats.toughness));
+            for labor in LABORS.iter() {
+                ui.label(format!(
+                    "{:?}: {} ({}/{} xp)",
+                    labor,
+                    skills.level(*labor),
+                    skills.experience(*labor),
+                    skills.next_level_experience(*labor)
+                ));
+            }
+            if let Some(job) = worker.job.and_then(|id| job_board.get(id)) {
+                ui.label(format!(
+                    "job: {} at x: {} y: {} z: {}",
+                    job.kind.name(),
+                    job.pos.x,
+                    job.pos.y,
+                    job.pos.z
+                ));
+            }
+            if movement.is_idle() {
+                ui.label("idle");
+            } else {
+                ui.label(format!("moving, {} tiles left", movement.path.len()));
+            }
+        });
+    if !open {
+        selected_creature.0 = None;
+    }
+}
+
+/// Matrix of the labors of every dwarf, clicking a labor toggles it for all of them
+fn labors_window(
+    egui_context: Res<EguiContext>,
+    action_state: Res<ActionState>,
+    mut window: ResMut<LaborsWindow>,
+    mut query: Query<(&Name, &Skills, &mut Labors), With<Dwarf>>,
+) {
+    if action_state.just_pressed(Action::ToggleLabors) {
+        window.open = !window.open;
+    }
+    if !window.open {
+        return;
+    }
+
+    let mut dwarves = query.iter_mut().collect::<Vec<_>>();
+    dwarves.sort_by(|(a, _, _), (b, _, _)| a.as_str().cmp(b.as_str()));
+    let mut open = true;
+    egui::Window::new("Labors")
+        .open(&mut open)
+        .show(egui_context.ctx(), |ui| {
+            egui::Grid::new("labors_grid").striped(true).show(ui, |ui| {
+                ui.label("");
+                for labor in LABORS.iter() {
+                    if ui.small_button(format!("{:?}", labor)).clicked() {
+                        let enabled = !dwarves
+                            .iter()
+                            .all(|(_, _, labors)| labors.is_enabled(*labor));
+                        for (_, _, labors) in dwarves.iter_mut() {
+                            if labors.is_enabled(*labor) != enabled {
+                                labors.set(*labor, enabled);
+                            }
+                        }
+                    }
+                }
+                ui.end_row();
+                for (name, skills, labors) in dwarves.iter_mut() {
+                    ui.label(name.as_str());
+                    for labor in LABORS.iter() {
+                        // the checkbox shows the skill level of the labor
+                        let mut enabled = labors.is_enabled(*labor);
+                        if ui
+                            .checkbox(&mut enabled, skills.level(*labor).to_string())
+                            .changed()
+                        {
+                            labors.set(*labor, enabled);
+                        }
+                    }
+                    ui.end_row();
+                }
+            });
+        });
+    if !open {
+        window.open = false;
+    }
+}
@@ -0,0 +1,451 @@
+use bevy::{
+    math::Vec3Swizzles,
+    prelude::*,
+    utils::{HashMap, HashSet},
+};
+use bevy_egui::{egui, EguiContext};
+
+use crate::{
+    map::{
+        ChangedTiles, Construction, CurrentZLevel, Designation, DesignationsChangedEvent, MapData,
+        MapGeneratedEvent, MapLabel, RenderMode, ViewRotation, TILE_WIDTH, Z_LEVELS,
+    },
+    selector::SelectionCommittedEvent,
+    utils::{tile_to_world, tile_z_order},
+    workshop::{WorkshopKind, WORKSHOP_KINDS},
+};
+
+// TODO
+// * keyboard shortcuts for the tools
+
+const DESIGNATIONS: [Designation; 14] = [
+    Designation::Dig,
+    Designation::Channel,
+    Designation::UpStair,
+    Designation::DownStair,
+    Designation::UpDownStair,
+    Designation::Ramp,
+    Designation::Chop,
+    Designation::Build(Construction::Wall),
+    Designation::Build(Construction::Floor),
+    Designation::Build(Construction::UpStair),
+    Designation::Build(Construction::DownStair),
+    Designation::Build(Construction::UpDownStair),
+    Designation::Build(Construction::Ramp),
+    Designation::Deconstruct,
+];
+
+pub struct DesignationPlugin;
+
+impl Plugin for DesignationPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app.add_startup_system(designation_setup.system())
+            .insert_resource(Tool::Select)
+            .insert_resource(DesignationOverlays(HashMap::default()))
+            .add_system(tool_palette.system())
+            .add_system(clear_designations.system())
+            .add_system(designate_selection.system())
+            .add_system(
+                remove_invalid_designations
+                    .system()
+                    .before(MapLabel::UpdateTiles),
+            )
+            .add_system(
+                update_designation_overlays
+                    .system()
+                    .before(MapLabel::UpdateTiles),
+            );
+    }
+}
+
+/// What happens to the selection when it's committed
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Tool {
+    Select,
+    Designate(Designation),
+    RemoveDesignation,
+    PlaceStockpile,
+    RemoveStockpile,
+    PlaceWorkshop(WorkshopKind),
+}
+
+struct DesignationOverlay;
+
+/// Overlay sprite of each designated tile with the designation it shows
+struct DesignationOverlays(HashMap<UVec3, (Designation, Entity)>);
+
+struct DesignationMaterials {
+    isometric: HashMap<Designation, Handle<ColorMaterial>>,
+    top_down: HashMap<Designation, Handle<ColorMaterial>>,
+}
+
+impl DesignationMaterials {
+    fn get(
+        &self,
+        designation: Designation,
+        render_mode: RenderMode,
+    ) -> (Handle<ColorMaterial>, Sprite) {
+        match render_mode {
+            RenderMode::Isometric => (self.isometric[&designation].clone(), Sprite::default()),
+            RenderMode::TopDown => (
+                self.top_down[&designation].clone(),
+                Sprite::new(Vec2::splat(TILE_WIDTH as f32)),
+            ),
+        }
+    }
+}
+
+fn overlay_color(designation: Designation) -> Color {
+    match designation {
+        Designation::Dig => Color::rgb(1.0, 0.85, 0.2),
+        Designation::Channel => Color::rgb(1.0, 0.5, 0.1),
+        Designation::UpStair | Designation::DownStair | Designation::UpDownStair => {
+            Color::rgb(0.3, 0.6, 1.0)
+        }
+        Designation::Ramp => Color::rgb(0.7, 0.4, 1.0),
+        Designation::Chop => Color::rgb(0.3, 1.0, 0.3),
+        Designation::Build(_) => Color::rgb(0.2, 0.9, 0.9),
+        Designation::Deconstruct => Color::rgb(1.0, 0.3, 0.3),
+    }
+}
+
+fn designation_setup(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    mut materials: ResMut<Assets<ColorMaterial>>,
+) {
+    let texture_handle = asset_server.load("iso_select.png");
+    let mut isometric = HashMap::default();
+    let mut top_down = HashMap::default();
+    for designation in DESIGNATIONS.iter() {
+        let color = overlay_color(*designation);
+        isometric.insert(
+            *designation,
+            materials.add(ColorMaterial::modulated_texture(
+                texture_handle.clone(),
+                color,
+            )),
+        );
+        let [r, g, b, _] = color.as_rgba_f32();
+        top_down.insert(
+            *designation,
+            materials.add(Color::rgba(r, g, b, 0.4).into()),
+        );
+    }
+    commands.insert_resource(DesignationMaterials {
+        isometric,
+        top_down,
+    });
+}
+
+fn tool_palette(egui_context: Res<EguiContext>, mut tool: ResMut<Tool>) {
+    let mut current_tool = *tool;
+    egui::Window::new("Designations")
+        .anchor(egui::Align2::LEFT_BOTTOM, [0., 0.])
+        .resizable(false)
+        .show(egui_context.ctx(), |ui| {
+            ui.selectable_value(&mut current_tool, Tool::Select, "Select");
+            for designation in DESIGNATIONS.iter() {
+                ui.selectable_value(
+                    &mut current_tool,
+                    Tool::Designate(*designation),
+                    designation.name(),
+                );
+            }
+            ui.selectable_value(&mut current_tool, Tool::RemoveDesignation, "Remove");
+            ui.separator();
+            ui.selectable_value(&mut current_tool, Tool::PlaceStockpile, "Stockpile");
+            ui.selectable_value(&mut current_tool, Tool::RemoveStockpile, "Remove stockpile");
+            ui.separator();
+            for kind in WORKSHOP_KINDS.iter() {
+                ui.selectable_value(&mut current_tool, Tool::PlaceWorkshop(*kind), kind.name());
+            }
+        });
+
+    if current_tool != *tool {
+        *tool = current_tool;
+    }
+}
+
+/// Builds go on the open tile above the selected floor, the other designations on the selected tile
+fn designation_target(tile_pos: UVec3, designation: Designation) -> UVec3 {
+    match designation {
+        Designation::Build(_) => tile_pos + UVec3::Z,
+        _ => tile_pos,
+    }
+}
+
+/// The old designations don't make sense on a new map
+fn clear_designations(
+    mut events: EventReader<MapGeneratedEvent>,
+    mut map_data: ResMut<MapData>,
+    mut designations_changed: EventWriter<DesignationsChangedEvent>,
+) {
+    if events.iter().count() == 0 || map_data.designations.is_empty() {
+        return;
+    }
+    map_data.designations.clear();
+    designations_changed.send(DesignationsChangedEvent);
+}
+
+/// Applies the current tool to the committed selection, tiles that can't take the designation are skipped
+fn designate_selection(
+    mut events: EventReader<SelectionCommittedEvent>,
+    tool: Res<Tool>,
+    mut map_data: ResMut<MapData>,
+    mut designations_changed: EventWriter<DesignationsChangedEvent>,
+) {
+    for event in events.iter() {
+        match *tool {
+            // stockpiles and workshops are handled by their own plugins
+            Tool::Select
+            | Tool::PlaceStockpile
+            | Tool::RemoveStockpile
+            | Tool::PlaceWorkshop(_) => {}
+            Tool::Designate(designation) => {
+                let valid_tiles = event
+                    .tiles
+                    .iter()
+                    .map(|tile_pos| designation_target(*tile_pos, designation))
+                    .filter(|tile_pos| map_data.can_designate(*tile_pos, designation))
+                    .collect::<Vec<_>>();
+                info!(
+                    "designating {} tiles for {:?}, {} invalid tiles skipped",
+                    valid_tiles.len(),
+                    designation,
+                    event.tiles.len() - valid_tiles.len()
+                );
+                if !valid_tiles.is_empty() {
+                    for tile_pos in valid_tiles {
+                        map_data.designations.insert(tile_pos, designation);
+                    }
+                    designations_changed.send(DesignationsChangedEvent);
+                }
+            }
+            Tool::RemoveDesignation => {
+                // build designations are on the tile above the selected one
+                let tiles = event
+                    .tiles
+                    .iter()
+                    .flat_map(|tile_pos| {
+                        let above = *tile_pos + UVec3::Z;
+                        let is_build = |pos: &UVec3| {
+                            matches!(map_data.designations.get(pos), Some(Designation::Build(_)))
+                        };
+                        let on_tile = Some(*tile_pos).filter(|pos| {
+                            map_data.designations.contains_key(pos) && !is_build(pos)
+                        });
+                        let on_above = Some(above).filter(is_build);
+                        on_tile.into_iter().chain(on_above)
+                    })
+                    .collect::<Vec<_>>();
+                // avoid updating the overlays when there's nothing to remove
+                if !tiles.is_empty() {
+                    for tile_pos in tiles {
+                        map_data.designations.remove(&tile_pos);
+                    }
+                    designations_changed.send(DesignationsChangedEvent);
+                }
+            }
+        }
+    }
+}
+
+/// Only the designations on tiles that are drawn are shown
+pub fn is_overlay_visible(
+    map_data: &MapData,
+    tile_pos: UVec3,
+    current_z_level: u16,
+    render_mode: RenderMode,
+) -> bool {
+    match render_mode {
+        RenderMode::Isometric => tile_pos.z <= current_z_level as u32,
+        RenderMode::TopDown => {
+            map_data.find_top_tile(tile_pos.xy(), current_z_level) == Some(tile_pos)
+        }
+    }
+}
+
+/// A designation can become invalid when its tile changes, like a dug out tile. Channels also
+/// depend on the tile above and builds on the tile below, so those are checked again too
+fn invalid_designations(map_data: &MapData, changed_tiles: &ChangedTiles) -> HashSet<UVec3> {
+    changed_tiles
+        .0
+        .iter()
+        .flat_map(|(pos, _)| {
+            let below = Some(*pos).filter(|pos| pos.z > 0).map(|pos| pos - UVec3::Z);
+            std::iter::once(*pos)
+                .chain(below)
+                .chain(std::iter::once(*pos + UVec3::Z))
+        })
+        .filter(|pos| {
+            map_data.designations.get(pos).map_or(false, |designation| {
+                !map_data.can_designate(*pos, *designation)
+            })
+        })
+        .collect()
+}
+
+fn remove_invalid_designations(
+    mut map_data: ResMut<MapData>,
+    changed_tiles: Res<ChangedTiles>,
+    mut designations_changed: EventWriter<DesignationsChangedEvent>,
+) {
+    if changed_tiles.0.is_empty() || map_data.designations.is_empty() {
+        return;
+    }
+    let invalid = invalid_designations(&map_data, &changed_tiles);
+    // avoid updating the overlays and the jobs when nothing is removed
+    if !invalid.is_empty() {
+        for pos in invalid {
+            map_data.designations.remove(&pos);
+        }
+        designations_changed.send(DesignationsChangedEvent);
+    }
+}
+
+/// Only the overlays of the designations that changed are updated, all of them when the view
+/// changes. In top-down mode a tile change can also hide or show the overlays of its column.
+fn update_designation_overlays(
+    mut commands: Commands,
+    map_data: Res<MapData>,
+    mut designations_changed: EventReader<DesignationsChangedEvent>,
+    changed_tiles: Res<ChangedTiles>,
+    current_z_level: Res<CurrentZLevel>,
+    render_mode: Res<RenderMode>,
+    rotation: Res<ViewRotation>,
+    materials: Res<DesignationMaterials>,
+    mut overlays: ResMut<DesignationOverlays>,
+    mut query: Query<
+        (
+            &mut Transform,
+            &mut Visible,
+            &mut Handle<ColorMaterial>,
+            &mut Sprite,
+        ),
+        With<DesignationOverlay>,
+    >,
+) {
+    let designations = &map_data.designations;
+    let view_changed =
+        current_z_level.is_changed() || render_mode.is_changed() || rotation.is_changed();
+    let mut updated = HashSet::default();
+    if designations_changed.iter().count() > 0 || view_changed {
+        overlays.0.retain(|tile_pos, (_, entity)| {
+            let is_designated = designations.contains_key(tile_pos);
+            if !is_designated {
+                commands.entity(*entity).despawn();
+            }
+            is_designated
+        });
+        updated.extend(
+            designations
+                .iter()
+                .filter(|(tile_pos, designation)| {
+                    view_changed
+                        || overlays.0.get(tile_pos).map(|(shown, _)| shown) != Some(designation)
+                })
+                .map(|(tile_pos, _)| *tile_pos),
+        );
+    }
+    if *render_mode == RenderMode::TopDown && !changed_tiles.0.is_empty() {
+        let columns: HashSet<UVec2> = changed_tiles.0.iter().map(|(pos, _)| pos.xy()).collect();
+        for column in columns {
+            updated.extend(
+                (0..Z_LEVELS as u32)
+                    .map(|z| column.extend(z))
+                    .filter(|tile_pos| designations.contains_key(tile_pos)),
+            );
+        }
+    }
+
+    for tile_pos in updated {
+        let designation = designations[&tile_pos];
+        // builds are shown on the floor they are placed on
+        let overlay_pos = match designation {
+            Designation::Build(_) => tile_pos - UVec3::Z,
+            _ => tile_pos,
+        };
+        let translation = tile_to_world(overlay_pos, *render_mode, *rotation)
+            .extend(tile_z_order(overlay_pos.z, *render_mode));
+        let (new_material, new_sprite) = materials.get(designation, *render_mode);
+        let is_visible =
+            is_overlay_visible(&map_data, overlay_pos, current_z_level.0, *render_mode);
+
+        if let Some((shown, entity)) = overlays.0.get_mut(&tile_pos) {
+            *shown = designation;
+            if let Ok((mut transform, mut visible, mut material, mut sprite)) =
+                query.get_mut(*entity)
+            {
+                transform.translation = translation;
+                visible.is_visible = is_visible;
+                *material = new_material;
+                *sprite = new_sprite;
+            }
+        } else {
+            let entity = commands
+                .spawn_bundle(SpriteBundle {
+                    material: new_material,
+                    sprite: new_sprite,
+                    transform: Transform::from_translation(translation),
+                    visible: Visible {
+                        is_visible,
+                        is_transparent: true,
+                    },
+                    ..Default::default()
+                })
+                .insert(DesignationOverlay)
+                .id();
+            overlays.0.insert(tile_pos, (designation, entity));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::map::{Tile, TileType, HEIGHT, WIDTH};
+
+    fn tile(value: TileType) -> Tile {
+        Tile {
+            visible: true,
+            value,
+            ..Default::default()
+        }
+    }
+
+    fn rock_at(positions: &[UVec3]) -> MapData {
+        let mut map_data = MapData::new(WIDTH, HEIGHT, Z_LEVELS as usize);
+        for pos in positions {
+            map_data.set_tile(*pos, tile(TileType::Rock)).unwrap();
+        }
+        map_data
+    }
+
+    #[test]
+    fn digging_the_floor_invalidates_the_build_above() {
+        let floor = UVec3::new(4, 4, 1);
+        let build = Designation::Build(Construction::Wall);
+        let mut map_data = rock_at(&[floor]);
+        map_data.designations.insert(floor + UVec3::Z, build);
+        assert!(map_data.can_designate(floor + UVec3::Z, build));
+
+        let mut changed = ChangedTiles::default();
+        map_data.update_tile(floor, tile(TileType::Air), &mut changed);
+        let invalid = invalid_designations(&map_data, &changed);
+        assert!(invalid.contains(&(floor + UVec3::Z)));
+    }
+
+    #[test]
+    fn building_above_invalidates_the_channel_below() {
+        let floor = UVec3::new(4, 4, 1);
+        let mut map_data = rock_at(&[floor]);
+        map_data.designations.insert(floor, Designation::Channel);
+        assert!(map_data.can_designate(floor, Designation::Channel));
+
+        let mut changed = ChangedTiles::default();
+        map_data.update_tile(floor + UVec3::Z, tile(TileType::Wall), &mut changed);
+        let invalid = invalid_designations(&map_data, &changed);
+        assert!(invalid.contains(&floor));
+    }
+}
@@ -0,0 +1,405 @@
+use std::collections::VecDeque;
+
+use bevy::{
+    prelude::*,
+    utils::{HashMap, HashSet, Instant},
+};
+
+use crate::{
+    map::{
+        horizontal_neighbors, neighbors, ChangedTiles, MapData, MapGeneratedEvent, MapLabel,
+        TileType, HEIGHT, MAX_LIQUID_LEVEL, WIDTH, Z_LEVELS,
+    },
+    simulation::{SimulationStage, SimulationTime},
+};
+
+// TODO
+// * evaporation of puddles
+// * rivers and other infinite sources
+// * water putting out fires
+
+/// Size of the chunks woken up by the simulation, a chunk covers a single z-level
+const FLUID_CHUNK_SIZE: u32 = 16;
+/// Maximum number of tiles visited when looking for a place to push pressurized liquid
+const PRESSURE_SEARCH_LIMIT: usize = 64;
+/// Magma is viscous, it only flows once every this many ticks
+const MAGMA_FLOW_INTERVAL: u64 = 3;
+/// A burning tile sets its flammable neighbours on fire after burning this many ticks
+const FIRE_SPREAD_TICKS: u64 = 60;
+/// Ticks a tile burns before it's gone
+const BURN_TICKS: u64 = 300;
+
+pub struct FluidPlugin;
+
+impl Plugin for FluidPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app.insert_resource(FluidChunks::default())
+            .insert_resource(Fires::default())
+            .add_system(wake_generated_map.system())
+            .add_system(clear_fires.system())
+            .add_system(wake_changed_tiles.system().before(MapLabel::UpdateTiles))
+            .add_system_to_stage(SimulationStage, simulate_fluids.system())
+            .add_system_to_stage(SimulationStage, burn_fires.system());
+    }
+}
+
+/// Chunks that may contain unsettled liquid, the others are skipped by the simulation
+#[derive(Default)]
+pub struct FluidChunks {
+    awake: HashSet<UVec3>,
+}
+
+impl FluidChunks {
+    fn chunk(pos: UVec3) -> UVec3 {
+        UVec3::new(pos.x / FLUID_CHUNK_SIZE, pos.y / FLUID_CHUNK_SIZE, pos.z)
+    }
+
+    /// Wakes the chunk of the tile and the chunks of its neighbours
+    pub fn wake_around(&mut self, pos: UVec3) {
+        self.awake.insert(Self::chunk(pos));
+        for neighbor in neighbors(pos) {
+            self.awake.insert(Self::chunk(neighbor));
+        }
+    }
+}
+
+/// Burning tiles with the tick they caught fire
+#[derive(Default)]
+pub struct Fires(HashMap<UVec3, u64>);
+
+/// Level of the given liquid in a tile, only air and tiles of the same liquid can receive it
+fn liquid_level(map_data: &MapData, pos: UVec3, liquid: TileType) -> Option<u8> {
+    match map_data.get_tile(pos) {
+        Some(tile) if tile.value == TileType::Air || tile.value == liquid => Some(tile.liquid),
+        _ => None,
+    }
+}
+
+fn set_liquid_level(
+    map_data: &mut MapData,
+    pos: UVec3,
+    liquid: TileType,
+    level: u8,
+    changed: &mut HashSet<UVec3>,
+    tiles: &mut ChangedTiles,
+) {
+    let mut tile = *map_data.get_tile(pos).expect("tile out of bounds");
+    tile.liquid = level;
+    tile.value = if level == 0 { TileType::Air } else { liquid };
+    map_data.update_tile(pos, tile, tiles);
+    changed.insert(pos);
+}
+
+fn set_tile_type(
+    map_data: &mut MapData,
+    pos: UVec3,
+    value: TileType,
+    changed: &mut HashSet<UVec3>,
+    tiles: &mut ChangedTiles,
+) {
+    let mut tile = *map_data.get_tile(pos).expect("tile out of bounds");
+    tile.value = value;
+    tile.liquid = 0;
+    tile.constructed = None;
+    tile.burning = false;
+    map_data.update_tile(pos, tile, tiles);
+    changed.insert(pos);
+}
+
+/// Sets a flammable tile on fire, tiles already burning keep the tick they caught fire
+fn ignite(
+    map_data: &mut MapData,
+    fires: &mut Fires,
+    pos: UVec3,
+    tick: u64,
+    tiles: &mut ChangedTiles,
+) {
+    let mut tile = *map_data.get_tile(pos).expect("tile out of bounds");
+    if tile.burning || !tile.is_flammable() {
+        return;
+    }
+    tile.burning = true;
+    map_data.update_tile(pos, tile, tiles);
+    fires.0.insert(pos, tick);
+}
+
+/// Magma sets the flammable tiles next to it on fire and cools down to obsidian when it touches
+/// water. Returns true if the magma turned to obsidian
+fn react(
+    map_data: &mut MapData,
+    pos: UVec3,
+    tick: u64,
+    fires: &mut Fires,
+    changed: &mut HashSet<UVec3>,
+    tiles: &mut ChangedTiles,
+) -> bool {
+    for neighbor in neighbors(pos) {
+        let tile = *map_data.get_tile(neighbor).expect("tile out of bounds");
+        if tile.is_flammable() {
+            ignite(map_data, fires, neighbor, tick, tiles);
+        } else if tile.value == TileType::Water && tile.liquid > 0 {
+            set_liquid_level(
+                map_data,
+                neighbor,
+                TileType::Water,
+                tile.liquid - 1,
+                changed,
+                tiles,
+            );
+            set_tile_type(map_data, pos, TileType::Obsidian, changed, tiles);
+            return true;
+        }
+    }
+    false
+}
+
+/// Looks through the full tiles connected to `start` for a tile that isn't full.
+/// Liquid can't be pushed higher than `max_z`
+fn find_pressure_outlet(
+    map_data: &MapData,
+    start: UVec3,
+    liquid: TileType,
+    max_z: u32,
+) -> Option<UVec3> {
+    let mut queue = VecDeque::new();
+    let mut visited = HashSet::default();
+    queue.push_back(start);
+    visited.insert(start);
+    while let Some(pos) = queue.pop_front() {
+        if visited.len() > PRESSURE_SEARCH_LIMIT {
+            return None;
+        }
+        for neighbor in neighbors(pos) {
+            if neighbor.z > max_z || !visited.insert(neighbor) {
+                continue;
+            }
+            match liquid_level(map_data, neighbor, liquid) {
+                Some(level) if level < MAX_LIQUID_LEVEL => return Some(neighbor),
+                Some(_) => queue.push_back(neighbor),
+                None => {}
+            }
+        }
+    }
+    None
+}
+
+/// Moves the liquid of a single tile. It falls down first, if the tile below is full it's pushed
+/// through it to a lower z-level, otherwise it spreads to the lowest neighbour.
+/// Returns true if the liquid is magma waiting for its next turn to flow
+fn flow(
+    map_data: &mut MapData,
+    pos: UVec3,
+    tick: u64,
+    fires: &mut Fires,
+    changed: &mut HashSet<UVec3>,
+    tiles: &mut ChangedTiles,
+) -> bool {
+    // a tile is only updated once per tick, otherwise the liquid could cross the whole chunk
+    if changed.contains(&pos) {
+        return false;
+    }
+    let (liquid, level) = match map_data.get_tile(pos) {
+        Some(tile) if tile.value.is_liquid() && tile.liquid > 0 => (tile.value, tile.liquid),
+        _ => return false,
+    };
+
+    if liquid == TileType::Magma {
+        if react(map_data, pos, tick, fires, changed, tiles) {
+            return false;
+        }
+        if tick % MAGMA_FLOW_INTERVAL != 0 {
+            return true;
+        }
+    }
+
+    if pos.z > 0 {
+        let below = pos - UVec3::Z;
+        match liquid_level(map_data, below, liquid) {
+            Some(below_level) if below_level < MAX_LIQUID_LEVEL => {
+                let amount = level.min(MAX_LIQUID_LEVEL - below_level);
+                set_liquid_level(
+                    map_data,
+                    below,
+                    liquid,
+                    below_level + amount,
+                    changed,
+                    tiles,
+                );
+                set_liquid_level(map_data, pos, liquid, level - amount, changed, tiles);
+                return false;
+            }
+            Some(_) => {
+                if let Some(outlet) = find_pressure_outlet(map_data, below, liquid, pos.z - 1) {
+                    let outlet_level =
+                        liquid_level(map_data, outlet, liquid).expect("outlet isn't open");
+                    set_liquid_level(map_data, outlet, liquid, outlet_level + 1, changed, tiles);
+                    set_liquid_level(map_data, pos, liquid, level - 1, changed, tiles);
+                    return false;
+                }
+            }
+            None => {}
+        }
+    }
+
+    let lowest = horizontal_neighbors(pos)
+        .filter_map(|neighbor| {
+            liquid_level(map_data, neighbor, liquid).map(|level| (neighbor, level))
+        })
+        .min_by_key(|(_, level)| *level);
+    if let Some((neighbor, neighbor_level)) = lowest {
+        // a difference of 1 is settled, otherwise a puddle would move around forever
+        if neighbor_level + 1 < level {
+            set_liquid_level(
+                map_data,
+                neighbor,
+                liquid,
+                neighbor_level + 1,
+                changed,
+                tiles,
+            );
+            set_liquid_level(map_data, pos, liquid, level - 1, changed, tiles);
+        }
+    }
+    false
+}
+
+pub fn simulate_fluids(
+    simulation_time: Res<SimulationTime>,
+    mut map_data: ResMut<MapData>,
+    mut fluid_chunks: ResMut<FluidChunks>,
+    mut fires: ResMut<Fires>,
+    mut changed_tiles: ResMut<ChangedTiles>,
+) {
+    if fluid_chunks.awake.is_empty() {
+        return;
+    }
+    let map_data = &mut *map_data;
+    let tiles = &mut *changed_tiles;
+
+    // lower chunks first so falling liquid doesn't move more than once
+    let mut chunks = fluid_chunks.awake.drain().collect::<Vec<_>>();
+    chunks.sort_by_key(|chunk| chunk.z);
+
+    let mut changed = HashSet::default();
+    for chunk in chunks {
+        let min = UVec2::new(chunk.x * FLUID_CHUNK_SIZE, chunk.y * FLUID_CHUNK_SIZE);
+        let max =
+            (min + UVec2::splat(FLUID_CHUNK_SIZE)).min(UVec2::new(WIDTH as u32, HEIGHT as u32));
+        let mut waiting = false;
+        for y in min.y..max.y {
+            for x in min.x..max.x {
+                waiting |= flow(
+                    map_data,
+                    UVec3::new(x, y, chunk.z),
+                    simulation_time.tick,
+                    &mut fires,
+                    &mut changed,
+                    tiles,
+                );
+            }
+        }
+        // magma that hasn't had its turn yet can't be considered settled
+        if waiting {
+            fluid_chunks.awake.insert(chunk);
+        }
+    }
+
+    // chunks where nothing changed are settled and stay asleep
+    for pos in changed {
+        fluid_chunks.wake_around(pos);
+    }
+}
+
+/// Fires spread to the flammable neighbours after a while and the tiles burn down to air.
+/// A tile that stopped being flammable, like a tree that was cut down, stops burning
+fn burn_fires(
+    simulation_time: Res<SimulationTime>,
+    mut map_data: ResMut<MapData>,
+    mut fires: ResMut<Fires>,
+    mut changed_tiles: ResMut<ChangedTiles>,
+) {
+    if fires.0.is_empty() {
+        return;
+    }
+    let tick = simulation_time.tick;
+    let mut spreading = vec![];
+    let mut burnt = vec![];
+    for (pos, started) in fires.0.iter() {
+        let tile = map_data.get_tile(*pos).expect("tile out of bounds");
+        if !tile.burning || !tile.is_flammable() || tick >= started + BURN_TICKS {
+            burnt.push(*pos);
+        } else if tick >= started + FIRE_SPREAD_TICKS {
+            spreading.push(*pos);
+        }
+    }
+
+    for pos in spreading {
+        for neighbor in neighbors(pos) {
+            ignite(
+                &mut map_data,
+                &mut fires,
+                neighbor,
+                tick,
+                &mut changed_tiles,
+            );
+        }
+    }
+    for pos in burnt {
+        fires.0.remove(&pos);
+        let mut tile = *map_data.get_tile(pos).expect("tile out of bounds");
+        if !tile.burning {
+            continue;
+        }
+        if tile.is_flammable() {
+            tile.value = TileType::Air;
+            tile.constructed = None;
+        }
+        tile.burning = false;
+        map_data.update_tile(pos, tile, &mut changed_tiles);
+    }
+}
+
+fn clear_fires(mut events: EventReader<MapGeneratedEvent>, mut fires: ResMut<Fires>) {
+    if events.iter().count() == 0 {
+        return;
+    }
+    fires.0.clear();
+}
+
+/// Any change to the map can make liquid flow again, like digging next to a lake
+fn wake_changed_tiles(changed_tiles: Res<ChangedTiles>, mut fluid_chunks: ResMut<FluidChunks>) {
+    if changed_tiles.0.is_empty() {
+        return;
+    }
+    for (pos, _) in changed_tiles.0.iter() {
+        fluid_chunks.wake_around(*pos);
+    }
+}
+
+fn wake_generated_map(
+    mut events: EventReader<MapGeneratedEvent>,
+    map_data: Res<MapData>,
+    mut fluid_chunks: ResMut<FluidChunks>,
+) {
+    if events.iter().count() == 0 {
+        return;
+    }
+    info!("waking fluid chunks...");
+    let start = Instant::now();
+    fluid_chunks.awake.clear();
+    for z in 0..Z_LEVELS as u32 {
+        for y in 0..HEIGHT as u32 {
+            for x in 0..WIDTH as u32 {
+                let pos = UVec3::new(x, y, z);
+                if matches!(map_data.get_tile(pos), Some(tile) if tile.liquid > 0) {
+                    fluid_chunks.awake.insert(FluidChunks::chunk(pos));
+                }
+            }
+        }
+    }
+    info!(
+        "waking fluid chunks...done elapsed: {:?} awake: {}",
+        start.elapsed(),
+        fluid_chunks.awake.len()
+    );
+}
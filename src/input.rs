@@ -1,56 +1,253 @@
 use crate::{
-    camera::CameraData,
-    map::{CurrentZLevel, Z_LEVELS},
+    actions::{Action, ActionState},
+    camera::{Bookmark, Bookmarks, CameraData, MainCamera, BOOKMARK_COUNT},
+    map::{CurrentZLevel, MapData, RenderMode, ViewRotation, HEIGHT, WIDTH, Z_LEVELS},
+    utils::{cursor_to_world, tile_to_world, world_to_tile},
 };
-use bevy::{input::mouse::MouseWheel, prelude::*};
+use bevy::{app::AppExit, math::Vec3Swizzles, prelude::*, render::camera::Camera};
+use bevy_egui::EguiContext;
 
 const CAMERA_SPEED: f32 = 500.;
+/// Distance from the edge of the window where the camera starts panning, in pixels
+const EDGE_PAN_MARGIN: f32 = 10.0;
 
 pub struct InputPlugin;
 
 impl Plugin for InputPlugin {
     fn build(&self, app: &mut AppBuilder) {
         app.add_system(movement.system())
-            .add_system(mouse_wheel.system());
+            .add_system(drag_camera.system())
+            .add_system(bookmarks.system())
+            .add_system(zoom.system())
+            .add_system(z_level.system())
+            .add_system(jump_to_surface.system())
+            .add_system(quit.system())
+            .add_system(rotation.system())
+            .add_system(render_mode.system());
     }
 }
 
-pub fn movement(keyboard_input: Res<Input<KeyCode>>, mut camera: ResMut<CameraData>) {
+pub fn movement(
+    actions: Res<ActionState>,
+    windows: Res<Windows>,
+    egui_context: Res<EguiContext>,
+    mut camera: ResMut<CameraData>,
+) {
     camera.direction = Vec3::ZERO;
-    if keyboard_input.pressed(KeyCode::A) {
+    let window = windows.get_primary().expect("primary window not found");
+    if let Some(cursor_position) = window.cursor_position() {
+        if window.is_focused() && !egui_context.ctx().is_pointer_over_area() {
+            if cursor_position.x < EDGE_PAN_MARGIN {
+                camera.direction -= Vec3::X;
+            } else if cursor_position.x > window.width() - EDGE_PAN_MARGIN {
+                camera.direction += Vec3::X;
+            }
+            if cursor_position.y < EDGE_PAN_MARGIN {
+                camera.direction -= Vec3::Y;
+            } else if cursor_position.y > window.height() - EDGE_PAN_MARGIN {
+                camera.direction += Vec3::Y;
+            }
+        }
+    }
+    if actions.pressed(Action::CameraLeft) {
         camera.direction -= Vec3::X;
     }
-    if keyboard_input.pressed(KeyCode::D) {
+    if actions.pressed(Action::CameraRight) {
         camera.direction += Vec3::X;
     }
-    if keyboard_input.pressed(KeyCode::W) {
+    if actions.pressed(Action::CameraUp) {
         camera.direction += Vec3::Y;
     }
-    if keyboard_input.pressed(KeyCode::S) {
+    if actions.pressed(Action::CameraDown) {
         camera.direction -= Vec3::Y;
     }
-    if keyboard_input.pressed(KeyCode::LShift) {
+    if actions.pressed(Action::CameraFast) {
         camera.movement_strength = CAMERA_SPEED * 2.0;
     } else {
         camera.movement_strength = CAMERA_SPEED;
     }
 }
 
-pub fn mouse_wheel(
-    keyboard_input: Res<Input<KeyCode>>,
-    mut mouse_wheel_events: EventReader<MouseWheel>,
+/// Keeps the point under the cursor at the same place while dragging
+pub fn drag_camera(
+    actions: Res<ActionState>,
+    windows: Res<Windows>,
+    mut last_cursor_position: Local<Option<Vec2>>,
+    mut camera_query: Query<&mut Transform, With<MainCamera>>,
+) {
+    let window = windows.get_primary().expect("primary window not found");
+    if !actions.pressed(Action::DragCamera) {
+        *last_cursor_position = None;
+        return;
+    }
+    let cursor_position = match window.cursor_position() {
+        Some(cursor_position) => cursor_position,
+        None => return,
+    };
+    if let Some(last_cursor_position) = *last_cursor_position {
+        for mut transform in camera_query.iter_mut() {
+            let delta = (cursor_position - last_cursor_position) * transform.scale.xy();
+            transform.translation -= delta.extend(0.0);
+        }
+    }
+    *last_cursor_position = Some(cursor_position);
+}
+
+pub fn bookmarks(
+    actions: Res<ActionState>,
+    render_mode: Res<RenderMode>,
+    rotation: Res<ViewRotation>,
     mut camera: ResMut<CameraData>,
     mut current_z_level: ResMut<CurrentZLevel>,
+    mut bookmarks: ResMut<Bookmarks>,
+    mut camera_query: Query<&mut Transform, With<MainCamera>>,
 ) {
-    for event in mouse_wheel_events.iter() {
-        if keyboard_input.pressed(KeyCode::LControl) {
-            let new_z_level = (current_z_level.0 as i32 - event.y as i32).clamp(0, Z_LEVELS as i32);
-            current_z_level.0 = new_z_level as u16;
-        } else {
-            camera.scale -= event.y;
-            if camera.scale < 1.0 {
-                camera.scale = 1.0;
+    let mut transform = camera_query.single_mut().expect("main camera not found");
+    for i in 0..BOOKMARK_COUNT {
+        if actions.just_pressed(Action::SaveBookmark(i as u8)) {
+            let position = world_to_tile(
+                transform.translation.xy(),
+                current_z_level.0,
+                *render_mode,
+                *rotation,
+            );
+            bookmarks.0[i] = Some(Bookmark {
+                position,
+                scale: camera.target_scale,
+                z_level: current_z_level.0,
+            });
+            info!("saved bookmark {}", i + 1);
+        } else if actions.just_pressed(Action::RecallBookmark(i as u8)) {
+            if let Some(bookmark) = bookmarks.0[i] {
+                let pos = tile_to_world(
+                    bookmark.position.as_u32().extend(bookmark.z_level as u32),
+                    *render_mode,
+                    *rotation,
+                );
+                transform.translation = pos.extend(transform.translation.z);
+                camera.target_scale = bookmark.scale;
+                current_z_level.0 = bookmark.z_level;
             }
         }
     }
 }
+
+pub fn zoom(actions: Res<ActionState>, mut camera: ResMut<CameraData>) {
+    if actions.just_pressed(Action::ZoomIn) {
+        camera.target_scale = (camera.target_scale - 1.0).max(1.0);
+    }
+    if actions.just_pressed(Action::ZoomOut) {
+        camera.target_scale += 1.0;
+    }
+}
+
+pub fn z_level(actions: Res<ActionState>, mut current_z_level: ResMut<CurrentZLevel>) {
+    let mut new_z_level = current_z_level.0 as i32;
+    if actions.just_pressed(Action::ZLevelUp) {
+        new_z_level += 1;
+    }
+    if actions.just_pressed(Action::ZLevelDown) {
+        new_z_level -= 1;
+    }
+    let new_z_level = new_z_level.clamp(0, Z_LEVELS as i32 - 1) as u16;
+    if new_z_level != current_z_level.0 {
+        current_z_level.0 = new_z_level;
+    }
+}
+
+/// Moves to the z-level of the surface under the cursor
+pub fn jump_to_surface(
+    actions: Res<ActionState>,
+    windows: Res<Windows>,
+    map_data: Res<MapData>,
+    render_mode: Res<RenderMode>,
+    rotation: Res<ViewRotation>,
+    camera_query: Query<(&Camera, &GlobalTransform), With<MainCamera>>,
+    mut current_z_level: ResMut<CurrentZLevel>,
+) {
+    if !actions.just_pressed(Action::JumpToSurface) {
+        return;
+    }
+    let window = windows.get_primary().expect("primary window not found");
+    let (camera, camera_transform) = camera_query.single().expect("main camera not found");
+    let cursor_position = match cursor_to_world(window, camera, camera_transform) {
+        Some(cursor_position) => cursor_position,
+        None => return,
+    };
+
+    // the column under the cursor on the current z-level, the surface is somewhere in it
+    let tile_pos = world_to_tile(cursor_position, current_z_level.0, *render_mode, *rotation);
+    if tile_pos.x < 0.0
+        || tile_pos.y < 0.0
+        || tile_pos.x >= WIDTH as f32
+        || tile_pos.y >= HEIGHT as f32
+    {
+        return;
+    }
+    if let Some(surface) = map_data.find_top_tile(tile_pos.as_u32(), Z_LEVELS - 1) {
+        current_z_level.0 = surface.z as u16;
+    }
+}
+
+pub fn quit(actions: Res<ActionState>, mut app_exit_events: EventWriter<AppExit>) {
+    if actions.just_pressed(Action::Quit) {
+        app_exit_events.send(AppExit);
+    }
+}
+
+pub fn rotation(
+    actions: Res<ActionState>,
+    mut rotation: ResMut<ViewRotation>,
+    render_mode: Res<RenderMode>,
+    current_z_level: Res<CurrentZLevel>,
+    mut camera_query: Query<&mut Transform, With<MainCamera>>,
+) {
+    let new_rotation = if actions.just_pressed(Action::RotateCounterClockwise) {
+        rotation.rotate_ccw()
+    } else if actions.just_pressed(Action::RotateClockwise) {
+        rotation.rotate_cw()
+    } else {
+        return;
+    };
+
+    // keep the camera centered on the same part of the map
+    for mut transform in camera_query.iter_mut() {
+        let z = current_z_level.0;
+        let focused_tile = world_to_tile(transform.translation.xy(), z, *render_mode, *rotation);
+        let pos = tile_to_world(
+            focused_tile.as_u32().extend(z as u32),
+            *render_mode,
+            new_rotation,
+        );
+        transform.translation = pos.extend(transform.translation.z);
+    }
+
+    *rotation = new_rotation;
+}
+
+pub fn render_mode(
+    actions: Res<ActionState>,
+    mut render_mode: ResMut<RenderMode>,
+    rotation: Res<ViewRotation>,
+    current_z_level: Res<CurrentZLevel>,
+    mut camera_query: Query<&mut Transform, With<MainCamera>>,
+) {
+    if !actions.just_pressed(Action::ToggleRenderMode) {
+        return;
+    }
+    let new_render_mode = render_mode.toggle();
+
+    // keep the camera centered on the same tile
+    for mut transform in camera_query.iter_mut() {
+        let z = current_z_level.0;
+        let focused_tile = world_to_tile(transform.translation.xy(), z, *render_mode, *rotation);
+        let pos = tile_to_world(
+            focused_tile.as_u32().extend(z as u32),
+            new_render_mode,
+            *rotation,
+        );
+        transform.translation = pos.extend(transform.translation.z);
+    }
+
+    *render_mode = new_render_mode;
+}
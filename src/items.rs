@@ -0,0 +1,376 @@
+use bevy::{prelude::*, utils::HashMap};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    map::{CurrentZLevel, MapData, MapGeneratedEvent, Material, RenderMode, TilePos, ViewRotation},
+    utils::{floor_translation, is_floor_visible, squirrel_noise},
+};
+
+// TODO
+// * ores and gems once the map has them
+// * items falling when their floor is removed
+
+const ITEM_SEED: u32 = 4242;
+/// Maximum number of logs from a tree
+const MAX_LOGS: u32 = 3;
+/// Maximum number of fruit from a tree, some trees have none
+const MAX_FRUIT: u32 = 2;
+const ITEM_WIDTH: f32 = 12.0;
+const ITEM_HEIGHT: f32 = 8.0;
+
+pub struct ItemsPlugin;
+
+impl Plugin for ItemsPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app.add_startup_system(items_setup.system())
+            .add_event::<SpawnItemEvent>()
+            .insert_resource(ItemIndex::default())
+            .add_system(despawn_items.system().label(ItemLabel::Despawn))
+            .add_system(spawn_items.system().after(ItemLabel::Despawn))
+            .add_system(update_item_sprites.system());
+    }
+}
+
+#[derive(SystemLabel, Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ItemLabel {
+    Despawn,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum ItemKind {
+    Boulder,
+    Log,
+    Block,
+    Bed,
+    Barrel,
+    Charcoal,
+    Fruit,
+    Meal,
+}
+
+impl ItemKind {
+    /// Items of the same kind and material are merged up to this quantity
+    pub fn max_stack(&self) -> u32 {
+        match self {
+            ItemKind::Boulder | ItemKind::Bed | ItemKind::Barrel => 1,
+            ItemKind::Log | ItemKind::Block | ItemKind::Charcoal | ItemKind::Meal => 5,
+            ItemKind::Fruit => 10,
+        }
+    }
+
+    /// Items a builder can use for a construction
+    pub fn is_building_material(&self) -> bool {
+        matches!(self, ItemKind::Boulder | ItemKind::Log | ItemKind::Block)
+    }
+
+    pub fn category(&self) -> ItemCategory {
+        match self {
+            ItemKind::Boulder | ItemKind::Block => ItemCategory::Stone,
+            ItemKind::Log => ItemCategory::Wood,
+            ItemKind::Bed | ItemKind::Barrel => ItemCategory::Furniture,
+            ItemKind::Charcoal => ItemCategory::Fuel,
+            ItemKind::Fruit | ItemKind::Meal => ItemCategory::Food,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ItemKind::Boulder => "boulder",
+            ItemKind::Log => "log",
+            ItemKind::Block => "block",
+            ItemKind::Bed => "bed",
+            ItemKind::Barrel => "barrel",
+            ItemKind::Charcoal => "charcoal",
+            ItemKind::Fruit => "fruit",
+            ItemKind::Meal => "meal",
+        }
+    }
+}
+
+/// Groups of item kinds that stockpiles can accept
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum ItemCategory {
+    Stone,
+    Wood,
+    Furniture,
+    Fuel,
+    Food,
+}
+
+pub const ITEM_CATEGORIES: [ItemCategory; 5] = [
+    ItemCategory::Stone,
+    ItemCategory::Wood,
+    ItemCategory::Furniture,
+    ItemCategory::Fuel,
+    ItemCategory::Food,
+];
+
+/// How well an item was made, only crafted items can be better than ordinary
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Quality {
+    Ordinary,
+    WellCrafted,
+    Superior,
+    Masterwork,
+}
+
+impl Quality {
+    /// Quality of an item made by a crafter, the noise is a random number
+    pub fn roll(skill: u32, attribute: u32, noise: u32) -> Quality {
+        match skill * 4 + attribute + noise % 16 {
+            roll if roll >= 45 => Quality::Masterwork,
+            roll if roll >= 30 => Quality::Superior,
+            roll if roll >= 15 => Quality::WellCrafted,
+            _ => Quality::Ordinary,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Quality::Ordinary => "",
+            Quality::WellCrafted => "well-crafted ",
+            Quality::Superior => "superior ",
+            Quality::Masterwork => "masterwork ",
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Item {
+    pub kind: ItemKind,
+    pub material: Material,
+    pub quantity: u32,
+    pub quality: Quality,
+}
+
+impl Item {
+    pub fn can_stack(&self, other: &Item) -> bool {
+        self.kind == other.kind && self.material == other.material && self.quality == other.quality
+    }
+
+    pub fn name(&self) -> String {
+        if self.quantity == 1 {
+            format!(
+                "{}{:?} {}",
+                self.quality.name(),
+                self.material,
+                self.kind.name()
+            )
+        } else {
+            format!(
+                "{} {}{:?} {}s",
+                self.quantity,
+                self.quality.name(),
+                self.material,
+                self.kind.name()
+            )
+        }
+    }
+}
+
+/// Item left behind when a tile of the material is dug or cut, the position is used to pick the
+/// quantity so the same tile always gives the same yield
+pub fn tile_yield(material: Material, pos: UVec3) -> Option<Item> {
+    let (kind, quantity) = match material {
+        Material::Stone | Material::Obsidian => (ItemKind::Boulder, 1),
+        Material::Wood => {
+            let noise = squirrel_noise((pos.x ^ (pos.y << 10) ^ (pos.z << 20)) as i32, ITEM_SEED);
+            (ItemKind::Log, noise % MAX_LOGS + 1)
+        }
+        Material::Soil | Material::Water | Material::Magma | Material::Plant => return None,
+    };
+    Some(Item {
+        kind,
+        material,
+        quantity,
+        quality: Quality::Ordinary,
+    })
+}
+
+/// Fruit dropped by a tree when it's cut down
+pub fn tree_fruit(pos: UVec3) -> Option<Item> {
+    let noise = squirrel_noise(
+        (pos.x ^ (pos.y << 10) ^ (pos.z << 20)) as i32,
+        ITEM_SEED + 1,
+    );
+    let quantity = noise % (MAX_FRUIT + 1);
+    Some(Item {
+        kind: ItemKind::Fruit,
+        material: Material::Plant,
+        quantity,
+        quality: Quality::Ordinary,
+    })
+    .filter(|item| item.quantity > 0)
+}
+
+/// Item given back when a construction of the material is removed, it's the one it was built from
+pub fn construction_yield(material: Material) -> Item {
+    let kind = match material {
+        Material::Wood => ItemKind::Log,
+        _ => ItemKind::Boulder,
+    };
+    Item {
+        kind,
+        material,
+        quantity: 1,
+        quality: Quality::Ordinary,
+    }
+}
+
+/// Places an item on a tile, it's merged with the stacks already on the tile when possible
+pub struct SpawnItemEvent {
+    pub pos: UVec3,
+    pub item: Item,
+}
+
+/// Items on each tile
+#[derive(Default)]
+pub struct ItemIndex(HashMap<UVec3, Vec<Entity>>);
+
+impl ItemIndex {
+    pub fn items_at(&self, pos: UVec3) -> &[Entity] {
+        self.0.get(&pos).map_or(&[], |items| items.as_slice())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (UVec3, Entity)> + '_ {
+        self.0
+            .iter()
+            .flat_map(|(pos, items)| items.iter().map(move |entity| (*pos, *entity)))
+    }
+
+    pub fn insert(&mut self, pos: UVec3, entity: Entity) {
+        self.0.entry(pos).or_default().push(entity);
+    }
+
+    pub fn remove(&mut self, pos: UVec3, entity: Entity) {
+        if let Some(items) = self.0.get_mut(&pos) {
+            items.retain(|item| *item != entity);
+            if items.is_empty() {
+                self.0.remove(&pos);
+            }
+        }
+    }
+}
+
+pub struct ItemMaterials(HashMap<Material, Handle<ColorMaterial>>);
+
+impl ItemMaterials {
+    pub fn get(&self, material: Material) -> Handle<ColorMaterial> {
+        self.0[&material].clone()
+    }
+}
+
+fn items_setup(mut commands: Commands, mut materials: ResMut<Assets<ColorMaterial>>) {
+    let item_materials = [
+        Material::Water,
+        Material::Magma,
+        Material::Obsidian,
+        Material::Soil,
+        Material::Stone,
+        Material::Wood,
+        Material::Plant,
+    ]
+    .iter()
+    .map(|material| (*material, materials.add(material.color().into())))
+    .collect();
+    commands.insert_resource(ItemMaterials(item_materials));
+}
+
+fn item_sprite(kind: ItemKind) -> Sprite {
+    match kind {
+        ItemKind::Boulder => Sprite::new(Vec2::new(ITEM_WIDTH, ITEM_HEIGHT)),
+        ItemKind::Log => Sprite::new(Vec2::new(ITEM_WIDTH + 4.0, ITEM_HEIGHT / 2.0)),
+        ItemKind::Block => Sprite::new(Vec2::splat(ITEM_HEIGHT)),
+        ItemKind::Bed => Sprite::new(Vec2::new(ITEM_WIDTH + 4.0, ITEM_HEIGHT)),
+        ItemKind::Barrel => Sprite::new(Vec2::new(ITEM_HEIGHT, ITEM_WIDTH)),
+        ItemKind::Charcoal | ItemKind::Fruit | ItemKind::Meal => {
+            Sprite::new(Vec2::splat(ITEM_HEIGHT / 2.0))
+        }
+    }
+}
+
+fn spawn_items(
+    mut commands: Commands,
+    mut events: EventReader<SpawnItemEvent>,
+    materials: Res<ItemMaterials>,
+    render_mode: Res<RenderMode>,
+    rotation: Res<ViewRotation>,
+    mut item_index: ResMut<ItemIndex>,
+    mut query: Query<&mut Item>,
+) {
+    for SpawnItemEvent { pos, item } in events.iter() {
+        let mut item = *item;
+        for entity in item_index.items_at(*pos) {
+            if let Ok(mut stack) = query.get_mut(*entity) {
+                if !stack.can_stack(&item) || stack.quantity >= item.kind.max_stack() {
+                    continue;
+                }
+                let moved = item.quantity.min(item.kind.max_stack() - stack.quantity);
+                stack.quantity += moved;
+                item.quantity -= moved;
+            }
+        }
+
+        while item.quantity > 0 {
+            let quantity = item.quantity.min(item.kind.max_stack());
+            item.quantity -= quantity;
+            let entity = commands
+                .spawn_bundle(SpriteBundle {
+                    material: materials.get(item.material),
+                    sprite: item_sprite(item.kind),
+                    transform: Transform::from_translation(floor_translation(
+                        *pos,
+                        ITEM_HEIGHT,
+                        *render_mode,
+                        *rotation,
+                    )),
+                    ..Default::default()
+                })
+                .insert(Item { quantity, ..item })
+                .insert(TilePos(*pos))
+                .id();
+            item_index.insert(*pos, entity);
+        }
+    }
+}
+
+/// A new map starts without items
+fn despawn_items(
+    mut commands: Commands,
+    mut events: EventReader<MapGeneratedEvent>,
+    mut item_index: ResMut<ItemIndex>,
+    query: Query<Entity, With<Item>>,
+) {
+    if events.iter().count() == 0 {
+        return;
+    }
+    for entity in query.iter() {
+        commands.entity(entity).despawn();
+    }
+    item_index.0.clear();
+}
+
+fn update_item_sprites(
+    map_data: Res<MapData>,
+    current_z_level: Res<CurrentZLevel>,
+    render_mode: Res<RenderMode>,
+    rotation: Res<ViewRotation>,
+    mut query: QuerySet<(
+        Query<(&TilePos, &mut Transform, &mut Visible), With<Item>>,
+        Query<(&TilePos, &mut Transform, &mut Visible), (With<Item>, Changed<TilePos>)>,
+    )>,
+) {
+    let update = |(pos, mut transform, mut visible): (&TilePos, Mut<Transform>, Mut<Visible>)| {
+        transform.translation = floor_translation(pos.0, ITEM_HEIGHT, *render_mode, *rotation);
+        let is_visible = is_floor_visible(&map_data, pos.0, current_z_level.0, *render_mode);
+        if visible.is_visible != is_visible {
+            visible.is_visible = is_visible;
+        }
+    };
+    // the floor of an item can be removed, but it's cheaper to only check it when the view changes
+    if render_mode.is_changed() || rotation.is_changed() || current_z_level.is_changed() {
+        query.q0_mut().iter_mut().for_each(update);
+    } else {
+        query.q1_mut().iter_mut().for_each(update);
+    }
+}
@@ -0,0 +1,1110 @@
+use bevy::{
+    prelude::*,
+    utils::{HashMap, HashSet},
+};
+
+use crate::{
+    creature::{CreatureLabel, Labor, Labors, Movement, Skills, Stats},
+    items::{construction_yield, tile_yield, tree_fruit, Item, ItemIndex, Quality, SpawnItemEvent},
+    map::{
+        horizontal_neighbors, ChangedTiles, Designation, DesignationsChangedEvent, MapData,
+        Material, Tile, TilePos, TileType,
+    },
+    pathfinding::{distance, is_walkable, NavGraph},
+    regions::Regions,
+    simulation::{SimulationStage, SimulationTime},
+    stockpile::Stockpiles,
+    utils::squirrel_noise,
+    workshop::{find_inputs, Recipe, Recipes, Workshop},
+};
+
+// TODO
+// * let the player change the priority of a job
+// * show the suspended jobs
+
+/// Ticks needed to dig soil without any skill
+const BASE_WORK_TICKS: f32 = 30.0;
+/// Each skill level makes the work this much faster
+const SKILL_SPEEDUP: f32 = 0.2;
+/// Number of jobs a worker tries to find a path to before giving up for this tick
+const MAX_CLAIM_ATTEMPTS: usize = 8;
+/// A job whose work site couldn't be reached isn't claimed again for this many ticks
+const SUSPEND_TICKS: u64 = 500;
+/// Experience of a delivered haul, the other jobs give one point per tick of work
+const HAUL_EXPERIENCE: u32 = 5;
+const QUALITY_SEED: u32 = 99;
+
+pub struct JobsPlugin;
+
+impl Plugin for JobsPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app.insert_resource(JobBoard::default())
+            .add_system(sync_designation_jobs.system())
+            .add_system_to_stage(
+                SimulationStage,
+                claim_jobs
+                    .system()
+                    .label(JobLabel::Claim)
+                    .before(CreatureLabel::Move),
+            )
+            .add_system_to_stage(SimulationStage, work.system().after(CreatureLabel::Move));
+    }
+}
+
+#[derive(SystemLabel, Debug, Clone, PartialEq, Eq, Hash)]
+pub enum JobLabel {
+    /// Idle workers pick a job, systems that keep creatures busy should run after it
+    Claim,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct JobId(u32);
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum JobKind {
+    Designation(Designation),
+    /// Carries an item to a stockpile tile
+    Haul {
+        item: Entity,
+        destination: UVec3,
+    },
+    /// Brings the inputs of a recipe to a workshop and makes it
+    Craft {
+        workshop: Entity,
+        recipe: usize,
+        labor: Labor,
+    },
+    /// Eats the reserved food, a job a creature gives itself when it's hungry
+    Eat,
+    /// Drinks from the water next to the job or from the barrel on it
+    Drink,
+    /// Sleeps in the reserved bed, or on the floor without one
+    Sleep,
+}
+
+impl JobKind {
+    /// Needs don't take any skill
+    pub fn labor(&self) -> Option<Labor> {
+        match self {
+            JobKind::Designation(Designation::Chop) => Some(Labor::Woodcutting),
+            JobKind::Designation(Designation::Build(_))
+            | JobKind::Designation(Designation::Deconstruct) => Some(Labor::Construction),
+            JobKind::Designation(_) => Some(Labor::Mining),
+            JobKind::Haul { .. } => Some(Labor::Hauling),
+            JobKind::Craft { labor, .. } => Some(*labor),
+            JobKind::Eat | JobKind::Drink | JobKind::Sleep => None,
+        }
+    }
+
+    /// Jobs a creature gives itself, they are never on the board for someone else
+    pub fn is_need(&self) -> bool {
+        matches!(self, JobKind::Eat | JobKind::Drink | JobKind::Sleep)
+    }
+
+    /// Jobs with a higher priority are claimed first
+    pub fn default_priority(&self) -> u8 {
+        match self {
+            JobKind::Designation(Designation::Chop) => 3,
+            JobKind::Designation(_) => 4,
+            JobKind::Haul { .. } => 2,
+            JobKind::Craft { .. } => 3,
+            JobKind::Eat | JobKind::Drink | JobKind::Sleep => 5,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            JobKind::Designation(designation) => designation.name(),
+            JobKind::Haul { .. } => "Haul",
+            JobKind::Craft { .. } => "Craft",
+            JobKind::Eat => "Eat",
+            JobKind::Drink => "Drink",
+            JobKind::Sleep => "Sleep",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Job {
+    pub kind: JobKind,
+    pub pos: UVec3,
+    pub priority: u8,
+    pub claimed_by: Option<Entity>,
+    /// Items reserved for a construction or a recipe, the worker fetches them before working
+    pub materials: Vec<Entity>,
+    /// The job can't be claimed before this tick
+    pub suspended_until: u64,
+}
+
+impl Job {
+    /// Tiles a worker can stand on to do the job
+    pub fn work_sites(&self) -> Vec<UVec3> {
+        match self.kind {
+            // the floor is removed, so the worker can't stand on it
+            JobKind::Designation(Designation::Channel) => {
+                horizontal_neighbors(self.pos + UVec3::Z).collect()
+            }
+            // the built tile can't be stood on while it's being built, and working from above
+            // would dig out the worker's own floor, a stair or a ramp below isn't a floor
+            JobKind::Designation(_) => horizontal_neighbors(self.pos).collect(),
+            // the item is picked up from its tile
+            JobKind::Haul { .. } => vec![self.pos],
+            // the inputs are brought to the center of the workshop
+            JobKind::Craft { .. } => vec![self.pos],
+            // the water can be reached from its shore, which may be a z-level higher
+            JobKind::Drink => horizontal_neighbors(self.pos)
+                .chain(horizontal_neighbors(self.pos + UVec3::Z))
+                .collect(),
+            JobKind::Eat | JobKind::Sleep => vec![self.pos],
+        }
+    }
+}
+
+/// Every job waiting to be done or in progress
+#[derive(Default)]
+pub struct JobBoard {
+    jobs: HashMap<JobId, Job>,
+    /// Job of each designated tile
+    designation_jobs: HashMap<UVec3, JobId>,
+    next_id: u32,
+}
+
+impl JobBoard {
+    pub fn add(&mut self, kind: JobKind, pos: UVec3) -> JobId {
+        let id = JobId(self.next_id);
+        self.next_id += 1;
+        self.jobs.insert(
+            id,
+            Job {
+                kind,
+                pos,
+                priority: kind.default_priority(),
+                claimed_by: None,
+                materials: vec![],
+                suspended_until: 0,
+            },
+        );
+        if matches!(kind, JobKind::Designation(_)) {
+            self.designation_jobs.insert(pos, id);
+        }
+        id
+    }
+
+    /// Adds a job that is already claimed by a worker, with its reserved items
+    pub fn assign(
+        &mut self,
+        kind: JobKind,
+        pos: UVec3,
+        worker: Entity,
+        materials: Vec<Entity>,
+    ) -> JobId {
+        let id = self.add(kind, pos);
+        if let Some(job) = self.jobs.get_mut(&id) {
+            job.claimed_by = Some(worker);
+            job.materials = materials;
+        }
+        id
+    }
+
+    /// Removes a job, the worker notices it's gone and stops working on it
+    pub fn remove(&mut self, id: JobId) -> Option<Job> {
+        let job = self.jobs.remove(&id)?;
+        if matches!(job.kind, JobKind::Designation(_)) {
+            self.designation_jobs.remove(&job.pos);
+        }
+        Some(job)
+    }
+
+    pub fn get(&self, id: JobId) -> Option<&Job> {
+        self.jobs.get(&id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&JobId, &Job)> {
+        self.jobs.iter()
+    }
+
+    /// Puts the job back on the board for another worker, the needs of a worker are dropped
+    pub fn unclaim(&mut self, id: JobId) {
+        if self.jobs.get(&id).map_or(false, |job| job.kind.is_need()) {
+            self.remove(id);
+            return;
+        }
+        if let Some(job) = self.jobs.get_mut(&id) {
+            job.claimed_by = None;
+            job.materials.clear();
+        }
+    }
+
+    /// Puts back a job the worker couldn't get to, nobody claims it until the given tick
+    pub fn suspend(&mut self, id: JobId, until: u64) {
+        self.unclaim(id);
+        if let Some(job) = self.jobs.get_mut(&id) {
+            job.suspended_until = until;
+        }
+    }
+
+    /// Items that are hauled or used by a job
+    pub fn reserved_items(&self) -> HashSet<Entity> {
+        self.jobs
+            .values()
+            .flat_map(|job| match job.kind {
+                JobKind::Haul { item, .. } => vec![item],
+                _ => job.materials.clone(),
+            })
+            .collect()
+    }
+}
+
+/// Job a creature is working on
+#[derive(Default)]
+pub struct Worker {
+    pub job: Option<JobId>,
+    /// Ticks spent working on the job
+    pub progress: u32,
+    /// Item picked up for a haul job or a construction
+    pub carrying: Option<Entity>,
+}
+
+/// Designations that were removed or replaced cancel their job
+fn sync_designation_jobs(
+    mut events: EventReader<DesignationsChangedEvent>,
+    map_data: Res<MapData>,
+    mut job_board: ResMut<JobBoard>,
+) {
+    if events.iter().count() == 0 {
+        return;
+    }
+    let cancelled = job_board
+        .designation_jobs
+        .iter()
+        .filter(|(pos, id)| match map_data.designations.get(pos) {
+            Some(designation) => job_board.jobs[id].kind != JobKind::Designation(*designation),
+            None => true,
+        })
+        .map(|(_, id)| *id)
+        .collect::<Vec<_>>();
+    for id in cancelled {
+        job_board.remove(id);
+    }
+
+    for (pos, designation) in map_data.designations.iter() {
+        if !job_board.designation_jobs.contains_key(pos) {
+            job_board.add(JobKind::Designation(*designation), *pos);
+        }
+    }
+}
+
+/// Path to the closest reachable work site of the job
+pub fn path_to_job(
+    map_data: &MapData,
+    nav_graph: &NavGraph,
+    regions: &Regions,
+    pos: UVec3,
+    job: &Job,
+) -> Option<Vec<UVec3>> {
+    let mut sites = job
+        .work_sites()
+        .into_iter()
+        .filter(|site| is_walkable(map_data, *site) && regions.is_reachable(pos, *site))
+        .collect::<Vec<_>>();
+    sites.sort_by_key(|site| distance(pos, *site));
+    sites
+        .into_iter()
+        .find_map(|site| nav_graph.find_path(map_data, pos, site))
+}
+
+/// Closest loose item a builder can use, it has to be reachable from the worker
+fn find_material(
+    regions: &Regions,
+    item_index: &ItemIndex,
+    items: &Query<&Item>,
+    reserved: &HashSet<Entity>,
+    pos: UVec3,
+) -> Option<(Entity, UVec3)> {
+    item_index
+        .iter()
+        .filter(|(item_pos, entity)| {
+            !reserved.contains(entity)
+                && items
+                    .get(*entity)
+                    .map_or(false, |item| item.kind.is_building_material())
+                && regions.is_reachable(pos, *item_pos)
+        })
+        .min_by_key(|(item_pos, _)| distance(pos, *item_pos))
+        .map(|(item_pos, entity)| (entity, item_pos))
+}
+
+/// Path between two tiles, `None` if they aren't in the same region
+fn path_between(
+    map_data: &MapData,
+    nav_graph: &NavGraph,
+    regions: &Regions,
+    from: UVec3,
+    to: UVec3,
+) -> Option<Vec<UVec3>> {
+    if !regions.is_reachable(from, to) {
+        return None;
+    }
+    nav_graph.find_path(map_data, from, to)
+}
+
+/// Idle workers claim the job with the highest priority, the closest one first. Builders and
+/// crafters go to their materials first.
+fn claim_jobs(
+    simulation_time: Res<SimulationTime>,
+    map_data: Res<MapData>,
+    nav_graph: Res<NavGraph>,
+    regions: Res<Regions>,
+    stockpiles: Res<Stockpiles>,
+    item_index: Res<ItemIndex>,
+    recipes: Res<Recipes>,
+    mut job_board: ResMut<JobBoard>,
+    mut query: Query<(Entity, &TilePos, &mut Movement, &mut Worker, &Labors)>,
+    items: Query<&Item>,
+    workshops: Query<&Workshop>,
+) {
+    if job_board.jobs.values().all(|job| job.claimed_by.is_some()) {
+        return;
+    }
+    let mut reserved = job_board.reserved_items();
+    let mut stock = None;
+    for (entity, pos, mut movement, mut worker, labors) in query.iter_mut() {
+        if worker.job.is_some() || !movement.is_idle() {
+            continue;
+        }
+        let can_reach_site = |job: &Job| {
+            job.work_sites()
+                .into_iter()
+                .any(|site| is_walkable(&map_data, site) && regions.is_reachable(pos.0, site))
+        };
+        // the unreachable jobs are left out before the attempts are counted, otherwise they
+        // could keep the worker from ever trying the jobs after them
+        let mut candidates = job_board
+            .iter()
+            .filter(|(_, job)| {
+                job.claimed_by.is_none()
+                    && job.suspended_until <= simulation_time.tick
+                    && job
+                        .kind
+                        .labor()
+                        .map_or(true, |labor| labors.is_enabled(labor))
+                    && can_reach_site(job)
+            })
+            .map(|(id, job)| (*id, job.clone()))
+            .collect::<Vec<_>>();
+        candidates
+            .sort_by_key(|(_, job)| (std::cmp::Reverse(job.priority), distance(pos.0, job.pos)));
+
+        // craft jobs whose order was done since they were made
+        let mut done = vec![];
+        let claimed =
+            candidates
+                .into_iter()
+                .take(MAX_CLAIM_ATTEMPTS)
+                .find_map(|(id, job)| match job.kind {
+                    JobKind::Designation(Designation::Build(_)) => {
+                        let (item, item_pos) =
+                            find_material(&regions, &item_index, &items, &reserved, pos.0)?;
+                        nav_graph
+                            .find_path(&map_data, pos.0, item_pos)
+                            .map(|path| (id, vec![item], path))
+                    }
+                    JobKind::Craft {
+                        workshop, recipe, ..
+                    } => {
+                        let needs_work = workshops
+                            .get(workshop)
+                            .ok()
+                            .and_then(|workshop| workshop.order(recipe))
+                            .map_or(false, |order| {
+                                order.needs_work(&recipes, &mut stock, &items)
+                            });
+                        if !needs_work {
+                            done.push(id);
+                            return None;
+                        }
+                        let recipe = recipes.get(recipe)?;
+                        let inputs = find_inputs(
+                            recipe,
+                            &regions,
+                            &stockpiles,
+                            &item_index,
+                            &items,
+                            &reserved,
+                            pos.0,
+                        )?;
+                        let (_, first_pos) = *inputs.first()?;
+                        let inputs = inputs.into_iter().map(|(item, _)| item).collect();
+                        nav_graph
+                            .find_path(&map_data, pos.0, first_pos)
+                            .map(|path| (id, inputs, path))
+                    }
+                    _ => path_to_job(&map_data, &nav_graph, &regions, pos.0, &job)
+                        .map(|path| (id, vec![], path)),
+                });
+        for id in done {
+            job_board.remove(id);
+        }
+        if let Some((id, materials, path)) = claimed {
+            reserved.extend(materials.iter().copied());
+            if let Some(job) = job_board.jobs.get_mut(&id) {
+                job.claimed_by = Some(entity);
+                job.materials = materials;
+            }
+            worker.job = Some(id);
+            worker.progress = 0;
+            movement.follow(path);
+        }
+    }
+}
+
+/// Ticks needed to do the job, skilled and fit workers are faster
+fn work_duration(job: &Job, ticks: f32, skills: &Skills, stats: &Stats) -> u32 {
+    let labor = match job.kind.labor() {
+        Some(labor) => labor,
+        None => return ticks.ceil() as u32,
+    };
+    let skill = skills.level(labor) as f32;
+    (ticks / ((1.0 + skill * SKILL_SPEEDUP) * stats.work_speed(labor))).ceil() as u32
+}
+
+/// Experience in the labor of the job for the worker
+fn gain_experience(name: &Name, skills: &mut Skills, job: &Job, amount: u32) {
+    if let Some(labor) = job.kind.labor() {
+        if skills.gain_experience(labor, amount) {
+            info!(
+                "{} reached level {} in {:?}",
+                name.as_str(),
+                skills.level(labor),
+                labor
+            );
+        }
+    }
+}
+
+/// Changes the map once the job is done, constructions are made of the given material
+fn complete_job(
+    map_data: &mut MapData,
+    changed_tiles: &mut ChangedTiles,
+    designations_changed: &mut EventWriter<DesignationsChangedEvent>,
+    spawn_items: &mut EventWriter<SpawnItemEvent>,
+    job: &Job,
+    material: Option<Material>,
+) {
+    let tile = match map_data.get_tile(job.pos) {
+        Some(tile) => *tile,
+        None => return,
+    };
+    let value = match job.kind {
+        JobKind::Designation(Designation::Dig)
+        | JobKind::Designation(Designation::Channel)
+        | JobKind::Designation(Designation::Chop)
+        | JobKind::Designation(Designation::Deconstruct) => TileType::Air,
+        JobKind::Designation(Designation::UpStair) => TileType::UpStair,
+        JobKind::Designation(Designation::DownStair) => TileType::DownStair,
+        JobKind::Designation(Designation::UpDownStair) => TileType::UpDownStair,
+        JobKind::Designation(Designation::Ramp) => TileType::Ramp,
+        JobKind::Designation(Designation::Build(construction)) => construction.tile_type(),
+        // hauling, crafting and the needs don't change the map
+        JobKind::Haul { .. }
+        | JobKind::Craft { .. }
+        | JobKind::Eat
+        | JobKind::Drink
+        | JobKind::Sleep => return,
+    };
+    let constructed = match job.kind {
+        JobKind::Designation(Designation::Build(_)) => material,
+        _ => None,
+    };
+    map_data.update_tile(
+        job.pos,
+        Tile {
+            value,
+            liquid: 0,
+            constructed,
+            burning: false,
+            ..tile
+        },
+        changed_tiles,
+    );
+
+    // only the tiles that are removed leave something behind
+    if value == TileType::Air {
+        let item = match tile.constructed {
+            Some(material) => Some(construction_yield(material)),
+            None => tile.material().and_then(|m| tile_yield(m, job.pos)),
+        };
+        if let Some(item) = item {
+            spawn_items.send(SpawnItemEvent { pos: job.pos, item });
+        }
+    }
+    if job.kind == JobKind::Designation(Designation::Chop) {
+        if let Some(item) = tree_fruit(job.pos) {
+            spawn_items.send(SpawnItemEvent { pos: job.pos, item });
+        }
+    }
+
+    // a channel leaves a ramp below to climb back up
+    if job.kind == JobKind::Designation(Designation::Channel) && job.pos.z > 0 {
+        let below = job.pos - UVec3::Z;
+        if let Some(below_tile) = map_data
+            .get_tile(below)
+            .copied()
+            .filter(|tile| tile.value.is_solid())
+        {
+            map_data.update_tile(
+                below,
+                Tile {
+                    value: TileType::Ramp,
+                    ..below_tile
+                },
+                changed_tiles,
+            );
+        }
+    }
+    // the job is done, the designation shouldn't create a new one
+    if map_data.designations.remove(&job.pos).is_some() {
+        designations_changed.send(DesignationsChangedEvent);
+    }
+}
+
+/// Puts the carried item down on a tile
+pub fn drop_item(
+    item_index: &mut ItemIndex,
+    items: &mut Query<(&mut TilePos, &mut Item)>,
+    worker: &mut Worker,
+    pos: UVec3,
+) {
+    if let Some(entity) = worker.carrying.take() {
+        if let Ok((mut item_pos, _)) = items.get_mut(entity) {
+            item_pos.0 = pos;
+            item_index.insert(pos, entity);
+        }
+    }
+}
+
+/// Uses one item of the carried stack, the rest of the stack is dropped
+pub fn consume_item(
+    commands: &mut Commands,
+    item_index: &mut ItemIndex,
+    items: &mut Query<(&mut TilePos, &mut Item)>,
+    worker: &mut Worker,
+    pos: UVec3,
+) {
+    let entity = match worker.carrying {
+        Some(entity) => entity,
+        None => return,
+    };
+    match items.get_mut(entity) {
+        Ok((_, mut item)) if item.quantity > 1 => {
+            item.quantity -= 1;
+            drop_item(item_index, items, worker, pos);
+        }
+        _ => {
+            commands.entity(entity).despawn();
+            worker.carrying = None;
+        }
+    }
+}
+
+/// Uses the inputs of the recipe lying at the workshop and puts the outputs there, what's left of
+/// the input stacks stays at the workshop
+fn complete_craft(
+    commands: &mut Commands,
+    item_index: &mut ItemIndex,
+    items: &mut Query<(&mut TilePos, &mut Item)>,
+    spawn_items: &mut EventWriter<SpawnItemEvent>,
+    recipe: &Recipe,
+    job: &Job,
+    quality: Quality,
+) {
+    let mut material = None;
+    for input in recipe.inputs.iter() {
+        let mut needed = input.quantity;
+        for entity in job.materials.iter() {
+            if needed == 0 {
+                break;
+            }
+            let (item_pos, mut item) = match items.get_mut(*entity) {
+                Ok((item_pos, item)) if item.kind == input.kind && item.quantity > 0 => {
+                    (item_pos.0, item)
+                }
+                _ => continue,
+            };
+            let used = needed.min(item.quantity);
+            item.quantity -= used;
+            needed -= used;
+            material.get_or_insert(item.material);
+            if item.quantity == 0 {
+                item_index.remove(item_pos, *entity);
+                commands.entity(*entity).despawn();
+            }
+        }
+    }
+
+    let material = match material {
+        Some(material) => material,
+        None => return,
+    };
+    for output in recipe.outputs.iter() {
+        spawn_items.send(SpawnItemEvent {
+            pos: job.pos,
+            item: Item {
+                kind: output.kind,
+                material,
+                quantity: output.quantity,
+                quality,
+            },
+        });
+    }
+}
+
+/// Picks up the item of a haul job and carries it to the destination.
+/// Returns true once it's delivered.
+fn haul(
+    map_data: &MapData,
+    nav_graph: &NavGraph,
+    regions: &Regions,
+    job_board: &mut JobBoard,
+    item_index: &mut ItemIndex,
+    items: &mut Query<(&mut TilePos, &mut Item)>,
+    id: JobId,
+    job: &Job,
+    pos: UVec3,
+    tick: u64,
+    movement: &mut Movement,
+    worker: &mut Worker,
+) -> bool {
+    let (item, destination) = match job.kind {
+        JobKind::Haul { item, destination } => (item, destination),
+        _ => return false,
+    };
+    if worker.carrying.is_none() {
+        if pos != job.pos {
+            match path_to_job(map_data, nav_graph, regions, pos, job) {
+                Some(path) => movement.follow(path),
+                None => {
+                    job_board.suspend(id, tick + SUSPEND_TICKS);
+                    *worker = Worker::default();
+                }
+            }
+            return false;
+        }
+        // the item was moved or merged since the job was created
+        if !item_index.items_at(pos).contains(&item) {
+            job_board.remove(id);
+            *worker = Worker::default();
+            return false;
+        }
+        item_index.remove(pos, item);
+        worker.carrying = Some(item);
+    }
+
+    if pos == destination {
+        drop_item(item_index, items, worker, destination);
+        job_board.remove(id);
+        *worker = Worker::default();
+        return true;
+    }
+    match path_between(map_data, nav_graph, regions, pos, destination) {
+        Some(path) => movement.follow(path),
+        None => {
+            drop_item(item_index, items, worker, pos);
+            job_board.remove(id);
+            *worker = Worker::default();
+        }
+    }
+    false
+}
+
+/// Goes to an item reserved for the job and picks it up, the job is given up if the item is gone.
+/// Returns true once the worker carries it.
+pub fn fetch_item(
+    map_data: &MapData,
+    nav_graph: &NavGraph,
+    regions: &Regions,
+    job_board: &mut JobBoard,
+    item_index: &mut ItemIndex,
+    items: &mut Query<(&mut TilePos, &mut Item)>,
+    id: JobId,
+    entity: Entity,
+    pos: UVec3,
+    movement: &mut Movement,
+    worker: &mut Worker,
+) -> bool {
+    let item_pos = match items.get_mut(entity) {
+        Ok((item_pos, _)) if item_index.items_at(item_pos.0).contains(&entity) => item_pos.0,
+        // someone else picked it up
+        _ => {
+            job_board.unclaim(id);
+            *worker = Worker::default();
+            return false;
+        }
+    };
+    if pos == item_pos {
+        item_index.remove(pos, entity);
+        worker.carrying = Some(entity);
+        return true;
+    }
+
+    match path_between(map_data, nav_graph, regions, pos, item_pos) {
+        Some(path) => movement.follow(path),
+        None => {
+            job_board.unclaim(id);
+            *worker = Worker::default();
+        }
+    }
+    false
+}
+
+/// Brings the inputs of a craft job to the workshop one at a time.
+/// Returns true once they are all there.
+fn deliver_inputs(
+    map_data: &MapData,
+    nav_graph: &NavGraph,
+    regions: &Regions,
+    job_board: &mut JobBoard,
+    item_index: &mut ItemIndex,
+    items: &mut Query<(&mut TilePos, &mut Item)>,
+    id: JobId,
+    job: &Job,
+    pos: UVec3,
+    movement: &mut Movement,
+    worker: &mut Worker,
+) -> bool {
+    if worker.carrying.is_some() {
+        if pos == job.pos {
+            drop_item(item_index, items, worker, pos);
+        } else {
+            match path_between(map_data, nav_graph, regions, pos, job.pos) {
+                Some(path) => movement.follow(path),
+                None => {
+                    drop_item(item_index, items, worker, pos);
+                    job_board.unclaim(id);
+                    *worker = Worker::default();
+                }
+            }
+            return false;
+        }
+    }
+
+    let mut next = None;
+    for entity in job.materials.iter() {
+        match items.get_mut(*entity) {
+            Ok((item_pos, _)) if item_index.items_at(item_pos.0).contains(entity) => {
+                if item_pos.0 != job.pos {
+                    next = Some(*entity);
+                    break;
+                }
+            }
+            // an input is gone or someone else picked it up
+            _ => {
+                job_board.unclaim(id);
+                *worker = Worker::default();
+                return false;
+            }
+        }
+    }
+    match next {
+        Some(entity) => {
+            fetch_item(
+                map_data, nav_graph, regions, job_board, item_index, items, id, entity, pos,
+                movement, worker,
+            );
+            false
+        }
+        None => true,
+    }
+}
+
+fn work(
+    mut commands: Commands,
+    simulation_time: Res<SimulationTime>,
+    mut map_data: ResMut<MapData>,
+    nav_graph: Res<NavGraph>,
+    regions: Res<Regions>,
+    recipes: Res<Recipes>,
+    mut job_board: ResMut<JobBoard>,
+    mut changed_tiles: ResMut<ChangedTiles>,
+    mut item_index: ResMut<ItemIndex>,
+    mut spawn_items: EventWriter<SpawnItemEvent>,
+    mut designations_changed: EventWriter<DesignationsChangedEvent>,
+    mut query: Query<
+        (
+            Entity,
+            &Name,
+            &TilePos,
+            &mut Movement,
+            &mut Worker,
+            &mut Skills,
+            &Stats,
+            &Labors,
+        ),
+        Without<Item>,
+    >,
+    mut items: Query<(&mut TilePos, &mut Item)>,
+    mut workshops: Query<&mut Workshop>,
+) {
+    for (entity, name, pos, mut movement, mut worker, mut skills, stats, labors) in query.iter_mut()
+    {
+        // carried items move with their worker
+        if let Some(carried) = worker.carrying {
+            if let Ok((mut item_pos, _)) = items.get_mut(carried) {
+                if item_pos.0 != pos.0 {
+                    item_pos.0 = pos.0;
+                }
+            }
+        }
+        let id = match worker.job {
+            Some(id) => id,
+            None => continue,
+        };
+        let job = match job_board.get(id) {
+            Some(job) => job.clone(),
+            // the job was cancelled
+            None => {
+                drop_item(&mut item_index, &mut items, &mut worker, pos.0);
+                *worker = Worker::default();
+                movement.stop();
+                continue;
+            }
+        };
+        // the needs are taken care of by the creatures themselves
+        if job.kind.is_need() {
+            continue;
+        }
+        // the labor was disabled while the worker was on the job
+        if job
+            .kind
+            .labor()
+            .map_or(false, |labor| !labors.is_enabled(labor))
+        {
+            drop_item(&mut item_index, &mut items, &mut worker, pos.0);
+            job_board.unclaim(id);
+            *worker = Worker::default();
+            movement.stop();
+            continue;
+        }
+        if !movement.is_idle() {
+            continue;
+        }
+
+        match job.kind {
+            JobKind::Haul { .. } => {
+                let delivered = haul(
+                    &map_data,
+                    &nav_graph,
+                    &regions,
+                    &mut job_board,
+                    &mut item_index,
+                    &mut items,
+                    id,
+                    &job,
+                    pos.0,
+                    simulation_time.tick,
+                    &mut movement,
+                    &mut worker,
+                );
+                if delivered {
+                    gain_experience(name, &mut skills, &job, HAUL_EXPERIENCE);
+                }
+                continue;
+            }
+            JobKind::Designation(Designation::Build(_)) if worker.carrying.is_none() => {
+                let fetched = match job.materials.first() {
+                    Some(entity) => fetch_item(
+                        &map_data,
+                        &nav_graph,
+                        &regions,
+                        &mut job_board,
+                        &mut item_index,
+                        &mut items,
+                        id,
+                        *entity,
+                        pos.0,
+                        &mut movement,
+                        &mut worker,
+                    ),
+                    None => {
+                        job_board.unclaim(id);
+                        *worker = Worker::default();
+                        false
+                    }
+                };
+                if !fetched {
+                    continue;
+                }
+            }
+            JobKind::Craft { .. } => {
+                let delivered = deliver_inputs(
+                    &map_data,
+                    &nav_graph,
+                    &regions,
+                    &mut job_board,
+                    &mut item_index,
+                    &mut items,
+                    id,
+                    &job,
+                    pos.0,
+                    &mut movement,
+                    &mut worker,
+                );
+                if !delivered {
+                    continue;
+                }
+            }
+            _ => {}
+        }
+
+        if !job.work_sites().contains(&pos.0) {
+            // the path was interrupted by a change to the map
+            match path_to_job(&map_data, &nav_graph, &regions, pos.0, &job) {
+                Some(path) => movement.follow(path),
+                None => {
+                    drop_item(&mut item_index, &mut items, &mut worker, pos.0);
+                    job_board.suspend(id, simulation_time.tick + SUSPEND_TICKS);
+                    *worker = Worker::default();
+                }
+            }
+            continue;
+        }
+
+        if let JobKind::Craft {
+            workshop, recipe, ..
+        } = job.kind
+        {
+            let recipe_data = match recipes.get(recipe) {
+                Some(recipe_data) => recipe_data,
+                None => {
+                    job_board.remove(id);
+                    *worker = Worker::default();
+                    continue;
+                }
+            };
+            worker.progress += 1;
+            if worker.progress >= work_duration(&job, recipe_data.ticks as f32, &skills, stats) {
+                let labor = recipe_data.labor;
+                let noise = squirrel_noise(simulation_time.tick as i32, QUALITY_SEED ^ entity.id());
+                let quality = Quality::roll(skills.level(labor), stats.for_labor(labor), noise);
+                complete_craft(
+                    &mut commands,
+                    &mut item_index,
+                    &mut items,
+                    &mut spawn_items,
+                    recipe_data,
+                    &job,
+                    quality,
+                );
+                gain_experience(name, &mut skills, &job, worker.progress);
+                if let Ok(mut workshop) = workshops.get_mut(workshop) {
+                    workshop.complete_order(recipe);
+                }
+                job_board.remove(id);
+                *worker = Worker::default();
+            }
+            continue;
+        }
+
+        // constructions take as long as their material, the rest as long as the tile
+        let material = match worker.carrying {
+            Some(carried) => items.get_mut(carried).ok().map(|(_, item)| item.material),
+            None => map_data.get_tile(job.pos).and_then(|tile| tile.material()),
+        };
+        let hardness = material.map_or(1.0, |material| material.hardness());
+        worker.progress += 1;
+        if worker.progress >= work_duration(&job, BASE_WORK_TICKS * hardness, &skills, stats) {
+            complete_job(
+                &mut map_data,
+                &mut changed_tiles,
+                &mut designations_changed,
+                &mut spawn_items,
+                &job,
+                material,
+            );
+            gain_experience(name, &mut skills, &job, worker.progress);
+            consume_item(
+                &mut commands,
+                &mut item_index,
+                &mut items,
+                &mut worker,
+                pos.0,
+            );
+            job_board.remove(id);
+            *worker = Worker::default();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::map::{HEIGHT, WIDTH, Z_LEVELS};
+
+    fn tile(value: TileType) -> Tile {
+        Tile {
+            visible: true,
+            value,
+            ..Default::default()
+        }
+    }
+
+    /// Two layers of rock with a corridor dug out along `y = 4` on the upper one
+    fn corridor_map() -> MapData {
+        let mut map_data = MapData::new(WIDTH, HEIGHT, Z_LEVELS as usize);
+        for z in 0..2 {
+            for y in 0..8 {
+                for x in 0..8 {
+                    let value = if z == 1 && y == 4 {
+                        TileType::Air
+                    } else {
+                        TileType::Rock
+                    };
+                    map_data.set_tile(UVec3::new(x, y, z), tile(value)).unwrap();
+                }
+            }
+        }
+        map_data
+    }
+
+    #[test]
+    fn carving_stairs_and_ramps_keeps_the_worker_on_a_floor() {
+        let nav_graph = NavGraph::default();
+        let pos = UVec3::new(4, 5, 1);
+        let carved = [
+            (Designation::UpStair, TileType::UpStair),
+            (Designation::DownStair, TileType::DownStair),
+            (Designation::UpDownStair, TileType::UpDownStair),
+            (Designation::Ramp, TileType::Ramp),
+        ];
+        for (designation, value) in carved.iter() {
+            let mut map_data = corridor_map();
+            let job = Job {
+                kind: JobKind::Designation(*designation),
+                pos,
+                priority: 0,
+                claimed_by: None,
+                materials: vec![],
+                suspended_until: 0,
+            };
+            let sites = job
+                .work_sites()
+                .into_iter()
+                .filter(|site| is_walkable(&map_data, *site))
+                .collect::<Vec<_>>();
+            assert!(!sites.is_empty(), "{:?} can't be reached", designation);
+            assert!(!sites.contains(&(pos + UVec3::Z)), "{:?}", designation);
+
+            map_data.set_tile(pos, tile(*value)).unwrap();
+            let corridor_end = UVec3::new(0, 4, 1);
+            for site in sites {
+                assert!(is_walkable(&map_data, site), "{:?} {}", designation, site);
+                assert!(
+                    nav_graph.find_path(&map_data, site, corridor_end).is_some(),
+                    "{:?} {}",
+                    designation,
+                    site
+                );
+            }
+        }
+    }
+}
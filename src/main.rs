@@ -1,4 +1,4 @@
-#![allow(clippy::type_complexity)]
+#![allow(clippy::type_complexity, clippy::too_many_arguments)]
 
 use std::collections::VecDeque;
 
@@ -10,11 +10,28 @@ use bevy::{
 use bevy_egui::EguiPlugin;
 use bevy_egui::{egui, EguiContext};
 
+mod actions;
 mod camera;
+mod creature;
+mod designation;
+mod fluid;
 mod input;
+mod items;
+mod jobs;
 pub mod map;
+mod minimap;
+mod needs;
+mod pathfinding;
+mod regions;
+mod save;
 mod selector;
+mod simulation;
+mod stockpile;
+mod structure;
+mod tile_info;
 mod utils;
+mod workshop;
+mod z_level;
 
 const FRAME_TIME_HISTORY_LEN: usize = 100;
 
@@ -94,11 +111,27 @@ fn main() {
         .add_plugins(DefaultPlugins)
         .add_plugin(FrameTimeDiagnosticsPlugin::default())
         .add_plugin(EguiPlugin)
+        .add_plugin(actions::ActionsPlugin)
+        .add_plugin(save::SavePlugin)
         .add_plugin(camera::CameraControlPlugin)
         .add_plugin(map::MapPlugin)
         .add_plugin(input::InputPlugin)
         .add_plugin(selector::SelectorPlugin)
-        .add_system(bevy::input::system::exit_on_esc_system.system())
+        .add_plugin(designation::DesignationPlugin)
+        .add_plugin(tile_info::TileInfoPlugin)
+        .add_plugin(z_level::ZLevelPlugin)
+        .add_plugin(minimap::MinimapPlugin)
+        .add_plugin(simulation::SimulationPlugin)
+        .add_plugin(fluid::FluidPlugin)
+        .add_plugin(pathfinding::PathfindingPlugin)
+        .add_plugin(regions::RegionsPlugin)
+        .add_plugin(creature::CreaturePlugin)
+        .add_plugin(jobs::JobsPlugin)
+        .add_plugin(needs::NeedsPlugin)
+        .add_plugin(items::ItemsPlugin)
+        .add_plugin(stockpile::StockpilePlugin)
+        .add_plugin(structure::StructurePlugin)
+        .add_plugin(workshop::WorkshopPlugin)
         .add_system(set_texture_filters_to_nearest.system())
         .add_system(performance_display.system())
         .insert_resource(FrameTimeHistory(VecDeque::with_capacity(
@@ -2,16 +2,25 @@ use bevy::{prelude::*, utils::Instant};
 use bevy_inspector_egui::Inspectable;
 use noise::{NoiseFn, SuperSimplex};
 
-use crate::utils::inverse_lerp;
+use crate::utils::{inverse_lerp, squirrel_noise};
 
 use super::{
-    MapData, MapGeneratedEvent, Tile, TileType, ELEVATION_MULTIPLIER, HEIGHT, WIDTH, Z_LEVELS,
+    Biome, MapData, MapGeneratedEvent, Tile, TileType, ELEVATION_MULTIPLIER, HEIGHT,
+    MAX_LIQUID_LEVEL, WIDTH, Z_LEVELS,
 };
 
 // TODO
 // * try to avoid using constants to make it more dynamic
 // * generate in AsyncTaskPool
 
+/// Chance out of 100 for a grass tile to have a tree on it
+const TREE_CHANCE: u32 = 4;
+const TREE_SEED: u32 = 42;
+/// Everything below this elevation is under water
+const WATER_LEVEL: f32 = 0.35;
+/// Number of z-levels at the bottom of the map where the rock is replaced by magma
+pub const MAGMA_SEA_DEPTH: u16 = 1;
+
 #[derive(Inspectable)]
 pub struct NoiseSettings {
     #[inspectable(visual, min = Vec2::splat(-2.0), max = Vec2::splat(2.0))]
@@ -66,7 +75,7 @@ pub fn generate_map(
                     ((elevation - z_level).abs() * Z_LEVELS as f32).round() / Z_LEVELS as f32;
 
                 let value = if rounded_elevation_diff < ELEVATION_MULTIPLIER {
-                    if elevation <= 0.35 {
+                    if elevation <= WATER_LEVEL {
                         TileType::Water
                     } else {
                         TileType::Grass
@@ -75,17 +84,27 @@ pub fn generate_map(
                     // everything under the grass is rocks
                     if rounded_elevation_diff <= ELEVATION_MULTIPLIER * 2.0 {
                         TileType::Dirt
+                    } else if z < MAGMA_SEA_DEPTH {
+                        TileType::Magma
                     } else {
                         TileType::Rock
                     }
-                } else if z_level <= 0.35 {
+                } else if z_level <= WATER_LEVEL {
                     TileType::Water
                 } else {
                     TileType::Air
                 };
+                let liquid = if value.is_liquid() {
+                    MAX_LIQUID_LEVEL
+                } else {
+                    0
+                };
                 let tile = Tile {
                     value,
                     visible: true,
+                    liquid,
+                    constructed: None,
+                    burning: false,
                 };
                 let layer = &mut map.layers[z as usize];
                 layer
@@ -94,10 +113,52 @@ pub fn generate_map(
             }
         }
     }
+    for (biome, elevation) in map.biomes.iter_mut().zip(elevation_map.iter()) {
+        *biome = biome_from_elevation(*elevation);
+    }
+    place_trees(&mut map);
     info!("generating map...done elapsed: {:?}", start.elapsed());
     event.send(MapGeneratedEvent);
 }
 
+fn biome_from_elevation(elevation: f32) -> Biome {
+    if elevation <= WATER_LEVEL {
+        Biome::Lake
+    } else if elevation < 0.6 {
+        Biome::Grassland
+    } else if elevation < 0.8 {
+        Biome::Hills
+    } else {
+        Biome::Mountains
+    }
+}
+
+fn place_trees(map: &mut MapData) {
+    for y in 0..HEIGHT {
+        for x in 0..WIDTH {
+            if squirrel_noise((y * WIDTH + x) as i32, TREE_SEED) % 100 >= TREE_CHANCE {
+                continue;
+            }
+            let surface = match map.find_top_tile(UVec2::new(x as u32, y as u32), Z_LEVELS - 1) {
+                Some(surface) => surface,
+                None => continue,
+            };
+            let is_grass = matches!(map.get_tile(surface), Some(tile) if matches!(tile.value, TileType::Grass));
+            if is_grass && surface.z + 1 < Z_LEVELS as u32 {
+                let tile = Tile {
+                    value: TileType::Tree,
+                    visible: true,
+                    liquid: 0,
+                    constructed: None,
+                    burning: false,
+                };
+                map.set_tile(surface + UVec3::Z, tile)
+                    .expect("generated tile out of bounds");
+            }
+        }
+    }
+}
+
 fn generate_elevation_map(
     width: usize,
     height: usize,
@@ -1,12 +1,15 @@
 use anyhow::{bail, Result};
-use bevy::prelude::*;
+use bevy::{prelude::*, utils::HashMap};
 use bevy_ecs_tilemap::prelude::*;
 use bevy_inspector_egui::InspectorPlugin;
 use noise::{Seedable, SuperSimplex};
 
 use self::{
     generator::{generate_map, NoiseSettings},
-    renderer::{set_map_textures, update_layer_visibility, update_tiles},
+    renderer::{
+        add_liquid_textures, set_map_textures, set_top_down_textures, top_down_texture,
+        update_layer_visibility, update_tiles,
+    },
 };
 
 pub mod generator;
@@ -28,30 +31,168 @@ pub const TILE_BATCH_SIZE: usize = TOTAL_TILE_COUNT / 6;
 pub const TILE_WIDTH: usize = 32;
 pub const TILE_HEIGHT: usize = 32;
 
-pub const TEXTURE_WIDTH: usize = 32 * 6;
+/// Tiles of the texture file, the liquid tiles of each level are added after them once it's loaded
+pub const TEXTURE_FILE_TILES: usize = 6;
+pub const TEXTURE_WIDTH: usize = 32 * (TEXTURE_FILE_TILES + 2 * MAX_LIQUID_LEVEL as usize);
 pub const TEXTURE_HEIGHT: usize = 32;
 
+/// A tile with this much liquid is full
+pub const MAX_LIQUID_LEVEL: u8 = 7;
+
+pub const ISO_MAP_ID: u16 = 0;
+pub const TOP_DOWN_MAP_ID: u16 = 1;
+
 pub struct MapGeneratedEvent;
 
+/// Sent when designations are added to or removed from `MapData`, the tile changes are tracked
+/// apart so the designations aren't checked again on every tile change
+pub struct DesignationsChangedEvent;
+
+/// Texture of the isometric tiles
+pub struct IsoTexture(pub Handle<Texture>);
+
+#[derive(SystemLabel, Debug, Clone, PartialEq, Eq, Hash)]
+pub enum MapLabel {
+    /// Draws the tiles recorded in `ChangedTiles` and clears them, the systems tracking the map
+    /// need to read them before it
+    UpdateTiles,
+}
+
+/// Highest visible z-level, it's always a valid layer index
 pub struct CurrentZLevel(pub u16);
 
-// TODO consider using a queue
-// Maybe tag existing tiles instead and query tiles with the tag
-pub struct TilesToUpdate(pub Vec<(UVec3, Tile)>);
+/// How the map is drawn, both modes use the same `MapData` and chunk layout
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum RenderMode {
+    Isometric,
+    /// Shows the current z-level as a flat grid, lower levels visible through air are shaded
+    TopDown,
+}
+
+impl RenderMode {
+    pub fn toggle(self) -> Self {
+        match self {
+            RenderMode::Isometric => RenderMode::TopDown,
+            RenderMode::TopDown => RenderMode::Isometric,
+        }
+    }
+}
+
+/// Orientation of the isometric view, the map is rotated around its center in 90 degree steps.
+/// This assumes the map is square, otherwise rotated tiles wouldn't fit in the tilemap.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ViewRotation {
+    North,
+    East,
+    South,
+    West,
+}
+
+impl Default for ViewRotation {
+    fn default() -> Self {
+        ViewRotation::North
+    }
+}
+
+impl ViewRotation {
+    pub fn rotate_cw(self) -> Self {
+        match self {
+            ViewRotation::North => ViewRotation::East,
+            ViewRotation::East => ViewRotation::South,
+            ViewRotation::South => ViewRotation::West,
+            ViewRotation::West => ViewRotation::North,
+        }
+    }
+
+    pub fn rotate_ccw(self) -> Self {
+        match self {
+            ViewRotation::North => ViewRotation::West,
+            ViewRotation::East => ViewRotation::North,
+            ViewRotation::South => ViewRotation::East,
+            ViewRotation::West => ViewRotation::South,
+        }
+    }
+
+    /// The tile sprites are mirrored when looking at the map from the sides
+    pub fn flip_x(self) -> bool {
+        matches!(self, ViewRotation::East | ViewRotation::West)
+    }
+
+    /// Converts a position in `MapData` to the position of the tile displaying it
+    pub fn map_to_view(self, pos: Vec2) -> Vec2 {
+        let max = (WIDTH - 1) as f32;
+        match self {
+            ViewRotation::North => pos,
+            ViewRotation::East => Vec2::new(max - pos.y, pos.x),
+            ViewRotation::South => Vec2::new(max - pos.x, max - pos.y),
+            ViewRotation::West => Vec2::new(pos.y, max - pos.x),
+        }
+    }
+
+    /// Converts the position of a displayed tile to its position in `MapData`
+    pub fn view_to_map(self, pos: Vec2) -> Vec2 {
+        let max = (WIDTH - 1) as f32;
+        match self {
+            ViewRotation::North => pos,
+            ViewRotation::East => Vec2::new(pos.y, max - pos.x),
+            ViewRotation::South => Vec2::new(max - pos.x, max - pos.y),
+            ViewRotation::West => Vec2::new(max - pos.y, pos.x),
+        }
+    }
+}
+
+/// Tiles changed since the last `MapLabel::UpdateTiles` with the tile each change replaced, a
+/// tile changed more than once is recorded each time
+#[derive(Default)]
+pub struct ChangedTiles(pub Vec<(UVec3, Tile)>);
 
 #[derive(Copy, Clone, Default)]
 pub struct Tile {
     pub visible: bool,
     pub value: TileType,
+    /// Depth of the liquid in the tile, from 0 to `MAX_LIQUID_LEVEL`
+    pub liquid: u8,
+    /// Material the tile was built from, `None` for natural tiles
+    pub constructed: Option<Material>,
+    /// On fire, the tile burns down after a while
+    pub burning: bool,
 }
 
-#[derive(Copy, Clone, Debug)]
+impl Tile {
+    /// Constructions are made of the material they were built from instead of the default one
+    pub fn material(&self) -> Option<Material> {
+        self.constructed.or_else(|| self.value.material())
+    }
+
+    pub fn is_flammable(&self) -> bool {
+        self.value.is_flammable() || self.constructed == Some(Material::Wood)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum TileType {
     Air,
     Water,
     Grass,
     Rock,
     Dirt,
+    Tree,
+    Magma,
+    /// Created when water meets magma
+    Obsidian,
+    /// Leads to a down stair on the z-level above
+    UpStair,
+    /// Leads to an up stair on the z-level below
+    DownStair,
+    UpDownStair,
+    /// Leads to the tile above it, which can be walked on
+    Ramp,
+    /// Lets creatures through but blocks liquids
+    Door,
+    /// Built block, it's solid like natural rock
+    Wall,
+    /// Built slab to walk on, it's solid but drawn lower than a wall
+    Floor,
 }
 
 impl Default for TileType {
@@ -60,6 +201,217 @@ impl Default for TileType {
     }
 }
 
+impl TileType {
+    /// Flat colour used when the tile isn't drawn with the isometric sprites
+    pub fn color(&self) -> Color {
+        match self {
+            TileType::Air => Color::NONE,
+            TileType::Water => Color::rgb_u8(93, 127, 153),
+            TileType::Grass => Color::rgb_u8(82, 107, 45),
+            TileType::Dirt => Color::rgb_u8(89, 64, 51),
+            TileType::Rock => Color::rgb_u8(99, 99, 99),
+            TileType::Tree => Color::rgb_u8(46, 82, 36),
+            TileType::Magma => Color::rgb_u8(207, 80, 16),
+            TileType::Obsidian => Color::rgb_u8(36, 30, 46),
+            TileType::UpStair | TileType::DownStair | TileType::UpDownStair => {
+                Color::rgb_u8(140, 140, 150)
+            }
+            TileType::Ramp => Color::rgb_u8(120, 110, 100),
+            TileType::Door => Color::rgb_u8(130, 90, 50),
+            TileType::Wall => Color::rgb_u8(125, 120, 115),
+            TileType::Floor => Color::rgb_u8(110, 105, 95),
+        }
+    }
+
+    /// Solid tiles can be dug and support what's above them
+    pub fn is_solid(&self) -> bool {
+        matches!(
+            self,
+            TileType::Grass
+                | TileType::Rock
+                | TileType::Dirt
+                | TileType::Obsidian
+                | TileType::Wall
+                | TileType::Floor
+        )
+    }
+
+    pub fn is_liquid(&self) -> bool {
+        matches!(self, TileType::Water | TileType::Magma)
+    }
+
+    /// Liquids can flow in open tiles
+    pub fn is_open(&self) -> bool {
+        *self == TileType::Air || self.is_liquid()
+    }
+
+    /// Flammable tiles burn when they are next to magma
+    pub fn is_flammable(&self) -> bool {
+        matches!(self, TileType::Tree | TileType::Door)
+    }
+
+    /// Stairs going to the z-level above
+    pub fn goes_up(&self) -> bool {
+        matches!(self, TileType::UpStair | TileType::UpDownStair)
+    }
+
+    /// Stairs going to the z-level below
+    pub fn goes_down(&self) -> bool {
+        matches!(self, TileType::DownStair | TileType::UpDownStair)
+    }
+
+    /// Tiles that hold up the tiles attached to them
+    pub fn is_structural(&self) -> bool {
+        self.is_solid() || self.goes_up() || self.goes_down() || *self == TileType::Ramp
+    }
+
+    pub fn material(&self) -> Option<Material> {
+        match self {
+            TileType::Air => None,
+            TileType::Water => Some(Material::Water),
+            TileType::Grass | TileType::Dirt => Some(Material::Soil),
+            TileType::Rock => Some(Material::Stone),
+            TileType::Tree => Some(Material::Wood),
+            TileType::Magma => Some(Material::Magma),
+            TileType::Obsidian => Some(Material::Obsidian),
+            // TODO keep the material of the tile they were carved from
+            TileType::UpStair
+            | TileType::DownStair
+            | TileType::UpDownStair
+            | TileType::Ramp
+            | TileType::Wall
+            | TileType::Floor => Some(Material::Stone),
+            TileType::Door => Some(Material::Wood),
+        }
+    }
+}
+
+/// What a tile is made of
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Material {
+    Water,
+    Magma,
+    Obsidian,
+    Soil,
+    Stone,
+    Wood,
+    /// Fruit and what's cooked from it
+    Plant,
+}
+
+impl Material {
+    /// How long it takes to dig or cut the material, relative to soil
+    pub fn hardness(&self) -> f32 {
+        match self {
+            Material::Water | Material::Magma => 0.0,
+            Material::Soil => 1.0,
+            Material::Wood => 1.5,
+            Material::Plant => 0.5,
+            Material::Stone => 3.0,
+            Material::Obsidian => 5.0,
+        }
+    }
+
+    pub fn color(&self) -> Color {
+        match self {
+            Material::Water => TileType::Water.color(),
+            Material::Magma => TileType::Magma.color(),
+            Material::Obsidian => TileType::Obsidian.color(),
+            Material::Soil => TileType::Dirt.color(),
+            Material::Stone => Color::rgb_u8(140, 140, 140),
+            Material::Wood => Color::rgb_u8(133, 94, 56),
+            Material::Plant => Color::rgb_u8(160, 60, 90),
+        }
+    }
+}
+
+/// Biomes are chosen from the elevation of each column of the map
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Biome {
+    Lake,
+    Grassland,
+    Hills,
+    Mountains,
+}
+
+impl Default for Biome {
+    fn default() -> Self {
+        Biome::Grassland
+    }
+}
+
+/// Position of an entity on the map, in map coordinates
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct TilePos(pub UVec3);
+
+/// What can be built on an open tile
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Construction {
+    Wall,
+    Floor,
+    UpStair,
+    DownStair,
+    UpDownStair,
+    Ramp,
+}
+
+impl Construction {
+    pub fn tile_type(&self) -> TileType {
+        match self {
+            Construction::Wall => TileType::Wall,
+            Construction::Floor => TileType::Floor,
+            Construction::UpStair => TileType::UpStair,
+            Construction::DownStair => TileType::DownStair,
+            Construction::UpDownStair => TileType::UpDownStair,
+            Construction::Ramp => TileType::Ramp,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Construction::Wall => "Build wall",
+            Construction::Floor => "Build floor",
+            Construction::UpStair => "Build up stair",
+            Construction::DownStair => "Build down stair",
+            Construction::UpDownStair => "Build up/down stair",
+            Construction::Ramp => "Build ramp",
+        }
+    }
+}
+
+/// Pending order on a tile, it doesn't change the tile until the work is done
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Designation {
+    Dig,
+    /// Digs the tile and removes the floor of the tile above, leaving a ramp below
+    Channel,
+    UpStair,
+    DownStair,
+    UpDownStair,
+    Ramp,
+    Chop,
+    /// Builds on an open tile with a material item brought by the builder
+    Build(Construction),
+    /// Removes a construction and gives back its material
+    Deconstruct,
+}
+
+impl Designation {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Designation::Dig => "Dig",
+            Designation::Channel => "Channel",
+            Designation::UpStair => "Up stair",
+            Designation::DownStair => "Down stair",
+            Designation::UpDownStair => "Up/down stair",
+            Designation::Ramp => "Ramp",
+            Designation::Chop => "Chop",
+            Designation::Build(construction) => construction.name(),
+            Designation::Deconstruct => "Deconstruct",
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct Layer {
     data: Vec<Tile>,
@@ -75,10 +427,17 @@ impl Layer {
 
 impl Layer {
     pub fn get_tile(&self, x: usize, y: usize) -> Option<&Tile> {
+        // without this check, an x past the edge would wrap around to the next row
+        if x >= WIDTH {
+            return None;
+        }
         self.data.get(y * WIDTH + x)
     }
 
     pub fn set_tile(&mut self, x: usize, y: usize, new_tile: Tile) -> Result<()> {
+        if x >= WIDTH {
+            bail!("tile out of bounds")
+        }
         if let Some(elem) = self.data.get_mut(y * WIDTH + x) {
             *elem = new_tile;
             Ok(())
@@ -90,20 +449,19 @@ impl Layer {
 
 pub struct MapData {
     pub layers: Vec<Layer>,
-}
-
-pub struct VisibleLayers(Vec<bool>);
-
-impl VisibleLayers {
-    pub fn new(z_levels: usize) -> Self {
-        Self(vec![true; z_levels])
-    }
+    /// Designations are sparse so they are stored separately from the tiles, a
+    /// `DesignationsChangedEvent` is sent when they change
+    pub designations: HashMap<UVec3, Designation>,
+    /// One biome for each column of tiles
+    pub biomes: Vec<Biome>,
 }
 
 impl MapData {
     pub fn new(width: usize, height: usize, z_levels: usize) -> Self {
         Self {
             layers: vec![Layer::new(width, height); z_levels],
+            designations: HashMap::default(),
+            biomes: vec![Biome::default(); width * height],
         }
     }
 
@@ -121,6 +479,95 @@ impl MapData {
             bail!("tile out of bounds")
         }
     }
+
+    /// Changes a tile of the generated map, every change goes through here so the renderer and
+    /// the systems tracking the map see it
+    pub fn update_tile(&mut self, pos: UVec3, new_tile: Tile, changed: &mut ChangedTiles) {
+        let old_tile = *self.get_tile(pos).expect("tile out of bounds");
+        self.set_tile(pos, new_tile).expect("tile out of bounds");
+        changed.0.push((pos, old_tile));
+    }
+
+    pub fn get_biome(&self, pos: UVec2) -> Option<Biome> {
+        if pos.x as usize >= WIDTH {
+            return None;
+        }
+        self.biomes
+            .get(pos.y as usize * WIDTH + pos.x as usize)
+            .copied()
+    }
+
+    /// Checks if the designation can be placed on the tile
+    pub fn can_designate(&self, pos: UVec3, designation: Designation) -> bool {
+        let tile = match self.get_tile(pos) {
+            Some(tile) => tile,
+            None => return false,
+        };
+        match designation {
+            // constructions are deconstructed instead of dug
+            Designation::Dig
+            | Designation::UpStair
+            | Designation::DownStair
+            | Designation::UpDownStair
+            | Designation::Ramp => tile.value.is_solid() && tile.constructed.is_none(),
+            // there needs to be a floor to remove
+            Designation::Channel => {
+                tile.value.is_solid()
+                    && tile.constructed.is_none()
+                    && !matches!(self.get_tile(pos + UVec3::Z), Some(above) if above.value.is_solid())
+            }
+            Designation::Chop => tile.value == TileType::Tree,
+            // constructions need something below to rest on
+            Designation::Build(_) => {
+                tile.value == TileType::Air
+                    && pos.z > 0
+                    && matches!(self.get_tile(pos - UVec3::Z), Some(below) if !below.value.is_open())
+            }
+            Designation::Deconstruct => tile.constructed.is_some(),
+        }
+    }
+
+    /// Finds the first tile that isn't air at or below the given z-level
+    pub fn find_top_tile(&self, pos: UVec2, z_level: u16) -> Option<UVec3> {
+        (0..=z_level as u32)
+            .rev()
+            .map(|z| pos.extend(z))
+            .find(|tile_pos| {
+                matches!(self.get_tile(*tile_pos), Some(tile) if !matches!(tile.value, TileType::Air))
+            })
+    }
+}
+
+/// Every neighbour of the tile, including the ones above and below, that is inside the map
+pub fn neighbors(pos: UVec3) -> impl Iterator<Item = UVec3> {
+    let max = UVec3::new(WIDTH as u32 - 1, HEIGHT as u32 - 1, Z_LEVELS as u32 - 1);
+    let mut neighbors = Vec::with_capacity(6);
+    if pos.z > 0 {
+        neighbors.push(pos - UVec3::Z);
+    }
+    neighbors.extend(horizontal_neighbors(pos));
+    if pos.z < max.z {
+        neighbors.push(pos + UVec3::Z);
+    }
+    neighbors.into_iter()
+}
+
+pub fn horizontal_neighbors(pos: UVec3) -> impl Iterator<Item = UVec3> {
+    let max = UVec2::new(WIDTH as u32 - 1, HEIGHT as u32 - 1);
+    let mut neighbors = Vec::with_capacity(4);
+    if pos.x > 0 {
+        neighbors.push(pos - UVec3::X);
+    }
+    if pos.x < max.x {
+        neighbors.push(pos + UVec3::X);
+    }
+    if pos.y > 0 {
+        neighbors.push(pos - UVec3::Y);
+    }
+    if pos.y < max.y {
+        neighbors.push(pos + UVec3::Y);
+    }
+    neighbors.into_iter()
 }
 
 pub struct MapPlugin;
@@ -130,11 +577,14 @@ impl Plugin for MapPlugin {
         app.add_plugin(TilemapPlugin)
             .add_plugin(InspectorPlugin::<NoiseSettings>::new())
             .add_event::<MapGeneratedEvent>()
+            .add_event::<DesignationsChangedEvent>()
             .add_startup_system(startup.system())
             .add_system(generate_map.system())
             .add_system(set_map_textures.system())
+            .add_system(set_top_down_textures.system())
+            .add_system(add_liquid_textures.system())
             .add_system(update_layer_visibility.system())
-            .add_system(update_tiles.system());
+            .add_system(update_tiles.system().label(MapLabel::UpdateTiles));
     }
 }
 
@@ -143,12 +593,14 @@ fn startup(
     asset_server: Res<AssetServer>,
     mut materials: ResMut<Assets<ColorMaterial>>,
     mut meshes: ResMut<Assets<Mesh>>,
+    mut textures: ResMut<Assets<Texture>>,
 ) {
     let texture_handle = asset_server.load("iso_tiles.png");
+    commands.insert_resource(IsoTexture(texture_handle.clone()));
     let material_handle = materials.add(ColorMaterial::texture(texture_handle));
 
     let map_entity = commands.spawn().id();
-    let mut map = Map::new(0u16, map_entity);
+    let mut map = Map::new(ISO_MAP_ID, map_entity);
 
     let mut layer_settings = LayerSettings::new(
         UVec2::new(MAP_WIDTH, MAP_HEIGHT),
@@ -167,7 +619,7 @@ fn startup(
             layer_settings,
             &mut meshes,
             material_handle.clone(),
-            0u16,
+            ISO_MAP_ID,
             layer_id,
             None,
             move |_| Some(TileBundle::default()),
@@ -186,16 +638,52 @@ fn startup(
         .insert(Transform::from_xyz(0.0, 0.0, 0.0))
         .insert(GlobalTransform::default());
 
+    // The top down view is a single layer showing the current z-level
+    let top_down_material = materials.add(ColorMaterial::texture(textures.add(top_down_texture())));
+    let top_down_map_entity = commands.spawn().id();
+    let mut top_down_map = Map::new(TOP_DOWN_MAP_ID, top_down_map_entity);
+
+    let top_down_settings = LayerSettings::new(
+        UVec2::new(MAP_WIDTH, MAP_HEIGHT),
+        UVec2::new(CHUNK_WIDTH, CHUNK_HEIGHT),
+        Vec2::new(TILE_WIDTH as f32, TILE_WIDTH as f32),
+        Vec2::new(TEXTURE_WIDTH as f32, TEXTURE_HEIGHT as f32),
+    );
+    let layer_entity = LayerBuilder::<TileBundle>::new_batch(
+        &mut commands,
+        top_down_settings,
+        &mut meshes,
+        top_down_material,
+        TOP_DOWN_MAP_ID,
+        0u16,
+        None,
+        move |_| Some(TileBundle::default()),
+    );
+    // The grid is flipped vertically to keep the same orientation as the isometric view,
+    // so the layer is moved down to have the first row start at the origin
+    commands.entity(layer_entity).insert(Transform::from_xyz(
+        0.0,
+        -((HEIGHT * TILE_WIDTH) as f32),
+        Z_LEVELS as f32,
+    ));
+    top_down_map.add_layer(&mut commands, 0u16, layer_entity);
+
+    commands
+        .entity(top_down_map_entity)
+        .insert(top_down_map)
+        .insert(Transform::from_xyz(0.0, 0.0, 0.0))
+        .insert(GlobalTransform::default());
+
     let noise_fn = SuperSimplex::new().set_seed(42);
     commands.insert_resource(noise_fn);
 
-    commands.insert_resource(MapData {
-        layers: vec![Layer::new(WIDTH, HEIGHT); Z_LEVELS as usize],
-    });
+    commands.insert_resource(MapData::new(WIDTH, HEIGHT, Z_LEVELS as usize));
+
+    commands.insert_resource(CurrentZLevel(Z_LEVELS - 1));
 
-    commands.insert_resource(VisibleLayers::new(Z_LEVELS as usize));
+    commands.insert_resource(ViewRotation::default());
 
-    commands.insert_resource(CurrentZLevel(Z_LEVELS));
+    commands.insert_resource(RenderMode::Isometric);
 
-    commands.insert_resource(TilesToUpdate(vec![]));
+    commands.insert_resource(ChangedTiles::default());
 }
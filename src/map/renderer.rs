@@ -1,10 +1,18 @@
-use bevy::{math::Vec3Swizzles, prelude::*, tasks::ComputeTaskPool, utils::Instant};
+use bevy::{
+    math::Vec3Swizzles,
+    prelude::*,
+    render::texture::{Extent3d, TextureDimension, TextureFormat},
+    tasks::ComputeTaskPool,
+    utils::{HashSet, Instant},
+};
 use bevy_ecs_tilemap::prelude::*;
 
 use crate::map::CurrentZLevel;
 
 use super::{
-    MapData, MapGeneratedEvent, TileType, TilesToUpdate, VisibleLayers, TILE_BATCH_SIZE, Z_LEVELS,
+    ChangedTiles, IsoTexture, MapData, MapGeneratedEvent, Material, RenderMode, Tile as TileData,
+    TileType, ViewRotation, HEIGHT, ISO_MAP_ID, MAX_LIQUID_LEVEL, TEXTURE_FILE_TILES,
+    TEXTURE_HEIGHT, TEXTURE_WIDTH, TILE_BATCH_SIZE, TILE_HEIGHT, TILE_WIDTH, TOP_DOWN_MAP_ID,
 };
 
 // TODO
@@ -13,114 +21,361 @@ use super::{
 //      let neighbors = map_query.get_tile_neighbors(*pos, 0u16, tile_parent.layer_id);
 // * merge MapRendererData and MapGeneratorData??
 
+/// How much darker each z-level below the current one is in the top down view
+const TOP_DOWN_DEPTH_SHADE: f32 = 0.15;
+const TOP_DOWN_MIN_SHADE: f32 = 0.25;
+/// Opacity of a liquid tile with the lowest level, full tiles are opaque
+const MIN_LIQUID_ALPHA: f32 = 0.3;
+/// The tiles of each water level are added after the tiles of the texture file, then the magma ones
+const WATER_LEVEL_TEXTURES: u16 = TEXTURE_FILE_TILES as u16;
+const MAGMA_LEVEL_TEXTURES: u16 = WATER_LEVEL_TEXTURES + MAX_LIQUID_LEVEL as u16;
+/// Burning tiles are drawn with this tint instead of their own
+const FIRE_TINT: [f32; 4] = [2.4, 0.9, 0.3, 1.0];
+
 pub fn update_layer_visibility(
     mut chunk_query: Query<(&Chunk, &mut Visible)>,
-    mut visible_layers: ResMut<VisibleLayers>,
     current_z_level: Res<CurrentZLevel>,
+    render_mode: Res<RenderMode>,
     pool: Res<ComputeTaskPool>,
 ) {
-    if !current_z_level.is_changed() {
+    if !current_z_level.is_changed() && !render_mode.is_changed() {
         return;
     }
 
     info!("updating layer visibility...");
     let start = Instant::now();
 
-    fn visibility_needs_update(
-        z_level: u16,
-        visible_layers: &VisibleLayers,
-        current_z_level: &CurrentZLevel,
-    ) -> Option<bool> {
-        let is_layer_visible = visible_layers.0[z_level as usize];
-        if is_layer_visible && z_level > current_z_level.0 {
-            Some(false)
-        } else if !is_layer_visible && z_level <= current_z_level.0 {
-            Some(true)
-        } else {
-            None
-        }
-    }
-
+    let render_mode = *render_mode;
+    let current_z_level = current_z_level.0;
     chunk_query.par_for_each_mut(&pool, 32, |(chunk, mut visible)| {
-        if let Some(visibility) =
-            visibility_needs_update(chunk.settings.layer_id, &visible_layers, &current_z_level)
-        {
+        let is_visible = match render_mode {
+            RenderMode::Isometric => {
+                chunk.settings.map_id == ISO_MAP_ID && chunk.settings.layer_id <= current_z_level
+            }
+            RenderMode::TopDown => chunk.settings.map_id == TOP_DOWN_MAP_ID,
+        };
+        // only touch the chunks that changed to avoid triggering change detection on every chunk
+        if visible.is_visible != is_visible {
             *visible = Visible {
-                is_visible: visibility,
+                is_visible,
                 ..Default::default()
             };
         }
     });
 
-    for z_level in 0..Z_LEVELS {
-        if let Some(visible) = visibility_needs_update(z_level, &visible_layers, &current_z_level) {
-            visible_layers.0[z_level as usize] = visible;
-        }
-    }
-
     info!(
         "updating layer visibility...done elapsed: {:?}",
         start.elapsed()
     );
 }
 
+fn texture_index(tile_type: TileType) -> u16 {
+    match tile_type {
+        TileType::Air => 1,
+        TileType::Water => 2,
+        TileType::Grass => 3,
+        TileType::Dirt => 4,
+        TileType::Rock => 5,
+        TileType::Tree => 3,
+        TileType::Magma => 5,
+        TileType::Obsidian => 5,
+        TileType::UpStair | TileType::DownStair | TileType::UpDownStair => 5,
+        TileType::Ramp => 4,
+        TileType::Door => 4,
+        TileType::Wall => 5,
+        TileType::Floor => 4,
+    }
+}
+
+/// Liquids in the isometric view use the tile of their level, the top is lower the less liquid
+/// there is
+fn iso_texture_index(tile_data: &TileData) -> u16 {
+    let first = match tile_data.value {
+        TileType::Water => WATER_LEVEL_TEXTURES,
+        TileType::Magma => MAGMA_LEVEL_TEXTURES,
+        value => return texture_index(value),
+    };
+    first + tile_data.liquid.clamp(1, MAX_LIQUID_LEVEL) as u16 - 1
+}
+
+/// Pixels a liquid tile is moved down by, a full tile isn't moved
+fn liquid_offset(level: u8) -> usize {
+    let missing = (MAX_LIQUID_LEVEL - level) as usize;
+    missing * (TILE_HEIGHT / 2) / MAX_LIQUID_LEVEL as usize
+}
+
+/// Adds the tiles of each water and magma level to the isometric texture once it's loaded, they
+/// are copies of the full liquid tile moved down by `liquid_offset`
+pub fn add_liquid_textures(
+    mut texture_events: EventReader<AssetEvent<Texture>>,
+    mut textures: ResMut<Assets<Texture>>,
+    iso_texture: Res<IsoTexture>,
+) {
+    for event in texture_events.iter() {
+        let texture = match event {
+            AssetEvent::Created { handle } if *handle == iso_texture.0 => {
+                match textures.get_mut(handle) {
+                    Some(texture) => texture,
+                    None => continue,
+                }
+            }
+            _ => continue,
+        };
+        let file_width = TEXTURE_FILE_TILES * TILE_WIDTH;
+        if texture.size.width as usize != file_width
+            || texture.size.height as usize != TEXTURE_HEIGHT
+            || texture.format != TextureFormat::Rgba8UnormSrgb
+        {
+            warn!(
+                "unexpected tile texture {:?} {:?}, liquid levels won't be drawn",
+                texture.size, texture.format
+            );
+            continue;
+        }
+
+        let mut data = Vec::with_capacity(TEXTURE_WIDTH * TEXTURE_HEIGHT * 4);
+        for y in 0..TEXTURE_HEIGHT {
+            let row = y * file_width * 4;
+            data.extend_from_slice(&texture.data[row..row + file_width * 4]);
+            for liquid in [TileType::Water, TileType::Magma].iter() {
+                let source = texture_index(*liquid) as usize * TILE_WIDTH;
+                for level in 1..=MAX_LIQUID_LEVEL {
+                    let offset = liquid_offset(level);
+                    for x in source..source + TILE_WIDTH {
+                        if y < offset {
+                            data.extend_from_slice(&[0; 4]);
+                        } else {
+                            let i = ((y - offset) * file_width + x) * 4;
+                            data.extend_from_slice(&texture.data[i..i + 4]);
+                        }
+                    }
+                }
+            }
+        }
+        texture.data = data;
+        texture.size.width = TEXTURE_WIDTH as u32;
+    }
+}
+
+/// Some tile types reuse the texture of another type with a different tint.
+/// Constructions are tinted by their material and liquids are more transparent the lower their
+/// level is
+fn tile_tint(tile_data: &TileData) -> Color {
+    let [r, g, b, _] = match (tile_data.constructed, tile_data.value) {
+        _ if tile_data.burning => FIRE_TINT,
+        (Some(Material::Wood), _) => [1.4, 1.0, 0.6, 1.0],
+        (Some(Material::Obsidian), _) => [0.35, 0.3, 0.45, 1.0],
+        (Some(_), _) => [1.2, 1.15, 1.1, 1.0],
+        (None, value) => natural_tint(value),
+    };
+    let alpha = if tile_data.value.is_liquid() {
+        let fill = tile_data.liquid as f32 / MAX_LIQUID_LEVEL as f32;
+        MIN_LIQUID_ALPHA + (1.0 - MIN_LIQUID_ALPHA) * fill
+    } else {
+        1.0
+    };
+    Color::rgba(r, g, b, alpha)
+}
+
+fn natural_tint(value: TileType) -> [f32; 4] {
+    match value {
+        TileType::Tree => [0.5, 0.7, 0.4, 1.0],
+        // the rock texture is grey, so it needs to be brightened to look orange
+        TileType::Magma => [2.6, 1.0, 0.25, 1.0],
+        TileType::Obsidian => [0.35, 0.3, 0.45, 1.0],
+        TileType::UpStair | TileType::DownStair | TileType::UpDownStair => [1.3, 1.3, 1.4, 1.0],
+        TileType::Ramp => [0.8, 0.8, 0.8, 1.0],
+        TileType::Door => [1.4, 1.0, 0.6, 1.0],
+        _ => [1.0, 1.0, 1.0, 1.0],
+    }
+}
+
+/// Generates the texture used by the top down view, it uses the same layout as the isometric tiles
+pub fn top_down_texture() -> Texture {
+    let tile_count = TEXTURE_WIDTH / TILE_WIDTH;
+    let mut colors = vec![Color::FUCHSIA; tile_count];
+    for tile_type in [
+        TileType::Air,
+        TileType::Water,
+        TileType::Grass,
+        TileType::Dirt,
+        TileType::Rock,
+    ]
+    .iter()
+    {
+        colors[texture_index(*tile_type) as usize] = tile_type.color();
+    }
+
+    let mut data = Vec::with_capacity(TEXTURE_WIDTH * TEXTURE_HEIGHT * 4);
+    for _y in 0..TEXTURE_HEIGHT {
+        for x in 0..TEXTURE_WIDTH {
+            let color = colors[x / TILE_WIDTH].as_rgba_f32();
+            data.extend(color.iter().map(|c| (c * 255.0) as u8));
+        }
+    }
+
+    Texture::new(
+        Extent3d::new(TEXTURE_WIDTH as u32, TEXTURE_HEIGHT as u32, 1),
+        TextureDimension::D2,
+        data,
+        TextureFormat::Rgba8UnormSrgb,
+    )
+}
+
+/// Converts a tile position of the top down layer to its position in `MapData`
+fn top_down_to_map(pos: UVec2, rotation: ViewRotation) -> UVec2 {
+    let view_pos = UVec2::new(pos.x, HEIGHT as u32 - 1 - pos.y);
+    rotation.view_to_map(view_pos.as_f32()).as_u32()
+}
+
+fn map_to_top_down(pos: UVec2, rotation: ViewRotation) -> UVec2 {
+    let view_pos = rotation.map_to_view(pos.as_f32()).as_u32();
+    UVec2::new(view_pos.x, HEIGHT as u32 - 1 - view_pos.y)
+}
+
+/// Brightness of a tile seen from a z-level above it
+pub fn depth_shade(depth: u16) -> f32 {
+    (1.0 - depth as f32 * TOP_DOWN_DEPTH_SHADE).max(TOP_DOWN_MIN_SHADE)
+}
+
+/// Finds the first tile at or below the current z-level and shades it based on its depth
+fn top_down_tile(map_data: &MapData, pos: UVec2, current_z_level: u16) -> (u16, Color) {
+    match map_data.find_top_tile(pos, current_z_level) {
+        Some(tile_pos) => {
+            let tile = map_data.get_tile(tile_pos).expect("tile out of bounds");
+            let shade = depth_shade(current_z_level - tile_pos.z as u16);
+            (texture_index(tile.value), tile_tint(tile) * shade)
+        }
+        None => (texture_index(TileType::Air), Color::WHITE),
+    }
+}
+
 pub fn set_map_textures(
     mut tile_query: Query<(&mut Tile, &TileParent, &UVec2)>,
     mut chunk_query: Query<&mut Chunk>,
     pool: Res<ComputeTaskPool>,
     map_data: Res<MapData>,
+    rotation: Res<ViewRotation>,
     mut events: EventReader<MapGeneratedEvent>,
 ) {
-    if events.iter().count() == 0 {
+    if events.iter().count() == 0 && !rotation.is_changed() {
         return;
     }
     info!("setting map textures...");
     let start = Instant::now();
 
+    // The tile entities never move, when the view is rotated they display a different part of the map
+    let rotation = *rotation;
     tile_query.par_for_each_mut(&pool, TILE_BATCH_SIZE, |(mut tile, tile_parent, pos)| {
+        if tile_parent.map_id != ISO_MAP_ID {
+            return;
+        }
+        let map_pos = rotation.view_to_map(pos.as_f32()).as_u32();
         let tile_data = &map_data
-            .get_tile(pos.extend(tile_parent.layer_id as u32))
+            .get_tile(map_pos.extend(tile_parent.layer_id as u32))
             .expect("Tile is out of bounds");
 
-        tile.texture_index = match tile_data.value {
-            TileType::Air => 1,
-            TileType::Water => 2,
-            TileType::Grass => 3,
-            TileType::Dirt => 4,
-            TileType::Rock => 5,
-        };
+        tile.texture_index = iso_texture_index(tile_data);
+        tile.color = tile_tint(tile_data);
+        tile.flip_x = rotation.flip_x();
     });
 
     for mut chunk in chunk_query.iter_mut() {
-        chunk.needs_remesh = true;
+        if chunk.settings.map_id == ISO_MAP_ID {
+            chunk.needs_remesh = true;
+        }
     }
     info!("setting map textures...done elapsed: {:?}", start.elapsed());
 }
 
+pub fn set_top_down_textures(
+    mut tile_query: Query<(&mut Tile, &TileParent, &UVec2)>,
+    mut chunk_query: Query<&mut Chunk>,
+    pool: Res<ComputeTaskPool>,
+    map_data: Res<MapData>,
+    rotation: Res<ViewRotation>,
+    render_mode: Res<RenderMode>,
+    current_z_level: Res<CurrentZLevel>,
+    mut events: EventReader<MapGeneratedEvent>,
+) {
+    let map_generated = events.iter().count() > 0;
+    // The top down layer is only kept up to date while it's visible
+    if *render_mode != RenderMode::TopDown
+        || !(map_generated
+            || render_mode.is_changed()
+            || current_z_level.is_changed()
+            || rotation.is_changed())
+    {
+        return;
+    }
+    info!("setting top down textures...");
+    let start = Instant::now();
+
+    let rotation = *rotation;
+    let current_z_level = current_z_level.0;
+    tile_query.par_for_each_mut(&pool, TILE_BATCH_SIZE, |(mut tile, tile_parent, pos)| {
+        if tile_parent.map_id != TOP_DOWN_MAP_ID {
+            return;
+        }
+        let map_pos = top_down_to_map(*pos, rotation);
+        let (texture_index, color) = top_down_tile(&map_data, map_pos, current_z_level);
+        tile.texture_index = texture_index;
+        tile.color = color;
+    });
+
+    for mut chunk in chunk_query.iter_mut() {
+        if chunk.settings.map_id == TOP_DOWN_MAP_ID {
+            chunk.needs_remesh = true;
+        }
+    }
+    info!(
+        "setting top down textures...done elapsed: {:?}",
+        start.elapsed()
+    );
+}
+
 pub fn update_tiles(
-    mut map_data: ResMut<MapData>,
+    map_data: Res<MapData>,
     mut map_query: MapQuery,
     mut tile_query: Query<&mut bevy_ecs_tilemap::Tile>,
-    mut tiles: ResMut<TilesToUpdate>,
+    mut changed_tiles: ResMut<ChangedTiles>,
+    rotation: Res<ViewRotation>,
+    render_mode: Res<RenderMode>,
+    current_z_level: Res<CurrentZLevel>,
 ) {
-    for (tile_pos, tile_data) in &tiles.0 {
-        map_data
-            .set_tile(*tile_pos, *tile_data)
-            .expect("tile out of bounds");
+    if changed_tiles.0.is_empty() {
+        return;
+    }
+    let positions = changed_tiles
+        .0
+        .drain(..)
+        .map(|(pos, _)| pos)
+        .collect::<HashSet<_>>();
+    for tile_pos in positions.iter() {
+        let tile_data = map_data.get_tile(*tile_pos).expect("tile out of bounds");
+        let view_pos = rotation.map_to_view(tile_pos.xy().as_f32()).as_u32();
         let tile_entity = map_query
-            .get_tile_entity(tile_pos.xy(), 0u16, tile_pos.z as u16)
+            .get_tile_entity(view_pos, ISO_MAP_ID, tile_pos.z as u16)
             .expect("no tile entity found");
         if let Ok(mut tile) = tile_query.get_mut(tile_entity) {
-            tile.texture_index = match tile_data.value {
-                TileType::Air => 1,
-                TileType::Water => 2,
-                TileType::Grass => 3,
-                TileType::Dirt => 4,
-                TileType::Rock => 5,
-            };
+            tile.texture_index = iso_texture_index(tile_data);
+            tile.color = tile_tint(tile_data);
         }
         // TODO cache chunks that needs updating
-        map_query.notify_chunk_for_tile(tile_pos.xy(), 0u16, tile_pos.z as u16);
+        map_query.notify_chunk_for_tile(view_pos, ISO_MAP_ID, tile_pos.z as u16);
+
+        if *render_mode == RenderMode::TopDown {
+            let top_down_pos = map_to_top_down(tile_pos.xy(), *rotation);
+            let tile_entity = map_query
+                .get_tile_entity(top_down_pos, TOP_DOWN_MAP_ID, 0u16)
+                .expect("no tile entity found");
+            if let Ok(mut tile) = tile_query.get_mut(tile_entity) {
+                let (texture_index, color) =
+                    top_down_tile(&map_data, tile_pos.xy(), current_z_level.0);
+                tile.texture_index = texture_index;
+                tile.color = color;
+            }
+            map_query.notify_chunk_for_tile(top_down_pos, TOP_DOWN_MAP_ID, 0u16);
+        }
     }
-    tiles.0.clear();
 }
@@ -0,0 +1,250 @@
+use bevy::{
+    math::Vec3Swizzles,
+    prelude::*,
+    render::texture::{Extent3d, TextureDimension, TextureFormat},
+    utils::{HashSet, Instant},
+};
+use bevy_egui::{egui, EguiContext};
+
+use crate::{
+    camera::MainCamera,
+    map::{
+        renderer::depth_shade, ChangedTiles, CurrentZLevel, MapData, MapGeneratedEvent, MapLabel,
+        RenderMode, ViewRotation, HEIGHT, WIDTH, Z_LEVELS,
+    },
+    utils::{tile_to_world, world_to_tile},
+};
+
+// TODO
+// * rotate the minimap with the view
+
+/// Each pixel of the minimap represents a square of MINIMAP_SCALE * MINIMAP_SCALE tiles
+const MINIMAP_SCALE: usize = 2;
+const MINIMAP_WIDTH: usize = WIDTH / MINIMAP_SCALE;
+const MINIMAP_HEIGHT: usize = HEIGHT / MINIMAP_SCALE;
+/// Size of the minimap in the ui, in pixels
+const MINIMAP_DISPLAY_SIZE: f32 = 240.0;
+const MINIMAP_TEXTURE_ID: u64 = 0;
+
+pub struct MinimapPlugin;
+
+impl Plugin for MinimapPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app.add_startup_system(minimap_setup.system())
+            .add_system(mark_dirty_tiles.system().before(MapLabel::UpdateTiles))
+            .add_system(update_minimap.system().after(MapLabel::UpdateTiles))
+            .add_system(minimap_window.system());
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MinimapMode {
+    /// Shows the current z-level like the top down view
+    ZLevel,
+    /// Shows the elevation of the surface
+    Surface,
+}
+
+pub struct Minimap {
+    texture: Handle<Texture>,
+    mode: MinimapMode,
+    needs_redraw: bool,
+    /// Pixels that need to be redrawn because a tile in them changed
+    dirty_pixels: HashSet<UVec2>,
+}
+
+fn minimap_setup(
+    mut commands: Commands,
+    mut textures: ResMut<Assets<Texture>>,
+    mut egui_context: ResMut<EguiContext>,
+) {
+    let texture = textures.add(Texture::new_fill(
+        Extent3d::new(MINIMAP_WIDTH as u32, MINIMAP_HEIGHT as u32, 1),
+        TextureDimension::D2,
+        &[0, 0, 0, 255],
+        TextureFormat::Rgba8UnormSrgb,
+    ));
+    egui_context.set_egui_texture(MINIMAP_TEXTURE_ID, texture.clone());
+    commands.insert_resource(Minimap {
+        texture,
+        mode: MinimapMode::ZLevel,
+        needs_redraw: true,
+        dirty_pixels: HashSet::default(),
+    });
+}
+
+/// This needs to run before the tiles are updated, otherwise the list is already cleared
+fn mark_dirty_tiles(tiles: Res<ChangedTiles>, mut minimap: ResMut<Minimap>) {
+    if tiles.0.is_empty() {
+        return;
+    }
+    for (tile_pos, _) in tiles.0.iter() {
+        minimap
+            .dirty_pixels
+            .insert(tile_pos.xy() / MINIMAP_SCALE as u32);
+    }
+}
+
+fn pixel_color(map_data: &MapData, pixel: UVec2, mode: MinimapMode, z_level: u16) -> [u8; 4] {
+    let tile_pos = pixel * MINIMAP_SCALE as u32;
+    let top_z_level = match mode {
+        MinimapMode::ZLevel => z_level,
+        MinimapMode::Surface => Z_LEVELS - 1,
+    };
+    let top_tile = match map_data.find_top_tile(tile_pos, top_z_level) {
+        Some(top_tile) => top_tile,
+        None => return [0, 0, 0, 255],
+    };
+    let tile = map_data.get_tile(top_tile).expect("tile out of bounds");
+    let shade = match mode {
+        MinimapMode::ZLevel => depth_shade(top_z_level - top_tile.z as u16),
+        // higher tiles are brighter
+        MinimapMode::Surface => 0.4 + 0.6 * top_tile.z as f32 / (Z_LEVELS - 1) as f32,
+    };
+
+    let mut color = [0, 0, 0, 255];
+    for (channel, value) in color
+        .iter_mut()
+        .zip(tile.value.color().as_rgba_f32().iter())
+        .take(3)
+    {
+        *channel = (value * shade * 255.0) as u8;
+    }
+    color
+}
+
+fn set_pixel(texture: &mut Texture, pixel: UVec2, color: [u8; 4]) {
+    let index = (pixel.y as usize * MINIMAP_WIDTH + pixel.x as usize) * 4;
+    texture.data[index..index + 4].copy_from_slice(&color);
+}
+
+fn update_minimap(
+    mut minimap: ResMut<Minimap>,
+    mut textures: ResMut<Assets<Texture>>,
+    map_data: Res<MapData>,
+    current_z_level: Res<CurrentZLevel>,
+    mut events: EventReader<MapGeneratedEvent>,
+) {
+    let z_level = current_z_level.0;
+    if events.iter().count() > 0
+        || (current_z_level.is_changed() && minimap.mode == MinimapMode::ZLevel)
+    {
+        minimap.needs_redraw = true;
+    }
+    if !minimap.needs_redraw && minimap.dirty_pixels.is_empty() {
+        return;
+    }
+
+    let texture = textures
+        .get_mut(&minimap.texture)
+        .expect("minimap texture not found");
+
+    if minimap.needs_redraw {
+        info!("drawing minimap...");
+        let start = Instant::now();
+        for y in 0..MINIMAP_HEIGHT as u32 {
+            for x in 0..MINIMAP_WIDTH as u32 {
+                let pixel = UVec2::new(x, y);
+                set_pixel(
+                    texture,
+                    pixel,
+                    pixel_color(&map_data, pixel, minimap.mode, z_level),
+                );
+            }
+        }
+        minimap.needs_redraw = false;
+        minimap.dirty_pixels.clear();
+        info!("drawing minimap...done elapsed: {:?}", start.elapsed());
+    } else {
+        let mode = minimap.mode;
+        for pixel in minimap.dirty_pixels.drain() {
+            set_pixel(texture, pixel, pixel_color(&map_data, pixel, mode, z_level));
+        }
+    }
+}
+
+fn minimap_window(
+    egui_context: Res<EguiContext>,
+    mut minimap: ResMut<Minimap>,
+    windows: Res<Windows>,
+    current_z_level: Res<CurrentZLevel>,
+    render_mode: Res<RenderMode>,
+    rotation: Res<ViewRotation>,
+    mut camera_query: Query<&mut Transform, With<MainCamera>>,
+) {
+    let window = windows.get_primary().expect("primary window not found");
+    let mut camera_transform = camera_query.single_mut().expect("main camera not found");
+    let z_level = current_z_level.0;
+    let mut mode = minimap.mode;
+
+    egui::Window::new("Minimap")
+        .anchor(egui::Align2::RIGHT_TOP, [0., 0.])
+        .resizable(false)
+        .show(egui_context.ctx(), |ui| {
+            ui.horizontal(|ui| {
+                ui.selectable_value(&mut mode, MinimapMode::ZLevel, "Z-level");
+                ui.selectable_value(&mut mode, MinimapMode::Surface, "Surface");
+            });
+
+            let response = ui.allocate_response(
+                egui::vec2(MINIMAP_DISPLAY_SIZE, MINIMAP_DISPLAY_SIZE),
+                egui::Sense::click_and_drag(),
+            );
+            let rect = response.rect;
+            ui.painter().image(
+                egui::TextureId::User(MINIMAP_TEXTURE_ID),
+                rect,
+                egui::Rect::from_min_max(egui::pos2(0.0, 0.0), egui::pos2(1.0, 1.0)),
+                egui::Color32::WHITE,
+            );
+
+            let map_to_minimap = |pos: Vec2| {
+                egui::pos2(
+                    rect.min.x + pos.x / WIDTH as f32 * MINIMAP_DISPLAY_SIZE,
+                    rect.min.y + pos.y / HEIGHT as f32 * MINIMAP_DISPLAY_SIZE,
+                )
+            };
+
+            // The viewport isn't axis aligned in map space, so draw each edge
+            let half_size =
+                Vec2::new(window.width(), window.height()) / 2.0 * camera_transform.scale.xy();
+            let center = camera_transform.translation.xy();
+            let corners = [
+                center + Vec2::new(-half_size.x, half_size.y),
+                center + half_size,
+                center + Vec2::new(half_size.x, -half_size.y),
+                center - half_size,
+            ]
+            .iter()
+            .map(|corner| map_to_minimap(world_to_tile(*corner, z_level, *render_mode, *rotation)))
+            .collect::<Vec<_>>();
+            let stroke = egui::Stroke::new(1.0, egui::Color32::WHITE);
+            for i in 0..corners.len() {
+                let next = (i + 1) % corners.len();
+                ui.painter()
+                    .line_segment([corners[i], corners[next]], stroke);
+            }
+
+            if response.clicked() || response.dragged() {
+                if let Some(pointer_pos) = response.interact_pointer_pos() {
+                    let minimap_pos = (pointer_pos - rect.min) / MINIMAP_DISPLAY_SIZE;
+                    let tile_pos = Vec2::new(
+                        (minimap_pos.x * WIDTH as f32).clamp(0.0, (WIDTH - 1) as f32),
+                        (minimap_pos.y * HEIGHT as f32).clamp(0.0, (HEIGHT - 1) as f32),
+                    );
+                    let pos = tile_to_world(
+                        tile_pos.as_u32().extend(z_level as u32),
+                        *render_mode,
+                        *rotation,
+                    );
+                    camera_transform.translation = pos.extend(camera_transform.translation.z);
+                }
+            }
+        });
+
+    // only touch the resource when needed to avoid triggering a redraw every frame
+    if mode != minimap.mode {
+        minimap.mode = mode;
+        minimap.needs_redraw = true;
+    }
+}
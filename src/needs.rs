@@ -0,0 +1,438 @@
+use bevy::{prelude::*, utils::HashSet};
+
+use crate::{
+    creature::{CreatureLabel, Health, Movement},
+    items::{Item, ItemCategory, ItemIndex, ItemKind},
+    jobs::{
+        consume_item, drop_item, fetch_item, path_to_job, Job, JobBoard, JobKind, JobLabel, Worker,
+    },
+    map::{MapData, TilePos, TileType, HEIGHT, WIDTH, Z_LEVELS},
+    pathfinding::{distance, NavGraph},
+    regions::Regions,
+    simulation::{SimulationStage, SimulationTime, TICKS_PER_DAY},
+    stockpile::Stockpiles,
+};
+
+// TODO
+// * brew drinks to fill the barrels, they never run out for now
+// * dwarves sharing a meal hall instead of eating where the food is
+
+const THIRSTY_TICKS: u32 = TICKS_PER_DAY as u32;
+const DEHYDRATED_TICKS: u32 = 3 * TICKS_PER_DAY as u32;
+const HUNGRY_TICKS: u32 = 2 * TICKS_PER_DAY as u32;
+const STARVING_TICKS: u32 = 6 * TICKS_PER_DAY as u32;
+const TIRED_TICKS: u32 = 2 * TICKS_PER_DAY as u32;
+/// Exhausted creatures fall asleep where they are
+const EXHAUSTED_TICKS: u32 = 4 * TICKS_PER_DAY as u32;
+/// Ticks between the checks for needs that should interrupt the work
+const NEED_CHECK_INTERVAL: u64 = 10;
+/// Starving or dehydrated creatures lose health this often
+const NEED_DAMAGE_INTERVAL: u64 = 50;
+const NEED_DAMAGE: u32 = 1;
+const EAT_TICKS: u32 = 30;
+const DRINK_TICKS: u32 = 20;
+/// Fatigue recovered per tick of sleep
+const BED_RECOVERY: u32 = 4;
+const FLOOR_RECOVERY: u32 = 2;
+/// Distance from the creature where it looks for water
+const WATER_SEARCH_RADIUS: i32 = 24;
+const WATER_SEARCH_DEPTH: i32 = 2;
+/// Number of drink sources a creature tries to find a path to before giving up for this check
+const MAX_DRINK_ATTEMPTS: usize = 4;
+
+pub struct NeedsPlugin;
+
+impl Plugin for NeedsPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app.add_system_to_stage(
+            SimulationStage,
+            update_needs
+                .system()
+                .after(JobLabel::Claim)
+                .before(CreatureLabel::Move),
+        )
+        .add_system_to_stage(
+            SimulationStage,
+            fulfil_needs.system().after(CreatureLabel::Move),
+        );
+    }
+}
+
+/// Ticks since each need was satisfied
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Needs {
+    pub hunger: u32,
+    pub thirst: u32,
+    pub fatigue: u32,
+}
+
+impl Needs {
+    /// Self-jobs for the needs that should be taken care of, the most urgent first
+    fn pressing(&self) -> Vec<JobKind> {
+        let mut pressing = Vec::new();
+        if self.thirst >= THIRSTY_TICKS {
+            pressing.push(JobKind::Drink);
+        }
+        if self.hunger >= HUNGRY_TICKS {
+            pressing.push(JobKind::Eat);
+        }
+        if self.fatigue >= TIRED_TICKS {
+            pressing.push(JobKind::Sleep);
+        }
+        pressing
+    }
+
+    /// Name, fill from 0 to 1 where 1 is harmful, and state of each need
+    pub fn describe(&self) -> [(&'static str, f32, &'static str); 3] {
+        [
+            describe_need(
+                "thirst",
+                self.thirst,
+                THIRSTY_TICKS,
+                DEHYDRATED_TICKS,
+                ["thirsty", "dehydrated"],
+            ),
+            describe_need(
+                "hunger",
+                self.hunger,
+                HUNGRY_TICKS,
+                STARVING_TICKS,
+                ["hungry", "starving"],
+            ),
+            describe_need(
+                "fatigue",
+                self.fatigue,
+                TIRED_TICKS,
+                EXHAUSTED_TICKS,
+                ["tired", "exhausted"],
+            ),
+        ]
+    }
+}
+
+fn describe_need(
+    name: &'static str,
+    value: u32,
+    warning: u32,
+    critical: u32,
+    states: [&'static str; 2],
+) -> (&'static str, f32, &'static str) {
+    let state = if value >= critical {
+        states[1]
+    } else if value >= warning {
+        states[0]
+    } else {
+        "fine"
+    };
+    (name, (value as f32 / critical as f32).min(1.0), state)
+}
+
+/// Closest food a creature can eat, the food in stockpiles is eaten first
+fn find_food(
+    map_data: &MapData,
+    nav_graph: &NavGraph,
+    regions: &Regions,
+    stockpiles: &Stockpiles,
+    item_index: &ItemIndex,
+    items: &mut Query<(&mut TilePos, &mut Item)>,
+    reserved: &HashSet<Entity>,
+    pos: UVec3,
+) -> Option<(Entity, UVec3, Vec<UVec3>)> {
+    let (item_pos, entity) = item_index
+        .iter()
+        .filter(|(item_pos, entity)| {
+            !reserved.contains(entity)
+                && items.get_mut(*entity).map_or(false, |(_, item)| {
+                    item.kind.category() == ItemCategory::Food
+                })
+                && regions.is_reachable(pos, *item_pos)
+        })
+        .min_by_key(|(item_pos, _)| {
+            (stockpiles.at(*item_pos).is_none(), distance(pos, *item_pos))
+        })?;
+    let path = nav_graph.find_path(map_data, pos, item_pos)?;
+    Some((entity, item_pos, path))
+}
+
+/// Closest barrel or water tile a creature can drink from
+fn find_drink(
+    map_data: &MapData,
+    nav_graph: &NavGraph,
+    regions: &Regions,
+    item_index: &ItemIndex,
+    items: &mut Query<(&mut TilePos, &mut Item)>,
+    pos: UVec3,
+) -> Option<(UVec3, Vec<UVec3>)> {
+    let mut sources = item_index
+        .iter()
+        .filter(|(_, entity)| {
+            items
+                .get_mut(*entity)
+                .map_or(false, |(_, item)| item.kind == ItemKind::Barrel)
+        })
+        .map(|(item_pos, _)| item_pos)
+        .collect::<Vec<_>>();
+    let min =
+        pos.as_i32() - IVec3::new(WATER_SEARCH_RADIUS, WATER_SEARCH_RADIUS, WATER_SEARCH_DEPTH);
+    let max = IVec3::new(WIDTH as i32 - 1, HEIGHT as i32 - 1, Z_LEVELS as i32 - 1).min(
+        pos.as_i32() + IVec3::new(WATER_SEARCH_RADIUS, WATER_SEARCH_RADIUS, WATER_SEARCH_DEPTH),
+    );
+    let min = min.max(IVec3::ZERO);
+    for z in min.z..=max.z {
+        for y in min.y..=max.y {
+            for x in min.x..=max.x {
+                let tile_pos = UVec3::new(x as u32, y as u32, z as u32);
+                if map_data
+                    .get_tile(tile_pos)
+                    .map_or(false, |tile| tile.value == TileType::Water)
+                {
+                    sources.push(tile_pos);
+                }
+            }
+        }
+    }
+    sources.sort_by_key(|source| distance(pos, *source));
+
+    sources
+        .into_iter()
+        .take(MAX_DRINK_ATTEMPTS)
+        .find_map(|source| {
+            let job = Job {
+                kind: JobKind::Drink,
+                pos: source,
+                priority: JobKind::Drink.default_priority(),
+                claimed_by: None,
+                materials: vec![],
+                suspended_until: 0,
+            };
+            path_to_job(map_data, nav_graph, regions, pos, &job).map(|path| (source, path))
+        })
+}
+
+/// Closest free bed a creature can sleep in
+fn find_bed(
+    map_data: &MapData,
+    nav_graph: &NavGraph,
+    regions: &Regions,
+    item_index: &ItemIndex,
+    items: &mut Query<(&mut TilePos, &mut Item)>,
+    reserved: &HashSet<Entity>,
+    pos: UVec3,
+) -> Option<(Entity, UVec3, Vec<UVec3>)> {
+    let (bed_pos, entity) = item_index
+        .iter()
+        .filter(|(bed_pos, entity)| {
+            !reserved.contains(entity)
+                && items
+                    .get_mut(*entity)
+                    .map_or(false, |(_, item)| item.kind == ItemKind::Bed)
+                && regions.is_reachable(pos, *bed_pos)
+        })
+        .min_by_key(|(bed_pos, _)| distance(pos, *bed_pos))?;
+    let path = nav_graph.find_path(map_data, pos, bed_pos)?;
+    Some((entity, bed_pos, path))
+}
+
+/// The needs grow every tick, pressing needs interrupt the work of the creature
+fn update_needs(
+    simulation_time: Res<SimulationTime>,
+    map_data: Res<MapData>,
+    nav_graph: Res<NavGraph>,
+    regions: Res<Regions>,
+    stockpiles: Res<Stockpiles>,
+    mut item_index: ResMut<ItemIndex>,
+    mut job_board: ResMut<JobBoard>,
+    mut query: Query<
+        (
+            Entity,
+            &TilePos,
+            &mut Movement,
+            &mut Worker,
+            &mut Needs,
+            &mut Health,
+        ),
+        Without<Item>,
+    >,
+    mut items: Query<(&mut TilePos, &mut Item)>,
+) {
+    let tick = simulation_time.tick;
+    let mut reserved = job_board.reserved_items();
+    for (entity, pos, mut movement, mut worker, mut needs, mut health) in query.iter_mut() {
+        let current = worker
+            .job
+            .and_then(|id| job_board.get(id))
+            .map(|job| job.kind);
+        needs.hunger += 1;
+        needs.thirst += 1;
+        if current != Some(JobKind::Sleep) {
+            needs.fatigue += 1;
+        }
+        if tick % NEED_DAMAGE_INTERVAL == 0
+            && (needs.hunger >= STARVING_TICKS || needs.thirst >= DEHYDRATED_TICKS)
+        {
+            health.damage(NEED_DAMAGE);
+        }
+
+        if tick % NEED_CHECK_INTERVAL != 0 || current.map_or(false, |kind| kind.is_need()) {
+            continue;
+        }
+        let mut found = None;
+        for kind in needs.pressing() {
+            found = match kind {
+                JobKind::Drink => find_drink(
+                    &map_data,
+                    &nav_graph,
+                    &regions,
+                    &item_index,
+                    &mut items,
+                    pos.0,
+                )
+                .map(|(source, path)| (kind, source, vec![], path)),
+                JobKind::Eat => find_food(
+                    &map_data,
+                    &nav_graph,
+                    &regions,
+                    &stockpiles,
+                    &item_index,
+                    &mut items,
+                    &reserved,
+                    pos.0,
+                )
+                .map(|(food, food_pos, path)| (kind, food_pos, vec![food], path)),
+                // without a bed the floor will do
+                _ if needs.fatigue >= EXHAUSTED_TICKS => Some((kind, pos.0, vec![], vec![])),
+                _ => Some(
+                    find_bed(
+                        &map_data,
+                        &nav_graph,
+                        &regions,
+                        &item_index,
+                        &mut items,
+                        &reserved,
+                        pos.0,
+                    )
+                    .map_or((kind, pos.0, vec![], vec![]), |(bed, bed_pos, path)| {
+                        (kind, bed_pos, vec![bed], path)
+                    }),
+                ),
+            };
+            if found.is_some() {
+                break;
+            }
+        }
+        let (kind, job_pos, materials, path) = match found {
+            Some(found) => found,
+            None => continue,
+        };
+
+        if let Some(id) = worker.job {
+            job_board.unclaim(id);
+        }
+        drop_item(&mut item_index, &mut items, &mut worker, pos.0);
+        *worker = Worker::default();
+        reserved.extend(materials.iter().copied());
+        worker.job = Some(job_board.assign(kind, job_pos, entity, materials));
+        movement.follow(path);
+    }
+}
+
+/// Creatures eat, drink and sleep once they get to their self-job
+fn fulfil_needs(
+    mut commands: Commands,
+    map_data: Res<MapData>,
+    nav_graph: Res<NavGraph>,
+    regions: Res<Regions>,
+    mut job_board: ResMut<JobBoard>,
+    mut item_index: ResMut<ItemIndex>,
+    mut query: Query<(&TilePos, &mut Movement, &mut Worker, &mut Needs), Without<Item>>,
+    mut items: Query<(&mut TilePos, &mut Item)>,
+) {
+    for (pos, mut movement, mut worker, mut needs) in query.iter_mut() {
+        let id = match worker.job {
+            Some(id) => id,
+            None => continue,
+        };
+        let job = match job_board.get(id) {
+            Some(job) if job.kind.is_need() => job.clone(),
+            _ => continue,
+        };
+        if !movement.is_idle() {
+            continue;
+        }
+
+        if job.kind == JobKind::Eat {
+            if worker.carrying.is_none() {
+                let fetched = match job.materials.first() {
+                    Some(food) => fetch_item(
+                        &map_data,
+                        &nav_graph,
+                        &regions,
+                        &mut job_board,
+                        &mut item_index,
+                        &mut items,
+                        id,
+                        *food,
+                        pos.0,
+                        &mut movement,
+                        &mut worker,
+                    ),
+                    None => {
+                        job_board.remove(id);
+                        *worker = Worker::default();
+                        false
+                    }
+                };
+                if !fetched {
+                    continue;
+                }
+            }
+            worker.progress += 1;
+            if worker.progress >= EAT_TICKS {
+                consume_item(
+                    &mut commands,
+                    &mut item_index,
+                    &mut items,
+                    &mut worker,
+                    pos.0,
+                );
+                needs.hunger = 0;
+                job_board.remove(id);
+                *worker = Worker::default();
+            }
+            continue;
+        }
+
+        if !job.work_sites().contains(&pos.0) {
+            match path_to_job(&map_data, &nav_graph, &regions, pos.0, &job) {
+                Some(path) => movement.follow(path),
+                None => {
+                    job_board.remove(id);
+                    *worker = Worker::default();
+                }
+            }
+            continue;
+        }
+        let done = match job.kind {
+            JobKind::Drink => {
+                worker.progress += 1;
+                if worker.progress >= DRINK_TICKS {
+                    needs.thirst = 0;
+                }
+                needs.thirst == 0
+            }
+            _ => {
+                let recovery = if job.materials.is_empty() {
+                    FLOOR_RECOVERY
+                } else {
+                    BED_RECOVERY
+                };
+                needs.fatigue = needs.fatigue.saturating_sub(recovery);
+                needs.fatigue == 0
+            }
+        };
+        if done {
+            job_board.remove(id);
+            *worker = Worker::default();
+        }
+    }
+}
@@ -0,0 +1,575 @@
+use std::{cmp::Ordering, collections::BinaryHeap};
+
+use bevy::{
+    prelude::*,
+    utils::{HashMap, HashSet, Instant},
+};
+
+use crate::{
+    actions::{Action, ActionState},
+    map::{
+        horizontal_neighbors, ChangedTiles, CurrentZLevel, MapData, MapGeneratedEvent, MapLabel,
+        RenderMode, TileType, ViewRotation, HEIGHT, TILE_WIDTH, WIDTH, Z_LEVELS,
+    },
+    regions::Regions,
+    selector::HoveredTile,
+    utils::{tile_to_world, tile_z_order},
+};
+
+// TODO
+// * more than one entrance on long chunk borders, paths can take small detours right now
+// * diagonal movement
+// * doors that can be locked
+// * build the chunks in parallel
+
+/// Size of the chunks of the abstraction graph, a chunk covers a single z-level
+const NAV_CHUNK_SIZE: u32 = 16;
+/// Creatures can walk in water up to this level
+pub const MAX_WADING_DEPTH: u8 = 3;
+/// Deeper water is swum through, creatures can't go through full tiles without drowning
+pub const MAX_SWIMMING_DEPTH: u8 = 6;
+const STEP_COST: u32 = 10;
+const WADING_COST: u32 = 10;
+const SWIMMING_COST: u32 = 30;
+/// Number of paths kept by the debug overlay
+const MAX_DEBUG_PATHS: usize = 8;
+
+pub struct PathfindingPlugin;
+
+impl Plugin for PathfindingPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app.add_startup_system(path_debug_setup.system())
+            .insert_resource(NavGraph::default())
+            .insert_resource(DebugPaths::default())
+            .add_system(build_nav_graph.system())
+            .add_system(mark_changed_chunks.system().before(MapLabel::UpdateTiles))
+            .add_system(update_nav_graph.system().after(MapLabel::UpdateTiles))
+            .add_system(debug_path.system())
+            .add_system(update_path_overlay.system());
+    }
+}
+
+/// Checks if a creature can stand in the tile
+pub fn is_walkable(map_data: &MapData, pos: UVec3) -> bool {
+    let tile = match map_data.get_tile(pos) {
+        Some(tile) => tile,
+        None => return false,
+    };
+    match tile.value {
+        TileType::UpStair
+        | TileType::DownStair
+        | TileType::UpDownStair
+        | TileType::Ramp
+        | TileType::Door => true,
+        TileType::Air => has_floor(map_data, pos),
+        // swimming creatures don't need a floor
+        TileType::Water => {
+            tile.liquid <= MAX_SWIMMING_DEPTH
+                && (tile.liquid > MAX_WADING_DEPTH || has_floor(map_data, pos))
+        }
+        _ => false,
+    }
+}
+
+/// Solid tiles can be walked on, the top of a ramp can be too
+fn has_floor(map_data: &MapData, pos: UVec3) -> bool {
+    pos.z > 0
+        && matches!(
+            map_data.get_tile(pos - UVec3::Z),
+            Some(below) if below.value.is_solid() || below.value == TileType::Ramp
+        )
+}
+
+/// Cost of moving into the tile
+fn step_cost(map_data: &MapData, pos: UVec3) -> u32 {
+    match map_data.get_tile(pos) {
+        Some(tile) if tile.value == TileType::Water && tile.liquid > MAX_WADING_DEPTH => {
+            STEP_COST + SWIMMING_COST
+        }
+        Some(tile) if tile.value == TileType::Water => STEP_COST + WADING_COST,
+        _ => STEP_COST,
+    }
+}
+
+/// Every tile a creature can move to from a walkable tile, with the cost of the move
+pub fn successors(map_data: &MapData, pos: UVec3) -> Vec<(UVec3, u32)> {
+    let tile = match map_data.get_tile(pos) {
+        Some(tile) => tile.value,
+        None => return vec![],
+    };
+    let mut successors = horizontal_neighbors(pos)
+        .filter(|neighbor| is_walkable(map_data, *neighbor))
+        .collect::<Vec<_>>();
+
+    if pos.z < Z_LEVELS as u32 - 1 {
+        let above = pos + UVec3::Z;
+        let climbs = match map_data.get_tile(above) {
+            Some(above_tile) if tile.goes_up() => above_tile.value.goes_down(),
+            Some(_) if tile == TileType::Ramp => is_walkable(map_data, above),
+            _ => false,
+        };
+        if climbs {
+            successors.push(above);
+        }
+    }
+    if pos.z > 0 {
+        let below = pos - UVec3::Z;
+        let descends = match map_data.get_tile(below) {
+            Some(below_tile) if tile.goes_down() => below_tile.value.goes_up(),
+            Some(below_tile) => below_tile.value == TileType::Ramp,
+            None => false,
+        };
+        if descends {
+            successors.push(below);
+        }
+    }
+
+    successors
+        .into_iter()
+        .map(|successor| (successor, step_cost(map_data, successor)))
+        .collect()
+}
+
+/// Manhattan distance between two tiles
+pub fn distance(a: UVec3, b: UVec3) -> u32 {
+    let diff = (a.as_i32() - b.as_i32()).abs();
+    (diff.x + diff.y + diff.z) as u32
+}
+
+#[derive(PartialEq, Eq)]
+struct Node {
+    cost: u32,
+    pos: UVec3,
+}
+
+// reversed so the heap pops the cheapest node first
+impl Ord for Node {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.cost.cmp(&self.cost)
+    }
+}
+
+impl PartialOrd for Node {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// A* from `start` to `goal`, returns the path including both ends and its cost
+fn astar<F>(start: UVec3, goal: UVec3, mut successors: F) -> Option<(Vec<UVec3>, u32)>
+where
+    F: FnMut(UVec3) -> Vec<(UVec3, u32)>,
+{
+    let mut open = BinaryHeap::new();
+    let mut costs = HashMap::default();
+    let mut came_from = HashMap::default();
+    open.push(Node {
+        cost: distance(start, goal) * STEP_COST,
+        pos: start,
+    });
+    costs.insert(start, 0);
+
+    while let Some(Node { pos, .. }) = open.pop() {
+        let cost = costs[&pos];
+        if pos == goal {
+            let mut path = vec![goal];
+            let mut current = goal;
+            while let Some(previous) = came_from.get(&current) {
+                path.push(*previous);
+                current = *previous;
+            }
+            path.reverse();
+            return Some((path, cost));
+        }
+        for (successor, step) in successors(pos) {
+            let new_cost = cost + step;
+            if matches!(costs.get(&successor), Some(old_cost) if *old_cost <= new_cost) {
+                continue;
+            }
+            costs.insert(successor, new_cost);
+            came_from.insert(successor, pos);
+            open.push(Node {
+                cost: new_cost + distance(successor, goal) * STEP_COST,
+                pos: successor,
+            });
+        }
+    }
+    None
+}
+
+/// Cost of reaching every tile reachable from `start`
+fn dijkstra<F>(start: UVec3, mut successors: F) -> HashMap<UVec3, u32>
+where
+    F: FnMut(UVec3) -> Vec<(UVec3, u32)>,
+{
+    let mut open = BinaryHeap::new();
+    let mut costs = HashMap::default();
+    open.push(Node {
+        cost: 0,
+        pos: start,
+    });
+    costs.insert(start, 0);
+
+    while let Some(Node { cost, pos }) = open.pop() {
+        if cost > costs[&pos] {
+            continue;
+        }
+        for (successor, step) in successors(pos) {
+            let new_cost = cost + step;
+            if matches!(costs.get(&successor), Some(old_cost) if *old_cost <= new_cost) {
+                continue;
+            }
+            costs.insert(successor, new_cost);
+            open.push(Node {
+                cost: new_cost,
+                pos: successor,
+            });
+        }
+    }
+    costs
+}
+
+fn chunk_of(pos: UVec3) -> UVec3 {
+    UVec3::new(pos.x / NAV_CHUNK_SIZE, pos.y / NAV_CHUNK_SIZE, pos.z)
+}
+
+/// Moves that stay inside the chunk
+fn local_successors(map_data: &MapData, pos: UVec3, chunk: UVec3) -> Vec<(UVec3, u32)> {
+    let mut successors = successors(map_data, pos);
+    successors.retain(|(successor, _)| chunk_of(*successor) == chunk);
+    successors
+}
+
+/// Neighbouring tiles along a chunk border are part of the same entrance, only the first tile
+/// of the entrance is kept. Moves between z-levels are always kept.
+fn is_first_of_entrance(map_data: &MapData, from: UVec3, to: UVec3) -> bool {
+    if from.z != to.z {
+        return true;
+    }
+    // the previous tile along the border
+    let step = if from.x != to.x { UVec3::Y } else { UVec3::X };
+    if (step == UVec3::X && from.x == 0) || (step == UVec3::Y && from.y == 0) {
+        return true;
+    }
+    let (previous_from, previous_to) = (from - step, to - step);
+    chunk_of(previous_from) != chunk_of(from)
+        || !is_walkable(map_data, previous_from)
+        || !is_walkable(map_data, previous_to)
+}
+
+/// Abstract graph used to find long paths, the nodes are the entrances between chunks.
+/// Each entrance is connected to the other entrances of its chunk and to the entrance on the
+/// other side of the border.
+#[derive(Default)]
+pub struct NavGraph {
+    chunks: HashMap<UVec3, HashMap<UVec3, Vec<(UVec3, u32)>>>,
+    /// Chunks that need to be rebuilt because some of their tiles changed
+    dirty: HashSet<UVec3>,
+}
+
+impl NavGraph {
+    fn build_chunk(&mut self, map_data: &MapData, chunk: UVec3) {
+        let min = UVec2::new(chunk.x * NAV_CHUNK_SIZE, chunk.y * NAV_CHUNK_SIZE);
+        let max = (min + UVec2::splat(NAV_CHUNK_SIZE)).min(UVec2::new(WIDTH as u32, HEIGHT as u32));
+
+        let mut edges: HashMap<UVec3, Vec<(UVec3, u32)>> = HashMap::default();
+        for y in min.y..max.y {
+            for x in min.x..max.x {
+                let pos = UVec3::new(x, y, chunk.z);
+                if !is_walkable(map_data, pos) {
+                    continue;
+                }
+                for (successor, cost) in successors(map_data, pos) {
+                    if chunk_of(successor) != chunk
+                        && is_first_of_entrance(map_data, pos, successor)
+                    {
+                        edges.entry(pos).or_default().push((successor, cost));
+                    }
+                }
+            }
+        }
+
+        let entrances = edges.keys().copied().collect::<Vec<_>>();
+        for entrance in entrances.iter() {
+            let costs = dijkstra(*entrance, |pos| local_successors(map_data, pos, chunk));
+            let entrance_edges = edges.get_mut(entrance).expect("entrance has no edges");
+            for other in entrances.iter() {
+                if let Some(cost) = costs.get(other).filter(|_| other != entrance) {
+                    entrance_edges.push((*other, *cost));
+                }
+            }
+        }
+
+        if edges.is_empty() {
+            self.chunks.remove(&chunk);
+        } else {
+            self.chunks.insert(chunk, edges);
+        }
+    }
+
+    fn entrances(&self, chunk: UVec3) -> impl Iterator<Item = &UVec3> {
+        self.chunks
+            .get(&chunk)
+            .into_iter()
+            .flat_map(|edges| edges.keys())
+    }
+
+    fn edges(&self, pos: UVec3) -> &[(UVec3, u32)] {
+        self.chunks
+            .get(&chunk_of(pos))
+            .and_then(|edges| edges.get(&pos))
+            .map_or(&[], |edges| edges.as_slice())
+    }
+
+    /// Finds the cheapest path between two walkable tiles, including both ends.
+    /// The path is first found on the abstract graph and then refined chunk by chunk.
+    /// Check `Regions::is_reachable` first, looking for an unreachable goal visits the whole graph
+    pub fn find_path(&self, map_data: &MapData, start: UVec3, goal: UVec3) -> Option<Vec<UVec3>> {
+        if !is_walkable(map_data, start) || !is_walkable(map_data, goal) {
+            return None;
+        }
+        let start_chunk = chunk_of(start);
+        let goal_chunk = chunk_of(goal);
+        if start_chunk == goal_chunk {
+            // the tiles could still be connected through another chunk
+            if let Some((path, _)) = astar(start, goal, |pos| {
+                local_successors(map_data, pos, start_chunk)
+            }) {
+                return Some(path);
+            }
+        }
+
+        // the start and the goal are temporarily connected to the entrances of their chunk
+        let start_costs = dijkstra(start, |pos| local_successors(map_data, pos, start_chunk));
+        let goal_costs = dijkstra(goal, |pos| local_successors(map_data, pos, goal_chunk));
+        let (nodes, _) = astar(start, goal, |node| {
+            let mut edges = self.edges(node).to_vec();
+            if node == start {
+                edges.extend(
+                    self.entrances(start_chunk)
+                        .filter_map(|entrance| start_costs.get(entrance).map(|c| (*entrance, *c))),
+                );
+            }
+            if chunk_of(node) == goal_chunk {
+                if let Some(cost) = goal_costs.get(&node) {
+                    edges.push((goal, *cost));
+                }
+            }
+            edges
+        })?;
+
+        let mut path = vec![start];
+        for pair in nodes.windows(2) {
+            let (from, to) = (pair[0], pair[1]);
+            let chunk = chunk_of(from);
+            if chunk == chunk_of(to) {
+                let (segment, _) = astar(from, to, |pos| local_successors(map_data, pos, chunk))?;
+                path.extend(segment.into_iter().skip(1));
+            } else {
+                path.push(to);
+            }
+        }
+        Some(path)
+    }
+}
+
+fn build_nav_graph(
+    mut events: EventReader<MapGeneratedEvent>,
+    map_data: Res<MapData>,
+    mut nav_graph: ResMut<NavGraph>,
+) {
+    if events.iter().count() == 0 {
+        return;
+    }
+    info!("building nav graph...");
+    let start = Instant::now();
+    nav_graph.chunks.clear();
+    nav_graph.dirty.clear();
+    for z in 0..Z_LEVELS as u32 {
+        for y in 0..(HEIGHT as u32 + NAV_CHUNK_SIZE - 1) / NAV_CHUNK_SIZE {
+            for x in 0..(WIDTH as u32 + NAV_CHUNK_SIZE - 1) / NAV_CHUNK_SIZE {
+                nav_graph.build_chunk(&map_data, UVec3::new(x, y, z));
+            }
+        }
+    }
+    info!(
+        "building nav graph...done elapsed: {:?} chunks: {}",
+        start.elapsed(),
+        nav_graph.chunks.len()
+    );
+}
+
+/// A tile can change the moves of the tiles around it, including the ones on other z-levels
+fn mark_changed_chunks(tiles: Res<ChangedTiles>, mut nav_graph: ResMut<NavGraph>) {
+    if tiles.0.is_empty() {
+        return;
+    }
+    let max = IVec3::new(WIDTH as i32 - 1, HEIGHT as i32 - 1, Z_LEVELS as i32 - 1);
+    for (pos, _) in tiles.0.iter() {
+        for z in -1..=1 {
+            for y in -1..=1 {
+                for x in -1..=1 {
+                    let neighbor = (pos.as_i32() + IVec3::new(x, y, z))
+                        .max(IVec3::ZERO)
+                        .min(max)
+                        .as_u32();
+                    nav_graph.dirty.insert(chunk_of(neighbor));
+                }
+            }
+        }
+    }
+}
+
+fn update_nav_graph(map_data: Res<MapData>, mut nav_graph: ResMut<NavGraph>) {
+    if nav_graph.dirty.is_empty() {
+        return;
+    }
+    let dirty = nav_graph.dirty.drain().collect::<Vec<_>>();
+    for chunk in dirty {
+        nav_graph.build_chunk(&map_data, chunk);
+    }
+}
+
+/// Paths drawn by the debug overlay. The debug action picks the start of a path on the
+/// first press and its goal on the second one
+#[derive(Default)]
+pub struct DebugPaths {
+    pub start: Option<UVec3>,
+    pub paths: Vec<Vec<UVec3>>,
+}
+
+impl DebugPaths {
+    pub fn push(&mut self, path: Vec<UVec3>) {
+        if self.paths.len() >= MAX_DEBUG_PATHS {
+            self.paths.remove(0);
+        }
+        self.paths.push(path);
+    }
+}
+
+struct PathMarker;
+
+struct PathDebugMaterials {
+    isometric: Handle<ColorMaterial>,
+    top_down: Handle<ColorMaterial>,
+    start: Handle<ColorMaterial>,
+}
+
+fn path_debug_setup(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    mut materials: ResMut<Assets<ColorMaterial>>,
+) {
+    let texture_handle = asset_server.load("iso_select.png");
+    commands.insert_resource(PathDebugMaterials {
+        isometric: materials.add(ColorMaterial::modulated_texture(
+            texture_handle.clone(),
+            Color::CYAN,
+        )),
+        top_down: materials.add(Color::rgba(0.0, 1.0, 1.0, 0.4).into()),
+        start: materials.add(ColorMaterial::modulated_texture(
+            texture_handle,
+            Color::ORANGE,
+        )),
+    });
+}
+
+fn debug_path(
+    actions: Res<ActionState>,
+    hovered_tile: Res<HoveredTile>,
+    map_data: Res<MapData>,
+    nav_graph: Res<NavGraph>,
+    regions: Res<Regions>,
+    mut debug_paths: ResMut<DebugPaths>,
+) {
+    if !actions.just_pressed(Action::DebugPath) {
+        return;
+    }
+    let hovered_tile = match hovered_tile.0 {
+        Some(hovered_tile) => hovered_tile,
+        None => return,
+    };
+    // the hovered tile is the floor, creatures walk on the tile above it
+    let tile = hovered_tile + UVec3::Z;
+
+    match debug_paths.start.take() {
+        None => debug_paths.start = Some(tile),
+        Some(start) if !regions.is_reachable(start, tile) => {
+            info!("{} is not reachable from {}", tile, start);
+        }
+        Some(start) => {
+            let timer = Instant::now();
+            match nav_graph.find_path(&map_data, start, tile) {
+                Some(path) => {
+                    info!(
+                        "path from {} to {}: {} tiles elapsed: {:?}",
+                        start,
+                        tile,
+                        path.len(),
+                        timer.elapsed()
+                    );
+                    debug_paths.push(path);
+                }
+                None => info!("no path from {} to {}", start, tile),
+            }
+        }
+    }
+}
+
+/// The markers are respawned whenever something changes, it's only used for debugging
+fn update_path_overlay(
+    mut commands: Commands,
+    debug_paths: Res<DebugPaths>,
+    current_z_level: Res<CurrentZLevel>,
+    render_mode: Res<RenderMode>,
+    rotation: Res<ViewRotation>,
+    materials: Res<PathDebugMaterials>,
+    query: Query<Entity, With<PathMarker>>,
+) {
+    if !debug_paths.is_changed()
+        && !current_z_level.is_changed()
+        && !render_mode.is_changed()
+        && !rotation.is_changed()
+    {
+        return;
+    }
+    for entity in query.iter() {
+        commands.entity(entity).despawn();
+    }
+
+    let tiles = debug_paths
+        .paths
+        .iter()
+        .flatten()
+        .map(|tile| (*tile, false))
+        .chain(debug_paths.start.map(|start| (start, true)));
+    for (tile, is_start) in tiles {
+        // the markers are drawn on the floor below the tile
+        let floor = tile - UVec3::Z.min(tile);
+        let is_visible = match *render_mode {
+            RenderMode::Isometric => floor.z <= current_z_level.0 as u32,
+            RenderMode::TopDown => tile.z == current_z_level.0 as u32,
+        };
+        if !is_visible {
+            continue;
+        }
+        let (material, sprite) = match *render_mode {
+            RenderMode::Isometric if is_start => (materials.start.clone(), Sprite::default()),
+            RenderMode::Isometric => (materials.isometric.clone(), Sprite::default()),
+            RenderMode::TopDown => (
+                materials.top_down.clone(),
+                Sprite::new(Vec2::splat(TILE_WIDTH as f32 / 2.0)),
+            ),
+        };
+        let translation = tile_to_world(floor, *render_mode, *rotation)
+            .extend(tile_z_order(floor.z, *render_mode) + 0.1);
+        commands
+            .spawn_bundle(SpriteBundle {
+                material,
+                sprite,
+                transform: Transform::from_translation(translation),
+                ..Default::default()
+            })
+            .insert(PathMarker);
+    }
+}
@@ -0,0 +1,312 @@
+use std::collections::VecDeque;
+
+use bevy::{
+    prelude::*,
+    utils::{HashMap, HashSet, Instant},
+};
+
+use crate::{
+    map::{ChangedTiles, MapData, MapGeneratedEvent, MapLabel, HEIGHT, WIDTH, Z_LEVELS},
+    pathfinding::{is_walkable, successors},
+};
+
+pub struct RegionsPlugin;
+
+impl Plugin for RegionsPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app.insert_resource(Regions::default())
+            .add_system(build_regions.system())
+            .add_system(mark_changed_tiles.system().before(MapLabel::UpdateTiles))
+            .add_system(update_regions.system().after(MapLabel::UpdateTiles));
+    }
+}
+
+/// Connected groups of walkable tiles. Two tiles in the same region can always reach each other.
+///
+/// Regions that get connected are merged with a union-find instead of relabelling their tiles.
+/// When a change could split a region, the sides are flood filled at the same time and every
+/// side that was completely filled gets a new id.
+pub struct Regions {
+    /// Region of every tile, 0 for tiles that can't be walked on
+    ids: Vec<u32>,
+    /// Root of each region id, it's kept flattened so a lookup is enough to find the root
+    parents: Vec<u32>,
+    ranks: Vec<u8>,
+    /// Tiles changed since the last update
+    changed: Vec<UVec3>,
+}
+
+impl Default for Regions {
+    fn default() -> Self {
+        Self {
+            ids: vec![0; WIDTH * HEIGHT * Z_LEVELS as usize],
+            // the id 0 is reserved for tiles that aren't walkable
+            parents: vec![0],
+            ranks: vec![0],
+            changed: vec![],
+        }
+    }
+}
+
+fn index(pos: UVec3) -> usize {
+    (pos.z as usize * HEIGHT + pos.y as usize) * WIDTH + pos.x as usize
+}
+
+impl Regions {
+    /// Root region of the tile, `None` if it can't be walked on
+    pub fn region(&self, pos: UVec3) -> Option<u32> {
+        match self.ids.get(index(pos)) {
+            Some(0) | None => None,
+            Some(id) => Some(self.parents[*id as usize]),
+        }
+    }
+
+    pub fn is_reachable(&self, from: UVec3, to: UVec3) -> bool {
+        matches!((self.region(from), self.region(to)), (Some(a), Some(b)) if a == b)
+    }
+
+    fn new_region(&mut self) -> u32 {
+        let id = self.parents.len() as u32;
+        self.parents.push(id);
+        self.ranks.push(0);
+        id
+    }
+
+    fn find(&self, mut id: u32) -> u32 {
+        while self.parents[id as usize] != id {
+            id = self.parents[id as usize];
+        }
+        id
+    }
+
+    fn union(&mut self, a: u32, b: u32) {
+        let (a, b) = (self.find(a), self.find(b));
+        if a == b {
+            return;
+        }
+        let (a, b) = if self.ranks[a as usize] < self.ranks[b as usize] {
+            (b, a)
+        } else {
+            (a, b)
+        };
+        self.parents[b as usize] = a;
+        if self.ranks[a as usize] == self.ranks[b as usize] {
+            self.ranks[a as usize] += 1;
+        }
+    }
+
+    /// Points every id directly to its root
+    fn flatten(&mut self) {
+        for id in 0..self.parents.len() as u32 {
+            self.parents[id as usize] = self.find(id);
+        }
+    }
+
+    fn rebuild(&mut self, map_data: &MapData) {
+        *self = Regions::default();
+        for z in 0..Z_LEVELS as u32 {
+            for y in 0..HEIGHT as u32 {
+                for x in 0..WIDTH as u32 {
+                    let pos = UVec3::new(x, y, z);
+                    if self.ids[index(pos)] != 0 || !is_walkable(map_data, pos) {
+                        continue;
+                    }
+                    let id = self.new_region();
+                    self.flood_fill(map_data, pos, id);
+                }
+            }
+        }
+    }
+
+    fn flood_fill(&mut self, map_data: &MapData, start: UVec3, id: u32) {
+        let mut queue = VecDeque::new();
+        self.ids[index(start)] = id;
+        queue.push_back(start);
+        while let Some(pos) = queue.pop_front() {
+            for (successor, _) in successors(map_data, pos) {
+                if self.ids[index(successor)] != id {
+                    self.ids[index(successor)] = id;
+                    queue.push_back(successor);
+                }
+            }
+        }
+    }
+
+    /// Updates the regions around the changed tiles
+    fn update(&mut self, map_data: &MapData, changed: &[UVec3]) {
+        // a tile can change the moves of every tile around it
+        let max = IVec3::new(WIDTH as i32 - 1, HEIGHT as i32 - 1, Z_LEVELS as i32 - 1);
+        let mut affected = HashSet::default();
+        for pos in changed {
+            for z in -1..=1 {
+                for y in -1..=1 {
+                    for x in -1..=1 {
+                        let neighbor = pos.as_i32() + IVec3::new(x, y, z);
+                        if neighbor.cmpge(IVec3::ZERO).all() && neighbor.cmple(max).all() {
+                            affected.insert(neighbor.as_u32());
+                        }
+                    }
+                }
+            }
+        }
+
+        let mut seeds = vec![];
+        for pos in affected.iter() {
+            if !is_walkable(map_data, *pos) {
+                self.ids[index(*pos)] = 0;
+                continue;
+            }
+            if self.ids[index(*pos)] == 0 {
+                self.ids[index(*pos)] = self.new_region();
+            }
+            seeds.push(*pos);
+        }
+
+        // merge the regions connected by the new tiles
+        for pos in seeds.iter() {
+            for (successor, _) in successors(map_data, *pos) {
+                let (a, b) = (self.ids[index(*pos)], self.ids[index(successor)]);
+                if b != 0 {
+                    self.union(a, b);
+                }
+            }
+        }
+        self.flatten();
+
+        // removed tiles or moves can split a region, the seeds of each region are checked
+        let mut seeds_by_region: HashMap<u32, Vec<UVec3>> = HashMap::default();
+        for pos in seeds {
+            let region = self.parents[self.ids[index(pos)] as usize];
+            seeds_by_region.entry(region).or_default().push(pos);
+        }
+        for seeds in seeds_by_region.values() {
+            if seeds.len() > 1 {
+                self.split(map_data, seeds);
+            }
+        }
+        self.flatten();
+    }
+
+    /// Flood fills from every seed at the same time until all of them met or only one side is
+    /// still growing. The sides that were completely filled become new regions.
+    fn split(&mut self, map_data: &MapData, seeds: &[UVec3]) {
+        // union-find of the seeds that met each other
+        let mut groups = (0..seeds.len()).collect::<Vec<_>>();
+        fn group(groups: &[usize], mut seed: usize) -> usize {
+            while groups[seed] != seed {
+                seed = groups[seed];
+            }
+            seed
+        }
+
+        let mut visited: HashMap<UVec3, usize> = HashMap::default();
+        let mut queues = vec![VecDeque::new(); seeds.len()];
+        let mut members = vec![vec![]; seeds.len()];
+        for (seed, pos) in seeds.iter().enumerate() {
+            match visited.get(pos) {
+                Some(other) => {
+                    let (a, b) = (group(&groups, seed), group(&groups, *other));
+                    groups[a] = b;
+                }
+                None => {
+                    visited.insert(*pos, seed);
+                    queues[seed].push_back(*pos);
+                    members[seed].push(*pos);
+                }
+            }
+        }
+
+        loop {
+            let mut roots = HashSet::default();
+            let mut growing = HashSet::default();
+            for seed in 0..seeds.len() {
+                let root = group(&groups, seed);
+                roots.insert(root);
+                if !queues[seed].is_empty() {
+                    growing.insert(root);
+                }
+            }
+            if roots.len() == 1 || growing.len() <= 1 {
+                break;
+            }
+
+            for seed in 0..seeds.len() {
+                let pos = match queues[seed].pop_front() {
+                    Some(pos) => pos,
+                    None => continue,
+                };
+                for (successor, _) in successors(map_data, pos) {
+                    match visited.get(&successor) {
+                        Some(other) => {
+                            let (a, b) = (group(&groups, seed), group(&groups, *other));
+                            groups[a] = b;
+                        }
+                        None => {
+                            visited.insert(successor, seed);
+                            queues[seed].push_back(successor);
+                            members[seed].push(successor);
+                        }
+                    }
+                }
+            }
+        }
+
+        let mut sides: HashMap<usize, Vec<usize>> = HashMap::default();
+        for seed in 0..seeds.len() {
+            sides.entry(group(&groups, seed)).or_default().push(seed);
+        }
+        if sides.len() == 1 {
+            return;
+        }
+        // the side that is still growing, or any side if they all finished, keeps the old id
+        let kept = sides
+            .iter()
+            .find(|(_, side)| side.iter().any(|seed| !queues[*seed].is_empty()))
+            .or_else(|| sides.iter().next())
+            .map(|(root, _)| *root);
+        for (root, side) in sides.iter() {
+            if Some(*root) == kept {
+                continue;
+            }
+            let id = self.new_region();
+            for seed in side {
+                for pos in members[*seed].iter() {
+                    self.ids[index(*pos)] = id;
+                }
+            }
+        }
+    }
+}
+
+fn build_regions(
+    mut events: EventReader<MapGeneratedEvent>,
+    map_data: Res<MapData>,
+    mut regions: ResMut<Regions>,
+) {
+    if events.iter().count() == 0 {
+        return;
+    }
+    info!("building regions...");
+    let start = Instant::now();
+    regions.rebuild(&map_data);
+    info!(
+        "building regions...done elapsed: {:?} regions: {}",
+        start.elapsed(),
+        regions.parents.len() - 1
+    );
+}
+
+fn mark_changed_tiles(tiles: Res<ChangedTiles>, mut regions: ResMut<Regions>) {
+    if tiles.0.is_empty() {
+        return;
+    }
+    regions.changed.extend(tiles.0.iter().map(|(pos, _)| *pos));
+}
+
+fn update_regions(map_data: Res<MapData>, mut regions: ResMut<Regions>) {
+    if regions.changed.is_empty() {
+        return;
+    }
+    let changed = std::mem::take(&mut regions.changed);
+    regions.update(&map_data, &changed);
+}
@@ -0,0 +1,71 @@
+use std::{fs, path::Path};
+
+use anyhow::Result;
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::camera::Bookmarks;
+
+// TODO
+// * save the map and everything on it
+// * multiple save slots
+
+const SAVE_PATH: &str = "saves/save.ron";
+
+pub struct SavePlugin;
+
+impl Plugin for SavePlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        let save_data = SaveData::load_or_default(SAVE_PATH);
+        app.insert_resource(save_data.bookmarks)
+            .add_system(save_on_change.system());
+    }
+}
+
+/// Everything that persists between sessions
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SaveData {
+    pub bookmarks: Bookmarks,
+}
+
+impl SaveData {
+    pub fn load_or_default(path: &str) -> Self {
+        if !Path::new(path).exists() {
+            return SaveData::default();
+        }
+        match SaveData::load(path) {
+            Ok(save_data) => save_data,
+            Err(err) => {
+                warn!("failed to load save file: {:?}", err);
+                SaveData::default()
+            }
+        }
+    }
+
+    pub fn load(path: &str) -> Result<Self> {
+        Ok(ron::de::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    pub fn save(&self, path: &str) -> Result<()> {
+        if let Some(parent) = Path::new(path).parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let save = ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default())?;
+        fs::write(path, save)?;
+        Ok(())
+    }
+}
+
+fn save_on_change(bookmarks: Res<Bookmarks>) {
+    if !bookmarks.is_changed() || bookmarks.is_added() {
+        return;
+    }
+    let save_data = SaveData {
+        bookmarks: bookmarks.clone(),
+    };
+    match save_data.save(SAVE_PATH) {
+        Ok(()) => info!("saved to {}", SAVE_PATH),
+        Err(err) => warn!("failed to save: {:?}", err),
+    }
+}
@@ -1,93 +1,288 @@
 use crate::{
-    camera::{MainCamera, SCALE},
+    actions::{Action, ActionState},
+    camera::MainCamera,
     map::{
-        CurrentZLevel, MapData, Tile, TileType, TilesToUpdate, HEIGHT, TILE_HEIGHT, TILE_WIDTH,
-        WIDTH,
+        CurrentZLevel, MapData, RenderMode, TileType, ViewRotation, HEIGHT, TILE_WIDTH, WIDTH,
+        Z_LEVELS,
     },
-    utils::{cursor_to_world, iso_to_world, world_to_iso},
+    utils::{cursor_to_world, tile_to_world, tile_z_order, world_to_grid, world_to_tile},
 };
-use bevy::{
-    input::{mouse::MouseButtonInput, ElementState},
-    math::Vec4Swizzles,
-    prelude::*,
-};
-
-// TODO
-// * maybe create a list of selected tiles in one system and update the map in another system
-// * instead of just moving the selector around, maybe spawn it when clicked
-//   and remove any other existing selector not part of the current selection
-// * support multi selection
+use bevy::{prelude::*, render::camera::Camera};
+use bevy_egui::EguiContext;
 
-// WARN there's something broken when trying to select tiles at the edge
+/// Selections bigger than this are still selected but not every tile is highlighted
+const MAX_SELECTOR_SPRITES: usize = 4096;
 
 pub struct SelectorPlugin;
 
 impl Plugin for SelectorPlugin {
     fn build(&self, app: &mut AppBuilder) {
         app.add_startup_system(selector_setup.system())
-            .add_system(selector.system());
+            .add_event::<SelectionCommittedEvent>()
+            .insert_resource(Selection { tiles: vec![] })
+            .insert_resource(HoveredTile(None))
+            .add_system(hover.system())
+            .add_system(update_hover_sprite.system())
+            .add_system(selector.system())
+            .add_system(update_selector_sprites.system());
     }
 }
 
+/// Tiles currently selected, in map coordinates
+pub struct Selection {
+    pub tiles: Vec<UVec3>,
+}
+
+/// Tile under the cursor, in map coordinates
+pub struct HoveredTile(pub Option<UVec3>);
+
+/// Sent when the mouse button is released at the end of a selection
+pub struct SelectionCommittedEvent {
+    pub tiles: Vec<UVec3>,
+}
+
 struct Selector;
 
+struct Hover;
+
+/// Selector sprites are reused between selections instead of being spawned every time
+struct SelectorPool(Vec<Entity>);
+
+/// The selector sprite is different for each render mode
+struct SelectorMaterials {
+    isometric: Handle<ColorMaterial>,
+    top_down: Handle<ColorMaterial>,
+    hover_isometric: Handle<ColorMaterial>,
+    hover_top_down: Handle<ColorMaterial>,
+}
+
+impl SelectorMaterials {
+    fn get(&self, render_mode: RenderMode) -> (Handle<ColorMaterial>, Sprite) {
+        match render_mode {
+            RenderMode::Isometric => (self.isometric.clone(), Sprite::default()),
+            RenderMode::TopDown => (
+                self.top_down.clone(),
+                Sprite::new(Vec2::splat(TILE_WIDTH as f32)),
+            ),
+        }
+    }
+
+    fn hover(&self, render_mode: RenderMode) -> (Handle<ColorMaterial>, Sprite) {
+        match render_mode {
+            RenderMode::Isometric => (self.hover_isometric.clone(), Sprite::default()),
+            RenderMode::TopDown => (
+                self.hover_top_down.clone(),
+                Sprite::new(Vec2::splat(TILE_WIDTH as f32)),
+            ),
+        }
+    }
+}
+
+/// Corners of the current drag selection
+#[derive(Default)]
+struct Drag {
+    start: Option<UVec3>,
+    /// Corner the selection box was last built to, it's only rebuilt when this changes
+    end: Option<UVec3>,
+}
+
+fn hover(
+    egui_context: Res<EguiContext>,
+    windows: Res<Windows>,
+    map_data: Res<MapData>,
+    current_z_level: Res<CurrentZLevel>,
+    rotation: Res<ViewRotation>,
+    render_mode: Res<RenderMode>,
+    camera_query: Query<(&Camera, &GlobalTransform), With<MainCamera>>,
+    mut hovered_tile: ResMut<HoveredTile>,
+) {
+    let window = windows.get_primary().expect("primary window not found");
+    let (camera, camera_transform) = camera_query.single().expect("main camera not found");
+    let tile_pos = if egui_context.ctx().is_pointer_over_area() {
+        None
+    } else {
+        cursor_to_world(window, camera, camera_transform).and_then(|cursor_position| {
+            pick_tile(
+                cursor_position,
+                &map_data,
+                current_z_level.0,
+                *render_mode,
+                *rotation,
+            )
+        })
+    };
+    // only touch the resource when needed to avoid moving the sprite every frame
+    if hovered_tile.0 != tile_pos {
+        hovered_tile.0 = tile_pos;
+    }
+}
+
+fn update_hover_sprite(
+    hovered_tile: Res<HoveredTile>,
+    render_mode: Res<RenderMode>,
+    rotation: Res<ViewRotation>,
+    selector_materials: Res<SelectorMaterials>,
+    mut query: Query<
+        (
+            &mut Transform,
+            &mut Visible,
+            &mut Handle<ColorMaterial>,
+            &mut Sprite,
+        ),
+        With<Hover>,
+    >,
+) {
+    if !hovered_tile.is_changed() && !render_mode.is_changed() && !rotation.is_changed() {
+        return;
+    }
+    let (mut transform, mut visible, mut material, mut sprite) =
+        query.single_mut().expect("hover sprite not found");
+    match hovered_tile.0 {
+        Some(tile_pos) => {
+            transform.translation = tile_to_world(tile_pos, *render_mode, *rotation)
+                .extend(tile_z_order(tile_pos.z, *render_mode));
+            visible.is_visible = true;
+            let (new_material, new_sprite) = selector_materials.hover(*render_mode);
+            *material = new_material;
+            *sprite = new_sprite;
+        }
+        None => visible.is_visible = false,
+    }
+}
+
 fn selector(
-    mut mouse_button_input_events: EventReader<MouseButtonInput>,
+    actions: Res<ActionState>,
+    egui_context: Res<EguiContext>,
     windows: Res<Windows>,
     map_data: Res<MapData>,
     current_z_level: Res<CurrentZLevel>,
-    mut tiles: ResMut<TilesToUpdate>,
-    mut queries: QuerySet<(
-        Query<&Transform, With<MainCamera>>,
-        Query<&mut Transform, With<Selector>>,
-    )>,
+    rotation: Res<ViewRotation>,
+    render_mode: Res<RenderMode>,
+    camera_query: Query<(&Camera, &GlobalTransform), With<MainCamera>>,
+    mut drag: Local<Drag>,
+    mut selection: ResMut<Selection>,
+    mut events: EventWriter<SelectionCommittedEvent>,
 ) {
-    for event in mouse_button_input_events.iter() {
-        if let ElementState::Pressed = event.state {
-            let window = windows.get_primary().expect("primary window not found");
-            let camera_transform = queries.q0().single().expect("main camera not found");
-            let cursor_position = cursor_to_world(window, camera_transform, SCALE)
-                .expect("cursor_position not found");
-            let selected_pos = world_to_iso(
-                cursor_position.xy(),
-                TILE_WIDTH as f32,
-                TILE_HEIGHT as f32 / 2.0,
-            );
-
-            for mut selector in queries.q1_mut().iter_mut() {
-                let pos = iso_to_world(&selected_pos, TILE_WIDTH as f32, TILE_HEIGHT as f32 / 2.0);
-                selector.translation = pos.extend(current_z_level.0 as f32);
-
-                let tile_pos = find_highest_tile(selected_pos, &map_data, current_z_level.0);
-                if tile_pos.x <= WIDTH as u32 || tile_pos.y <= HEIGHT as u32 {
-                    // TODO check if there's a tile above to make sure we aren't clicking through a tile
-                    tiles.0.push((
-                        tile_pos,
-                        Tile {
-                            value: TileType::Air,
-                            visible: true,
-                        },
-                    ));
-                }
+    let window = windows.get_primary().expect("primary window not found");
+    let (camera, camera_transform) = camera_query.single().expect("main camera not found");
+    let cursor_position = cursor_to_world(window, camera, camera_transform);
+
+    if actions.just_pressed(Action::Designate) && !selection.tiles.is_empty() {
+        events.send(SelectionCommittedEvent {
+            tiles: selection.tiles.clone(),
+        });
+    }
+
+    // clicks on the ui shouldn't go through to the map
+    if !egui_context.ctx().wants_pointer_input() {
+        if actions.just_pressed(Action::Select) {
+            drag.start = cursor_position.and_then(|cursor_position| {
+                pick_tile(
+                    cursor_position,
+                    &map_data,
+                    current_z_level.0,
+                    *render_mode,
+                    *rotation,
+                )
+            });
+            drag.end = None;
+        } else if actions.just_pressed(Action::ClearSelection) && drag.start.is_none() {
+            selection.tiles.clear();
+        }
+    }
+
+    if let (Some(start), Some(cursor_position)) = (drag.start, cursor_position) {
+        // When extended, the selection goes from the z-level of the start to the z-level of the
+        // end, otherwise the selection stays on the z-level of the start
+        let end = if actions.pressed(Action::ExtendSelection) {
+            pick_tile(
+                cursor_position,
+                &map_data,
+                current_z_level.0,
+                *render_mode,
+                *rotation,
+            )
+        } else {
+            let end = world_to_tile(cursor_position, start.z as u16, *render_mode, *rotation);
+            let max = Vec2::new((WIDTH - 1) as f32, (HEIGHT - 1) as f32);
+            Some(end.max(Vec2::ZERO).min(max).as_u32().extend(start.z))
+        };
+
+        // the box is only rebuilt when its end moves to another tile, this also avoids updating
+        // the sprites every frame
+        if let Some(end) = end.filter(|end| drag.end != Some(*end)) {
+            drag.end = Some(end);
+            selection.tiles = selection_box(start, end);
+        }
+    }
+
+    // the release is handled even when the cursor left the window, otherwise the selection
+    // would never be committed
+    if actions.just_released(Action::Select) && drag.start.take().is_some() {
+        drag.end = None;
+        events.send(SelectionCommittedEvent {
+            tiles: selection.tiles.clone(),
+        });
+    }
+}
+
+/// Every tile in the box between both corners
+fn selection_box(start: UVec3, end: UVec3) -> Vec<UVec3> {
+    let min = start.min(end);
+    let max = start.max(end);
+    let size = max - min + UVec3::ONE;
+    let mut tiles = Vec::with_capacity((size.x * size.y * size.z) as usize);
+    for z in min.z..=max.z {
+        for y in min.y..=max.y {
+            for x in min.x..=max.x {
+                tiles.push(UVec3::new(x, y, z));
             }
         }
     }
+    tiles
 }
 
-fn find_highest_tile(pos: Vec2, map_data: &MapData, current_z_level: u16) -> UVec3 {
-    let mut last_checked_position = pos.as_u32().extend(0);
-    let mut out = last_checked_position;
-    for _z in 0..current_z_level {
-        if let Some(tile) = map_data.get_tile(last_checked_position) {
-            if !matches!(tile.value, TileType::Air) {
-                out = last_checked_position;
+/// Finds the tile under the given point in world coordinates.
+///
+/// In the isometric view, each layer is shifted up by half a tile. The point is checked
+/// against every layer from the current z-level down, the first visible tile that isn't air is
+/// the one drawn on top.
+pub fn pick_tile(
+    pos: Vec2,
+    map_data: &MapData,
+    current_z_level: u16,
+    render_mode: RenderMode,
+    rotation: ViewRotation,
+) -> Option<UVec3> {
+    let current_z_level = current_z_level.min(Z_LEVELS - 1);
+    let is_in_bounds = |tile_pos: Vec2| {
+        tile_pos.x >= 0.0
+            && tile_pos.y >= 0.0
+            && tile_pos.x < WIDTH as f32
+            && tile_pos.y < HEIGHT as f32
+    };
+    match render_mode {
+        RenderMode::Isometric => (0..=current_z_level).rev().find_map(|z| {
+            let tile_pos = world_to_tile(pos, z, render_mode, rotation);
+            if !is_in_bounds(tile_pos) {
+                return None;
             }
-            last_checked_position += UVec3::ONE;
-        } else {
-            break;
+            let tile_pos = tile_pos.as_u32().extend(z as u32);
+            match map_data.get_tile(tile_pos) {
+                Some(tile) if tile.visible && !matches!(tile.value, TileType::Air) => {
+                    Some(tile_pos)
+                }
+                _ => None,
+            }
+        }),
+        RenderMode::TopDown => {
+            let tile_pos = world_to_grid(pos, TILE_WIDTH as f32, rotation);
+            if !is_in_bounds(tile_pos) {
+                return None;
+            }
+            map_data.find_top_tile(tile_pos.as_u32(), current_z_level)
         }
     }
-    out
 }
 
 fn selector_setup(
@@ -96,10 +291,252 @@ fn selector_setup(
     mut materials: ResMut<Assets<ColorMaterial>>,
 ) {
     let texture_handle = asset_server.load("iso_select.png");
+    let selector_materials = SelectorMaterials {
+        isometric: materials.add(texture_handle.clone().into()),
+        top_down: materials.add(Color::rgba(1.0, 1.0, 1.0, 0.3).into()),
+        hover_isometric: materials.add(ColorMaterial::modulated_texture(
+            texture_handle,
+            Color::rgba(1.0, 1.0, 1.0, 0.5),
+        )),
+        hover_top_down: materials.add(Color::rgba(1.0, 1.0, 1.0, 0.15).into()),
+    };
+
+    let (material, sprite) = selector_materials.hover(RenderMode::Isometric);
     commands
         .spawn_bundle(SpriteBundle {
-            material: materials.add(texture_handle.into()),
+            material,
+            sprite,
+            visible: Visible {
+                is_visible: false,
+                is_transparent: true,
+            },
             ..Default::default()
         })
-        .insert(Selector);
+        .insert(Hover);
+
+    commands.insert_resource(selector_materials);
+    commands.insert_resource(SelectorPool(vec![]));
+}
+
+fn update_selector_sprites(
+    mut commands: Commands,
+    selection: Res<Selection>,
+    render_mode: Res<RenderMode>,
+    rotation: Res<ViewRotation>,
+    selector_materials: Res<SelectorMaterials>,
+    mut pool: ResMut<SelectorPool>,
+    mut query: Query<
+        (
+            &mut Transform,
+            &mut Visible,
+            &mut Handle<ColorMaterial>,
+            &mut Sprite,
+        ),
+        With<Selector>,
+    >,
+) {
+    if !selection.is_changed() && !render_mode.is_changed() && !rotation.is_changed() {
+        return;
+    }
+
+    let sprite_count = selection.tiles.len().min(MAX_SELECTOR_SPRITES);
+    for (i, tile_pos) in selection.tiles.iter().take(sprite_count).enumerate() {
+        let translation = tile_to_world(*tile_pos, *render_mode, *rotation)
+            .extend(tile_z_order(tile_pos.z, *render_mode));
+        let (new_material, new_sprite) = selector_materials.get(*render_mode);
+
+        if let Some(entity) = pool.0.get(i) {
+            if let Ok((mut transform, mut visible, mut material, mut sprite)) =
+                query.get_mut(*entity)
+            {
+                transform.translation = translation;
+                visible.is_visible = true;
+                *material = new_material;
+                *sprite = new_sprite;
+            }
+        } else {
+            let entity = commands
+                .spawn_bundle(SpriteBundle {
+                    material: new_material,
+                    sprite: new_sprite,
+                    transform: Transform::from_translation(translation),
+                    ..Default::default()
+                })
+                .insert(Selector)
+                .id();
+            pool.0.push(entity);
+        }
+    }
+
+    for entity in pool.0.iter().skip(sprite_count) {
+        if let Ok((_, mut visible, _, _)) = query.get_mut(*entity) {
+            visible.is_visible = false;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::math::Vec3Swizzles;
+
+    use super::*;
+    use crate::map::{Tile, TILE_HEIGHT};
+
+    const ROTATIONS: [ViewRotation; 4] = [
+        ViewRotation::North,
+        ViewRotation::East,
+        ViewRotation::South,
+        ViewRotation::West,
+    ];
+    const RENDER_MODES: [RenderMode; 2] = [RenderMode::Isometric, RenderMode::TopDown];
+
+    /// `tile_to_world` gives the top corner of the tile in the isometric view, a point on the
+    /// edge could be rounded to a neighbour
+    fn tile_center(pos: UVec3, render_mode: RenderMode, rotation: ViewRotation) -> Vec2 {
+        let offset = match render_mode {
+            RenderMode::Isometric => Vec2::new(0.0, -(TILE_HEIGHT as f32) / 4.0),
+            RenderMode::TopDown => Vec2::ZERO,
+        };
+        tile_to_world(pos, render_mode, rotation) + offset
+    }
+
+    fn map_with_rocks(positions: &[UVec3]) -> MapData {
+        let mut map_data = MapData::new(WIDTH, HEIGHT, Z_LEVELS as usize);
+        for pos in positions {
+            let rock = Tile {
+                visible: true,
+                value: TileType::Rock,
+                ..Default::default()
+            };
+            map_data.set_tile(*pos, rock).unwrap();
+        }
+        map_data
+    }
+
+    #[test]
+    fn tile_to_world_known_positions() {
+        let half_width = TILE_WIDTH as f32 / 2.0;
+        let quarter_height = TILE_HEIGHT as f32 / 4.0;
+        let iso = |pos, rotation| tile_to_world(pos, RenderMode::Isometric, rotation);
+        let top_down = |pos, rotation| tile_to_world(pos, RenderMode::TopDown, rotation);
+
+        assert_eq!(iso(UVec3::ZERO, ViewRotation::North), Vec2::ZERO);
+        assert_eq!(
+            iso(UVec3::X, ViewRotation::North),
+            Vec2::new(half_width, -quarter_height)
+        );
+        assert_eq!(
+            iso(UVec3::Y, ViewRotation::North),
+            Vec2::new(-half_width, -quarter_height)
+        );
+        // each z-level is drawn half a tile higher
+        assert_eq!(
+            iso(UVec3::new(0, 0, 2), ViewRotation::North),
+            Vec2::new(0.0, TILE_HEIGHT as f32)
+        );
+        // the origin is in another corner of the view once rotated
+        let max = (WIDTH - 1) as f32;
+        assert_eq!(
+            iso(UVec3::ZERO, ViewRotation::East),
+            Vec2::new(max * half_width, -max * quarter_height)
+        );
+        assert_eq!(
+            iso(UVec3::ZERO, ViewRotation::South),
+            Vec2::new(0.0, -max * quarter_height * 2.0)
+        );
+        assert_eq!(
+            iso(UVec3::ZERO, ViewRotation::West),
+            Vec2::new(-max * half_width, -max * quarter_height)
+        );
+
+        // the top-down view ignores the z-level
+        assert_eq!(
+            top_down(UVec3::new(0, 0, 7), ViewRotation::North),
+            Vec2::new(half_width, -half_width)
+        );
+        assert_eq!(
+            top_down(UVec3::new(2, 1, 0), ViewRotation::North),
+            Vec2::new(5.0 * half_width, -3.0 * half_width)
+        );
+    }
+
+    #[test]
+    fn world_to_tile_reverses_tile_to_world() {
+        let positions = [
+            UVec3::ZERO,
+            UVec3::new(5, 17, 3),
+            UVec3::new(100, 40, 12),
+            UVec3::new(WIDTH as u32 - 1, HEIGHT as u32 - 1, Z_LEVELS as u32 - 1),
+        ];
+        for rotation in ROTATIONS.iter() {
+            for render_mode in RENDER_MODES.iter() {
+                for pos in positions.iter() {
+                    let world = tile_center(*pos, *render_mode, *rotation);
+                    assert_eq!(
+                        world_to_tile(world, pos.z as u16, *render_mode, *rotation),
+                        pos.xy().as_f32(),
+                        "{:?} {:?} {:?}",
+                        pos,
+                        render_mode,
+                        rotation
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn pick_tile_finds_elevated_tile() {
+        let pos = UVec3::new(10, 12, 5);
+        let map_data = map_with_rocks(&[pos]);
+        for rotation in ROTATIONS.iter() {
+            for render_mode in RENDER_MODES.iter() {
+                let world = tile_center(pos, *render_mode, *rotation);
+                assert_eq!(
+                    pick_tile(world, &map_data, 8, *render_mode, *rotation),
+                    Some(pos),
+                    "{:?} {:?}",
+                    render_mode,
+                    rotation
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn pick_tile_prefers_tile_drawn_on_top() {
+        let below = UVec3::new(10, 12, 5);
+        // a tile one z-level up and one tile closer is drawn over it in the isometric view
+        let above = UVec3::new(11, 13, 6);
+        let map_data = map_with_rocks(&[below, above]);
+        let world = tile_center(below, RenderMode::Isometric, ViewRotation::North);
+        let pick = |z_level| {
+            pick_tile(
+                world,
+                &map_data,
+                z_level,
+                RenderMode::Isometric,
+                ViewRotation::North,
+            )
+        };
+
+        assert_eq!(pick(6), Some(above));
+        // the z-levels above the current one aren't drawn
+        assert_eq!(pick(5), Some(below));
+        assert_eq!(pick(4), None);
+    }
+
+    #[test]
+    fn pick_tile_outside_the_map() {
+        let map_data = map_with_rocks(&[UVec3::ZERO]);
+        for rotation in ROTATIONS.iter() {
+            for render_mode in RENDER_MODES.iter() {
+                let world = Vec2::new(-100_000.0, 100_000.0);
+                assert_eq!(
+                    pick_tile(world, &map_data, 8, *render_mode, *rotation),
+                    None
+                );
+            }
+        }
+    }
 }
@@ -0,0 +1,198 @@
+use bevy::{ecs::schedule::ShouldRun, prelude::*};
+use bevy_egui::{egui, EguiContext};
+use serde::{Deserialize, Serialize};
+
+use crate::actions::{Action, ActionState};
+
+/// Duration of a tick at normal speed, in seconds
+const TICK_DURATION: f64 = 0.1;
+/// Avoids freezing the game when a frame takes longer than the ticks it has to catch up on
+const MAX_TICKS_PER_FRAME: u32 = 10;
+/// Ticks per frame when the game runs at max speed
+const MAX_SPEED_TICKS_PER_FRAME: u32 = 50;
+
+pub const TICKS_PER_DAY: u64 = 1200;
+pub const DAYS_PER_SEASON: u64 = 84;
+pub const SEASONS: [&str; 4] = ["Spring", "Summer", "Autumn", "Winter"];
+
+pub struct SimulationPlugin;
+
+impl Plugin for SimulationPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app.insert_resource(SimulationTime::default())
+            .insert_resource(Calendar::default())
+            .add_stage_after(
+                CoreStage::Update,
+                SimulationStage,
+                SystemStage::parallel().with_run_criteria(simulation_tick.system()),
+            )
+            .add_system_to_stage(SimulationStage, update_calendar.system())
+            .add_system(speed_controls.system())
+            .add_system(time_window.system());
+    }
+}
+
+/// Systems in this stage run once per tick, they can run multiple times per frame or not at all
+/// depending on the game speed
+#[derive(StageLabel, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SimulationStage;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum GameSpeed {
+    Normal,
+    Fast,
+    Faster,
+    /// As many ticks as allowed in a frame, the leftover time is dropped at the end of the frame
+    Max,
+}
+
+const GAME_SPEEDS: [GameSpeed; 4] = [
+    GameSpeed::Normal,
+    GameSpeed::Fast,
+    GameSpeed::Faster,
+    GameSpeed::Max,
+];
+
+impl GameSpeed {
+    fn multiplier(&self) -> f64 {
+        match self {
+            GameSpeed::Normal => 1.0,
+            GameSpeed::Fast => 2.0,
+            GameSpeed::Faster => 5.0,
+            GameSpeed::Max => f64::INFINITY,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            GameSpeed::Normal => "1x",
+            GameSpeed::Fast => "2x",
+            GameSpeed::Faster => "5x",
+            GameSpeed::Max => "max",
+        }
+    }
+}
+
+pub struct SimulationTime {
+    /// Number of ticks since the start of the game
+    pub tick: u64,
+    pub speed: GameSpeed,
+    pub paused: bool,
+    /// Time that hasn't been simulated yet, in seconds
+    accumulator: f64,
+    ticks_this_frame: u32,
+}
+
+impl Default for SimulationTime {
+    fn default() -> Self {
+        Self {
+            tick: 0,
+            speed: GameSpeed::Normal,
+            paused: false,
+            accumulator: 0.0,
+            ticks_this_frame: 0,
+        }
+    }
+}
+
+#[derive(Default, Debug)]
+pub struct Calendar {
+    /// Day of the season, starting at 0
+    pub day: u64,
+    /// Index in `SEASONS`
+    pub season: usize,
+    pub year: u64,
+}
+
+impl Calendar {
+    pub fn from_tick(tick: u64) -> Self {
+        let days = tick / TICKS_PER_DAY;
+        let seasons = days / DAYS_PER_SEASON;
+        Self {
+            day: days % DAYS_PER_SEASON,
+            season: (seasons % SEASONS.len() as u64) as usize,
+            year: seasons / SEASONS.len() as u64,
+        }
+    }
+}
+
+/// Run criteria of the simulation stage, the elapsed time is accumulated and the stage runs once
+/// for every tick that fits in it
+fn simulation_tick(time: Res<Time>, mut simulation_time: ResMut<SimulationTime>) -> ShouldRun {
+    let simulation_time = &mut *simulation_time;
+    let max_ticks = match simulation_time.speed {
+        GameSpeed::Max => MAX_SPEED_TICKS_PER_FRAME,
+        _ => MAX_TICKS_PER_FRAME,
+    };
+
+    // the first check of each frame
+    if simulation_time.ticks_this_frame == 0 && !simulation_time.paused {
+        simulation_time.accumulator +=
+            time.delta_seconds_f64() * simulation_time.speed.multiplier();
+    }
+
+    if simulation_time.paused
+        || simulation_time.accumulator < TICK_DURATION
+        || simulation_time.ticks_this_frame >= max_ticks
+    {
+        simulation_time.ticks_this_frame = 0;
+        // don't try to catch up on the time that was dropped
+        simulation_time.accumulator = simulation_time.accumulator.min(TICK_DURATION);
+        return ShouldRun::No;
+    }
+
+    simulation_time.accumulator -= TICK_DURATION;
+    simulation_time.ticks_this_frame += 1;
+    simulation_time.tick += 1;
+    ShouldRun::YesAndCheckAgain
+}
+
+fn update_calendar(simulation_time: Res<SimulationTime>, mut calendar: ResMut<Calendar>) {
+    if simulation_time.tick % TICKS_PER_DAY == 0 {
+        *calendar = Calendar::from_tick(simulation_time.tick);
+    }
+}
+
+fn speed_controls(actions: Res<ActionState>, mut simulation_time: ResMut<SimulationTime>) {
+    if actions.just_pressed(Action::Pause) {
+        simulation_time.paused = !simulation_time.paused;
+    }
+    for speed in GAME_SPEEDS.iter() {
+        if actions.just_pressed(Action::SetSpeed(*speed)) {
+            simulation_time.speed = *speed;
+            simulation_time.paused = false;
+        }
+    }
+}
+
+fn time_window(
+    egui_context: Res<EguiContext>,
+    calendar: Res<Calendar>,
+    mut simulation_time: ResMut<SimulationTime>,
+) {
+    let mut speed = simulation_time.speed;
+    let mut paused = simulation_time.paused;
+    egui::Window::new("Time")
+        .anchor(egui::Align2::CENTER_TOP, [0., 0.])
+        .resizable(false)
+        .show(egui_context.ctx(), |ui| {
+            ui.label(format!(
+                "{} {}, year {}",
+                SEASONS[calendar.season],
+                calendar.day + 1,
+                calendar.year + 1
+            ));
+            ui.horizontal(|ui| {
+                ui.checkbox(&mut paused, "Paused");
+                for option in GAME_SPEEDS.iter() {
+                    ui.selectable_value(&mut speed, *option, option.name());
+                }
+            });
+        });
+
+    // only touch the resource when needed
+    if speed != simulation_time.speed || paused != simulation_time.paused {
+        simulation_time.speed = speed;
+        simulation_time.paused = paused;
+    }
+}
@@ -0,0 +1,472 @@
+use std::{cmp::Reverse, collections::BTreeMap};
+
+use bevy::{
+    math::Vec3Swizzles,
+    prelude::*,
+    utils::{HashMap, HashSet},
+};
+use bevy_egui::{egui, EguiContext};
+
+use crate::{
+    designation::Tool,
+    items::{Item, ItemCategory, ItemIndex, ITEM_CATEGORIES},
+    jobs::{JobBoard, JobKind, JobLabel},
+    map::{
+        ChangedTiles, CurrentZLevel, MapData, MapGeneratedEvent, MapLabel, RenderMode, TilePos,
+        ViewRotation, TILE_WIDTH, Z_LEVELS,
+    },
+    pathfinding::{distance, is_walkable},
+    regions::Regions,
+    selector::SelectionCommittedEvent,
+    simulation::{SimulationStage, SimulationTime},
+    utils::{is_floor_visible, tile_to_world, tile_z_order},
+};
+
+// TODO
+// * let stockpiles take more than one stack per tile
+// * filter by material
+
+/// Haul jobs are only looked for every few ticks, it goes through every loose item
+const HAUL_INTERVAL: u64 = 10;
+const DEFAULT_PRIORITY: u8 = 3;
+const MAX_PRIORITY: u8 = 5;
+
+pub struct StockpilePlugin;
+
+impl Plugin for StockpilePlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app.add_startup_system(stockpile_setup.system())
+            .insert_resource(Stockpiles::default())
+            .insert_resource(StockpileOverlays(HashMap::default()))
+            .add_system(clear_stockpiles.system())
+            .add_system(paint_stockpiles.system())
+            .add_system(stockpile_panel.system())
+            .add_system(cancel_invalid_hauls.system())
+            .add_system(
+                update_stockpile_overlays
+                    .system()
+                    .before(MapLabel::UpdateTiles),
+            )
+            .add_system_to_stage(
+                SimulationStage,
+                generate_haul_jobs.system().before(JobLabel::Claim),
+            );
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct StockpileId(u32);
+
+/// Tiles where items of the accepted categories are stored, each tile holds one stack
+pub struct Stockpile {
+    pub name: String,
+    pub tiles: Vec<UVec3>,
+    pub categories: HashSet<ItemCategory>,
+    /// Items are hauled to the stockpiles with the highest priority first
+    pub priority: u8,
+}
+
+impl Stockpile {
+    pub fn accepts(&self, item: &Item) -> bool {
+        self.categories.contains(&item.kind.category())
+    }
+}
+
+#[derive(Default)]
+pub struct Stockpiles {
+    piles: BTreeMap<StockpileId, Stockpile>,
+    /// Stockpile of each tile
+    tiles: HashMap<UVec3, StockpileId>,
+    next_id: u32,
+}
+
+impl Stockpiles {
+    /// Creates a stockpile accepting every category, tiles already in a stockpile are skipped
+    pub fn add(&mut self, tiles: Vec<UVec3>) -> Option<StockpileId> {
+        let tiles = tiles
+            .into_iter()
+            .filter(|pos| !self.tiles.contains_key(pos))
+            .collect::<Vec<_>>();
+        if tiles.is_empty() {
+            return None;
+        }
+        self.next_id += 1;
+        let id = StockpileId(self.next_id);
+        for pos in tiles.iter() {
+            self.tiles.insert(*pos, id);
+        }
+        self.piles.insert(
+            id,
+            Stockpile {
+                name: format!("Stockpile {}", self.next_id),
+                tiles,
+                categories: ITEM_CATEGORIES.iter().copied().collect(),
+                priority: DEFAULT_PRIORITY,
+            },
+        );
+        Some(id)
+    }
+
+    /// Removes the tiles from their stockpile, stockpiles left without tiles are deleted
+    pub fn remove_tiles(&mut self, tiles: &[UVec3]) {
+        for pos in tiles {
+            if let Some(id) = self.tiles.remove(pos) {
+                if let Some(pile) = self.piles.get_mut(&id) {
+                    pile.tiles.retain(|tile| tile != pos);
+                }
+            }
+        }
+        self.piles.retain(|_, pile| !pile.tiles.is_empty());
+    }
+
+    pub fn remove(&mut self, id: StockpileId) {
+        if let Some(pile) = self.piles.remove(&id) {
+            for pos in pile.tiles {
+                self.tiles.remove(&pos);
+            }
+        }
+    }
+
+    pub fn get_mut(&mut self, id: StockpileId) -> Option<&mut Stockpile> {
+        self.piles.get_mut(&id)
+    }
+
+    pub fn at(&self, pos: UVec3) -> Option<&Stockpile> {
+        self.tiles.get(&pos).and_then(|id| self.piles.get(id))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&StockpileId, &Stockpile)> {
+        self.piles.iter()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.piles.is_empty()
+    }
+}
+
+struct StockpileOverlay;
+
+/// Overlay sprite of each stockpile tile
+struct StockpileOverlays(HashMap<UVec3, Entity>);
+
+struct StockpileMaterials {
+    isometric: Handle<ColorMaterial>,
+    top_down: Handle<ColorMaterial>,
+}
+
+fn stockpile_setup(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    mut materials: ResMut<Assets<ColorMaterial>>,
+) {
+    let texture_handle = asset_server.load("iso_select.png");
+    let color = Color::rgb(0.8, 0.6, 0.3);
+    let [r, g, b, _] = color.as_rgba_f32();
+    commands.insert_resource(StockpileMaterials {
+        isometric: materials.add(ColorMaterial::modulated_texture(texture_handle, color)),
+        top_down: materials.add(Color::rgba(r, g, b, 0.4).into()),
+    });
+}
+
+/// A new map starts without stockpiles
+fn clear_stockpiles(
+    mut events: EventReader<MapGeneratedEvent>,
+    mut stockpiles: ResMut<Stockpiles>,
+) {
+    if events.iter().count() == 0 {
+        return;
+    }
+    *stockpiles = Stockpiles::default();
+}
+
+/// Items are stored on the floor, so the tiles above the selected ones become the stockpile
+fn paint_stockpiles(
+    mut events: EventReader<SelectionCommittedEvent>,
+    tool: Res<Tool>,
+    map_data: Res<MapData>,
+    mut stockpiles: ResMut<Stockpiles>,
+) {
+    for event in events.iter() {
+        let tiles = event
+            .tiles
+            .iter()
+            .map(|tile_pos| *tile_pos + UVec3::Z)
+            .filter(|pos| map_data.get_tile(*pos).is_some());
+        match *tool {
+            Tool::PlaceStockpile => {
+                let valid_tiles = tiles
+                    .filter(|pos| {
+                        is_walkable(&map_data, *pos)
+                            && map_data
+                                .get_tile(*pos)
+                                .map_or(false, |tile| !tile.value.is_liquid())
+                    })
+                    .collect::<Vec<_>>();
+                let skipped = event.tiles.len() - valid_tiles.len();
+                if let Some(id) = stockpiles.add(valid_tiles) {
+                    info!(
+                        "placed stockpile {:?}, {} invalid tiles skipped",
+                        id, skipped
+                    );
+                }
+            }
+            Tool::RemoveStockpile => {
+                let tiles = tiles.collect::<Vec<_>>();
+                // avoid updating the overlays when there's nothing to remove
+                if tiles.iter().any(|pos| stockpiles.at(*pos).is_some()) {
+                    stockpiles.remove_tiles(&tiles);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn stockpile_panel(
+    egui_context: Res<EguiContext>,
+    item_index: Res<ItemIndex>,
+    mut stockpiles: ResMut<Stockpiles>,
+) {
+    let mut changes = vec![];
+    let mut removed = None;
+    egui::Window::new("Stockpiles")
+        .anchor(egui::Align2::LEFT_CENTER, [0., 0.])
+        .resizable(false)
+        .show(egui_context.ctx(), |ui| {
+            if stockpiles.is_empty() {
+                ui.label("No stockpiles");
+            }
+            for (id, pile) in stockpiles.iter() {
+                ui.separator();
+                ui.horizontal(|ui| {
+                    ui.label(&pile.name);
+                    if ui.small_button("Delete").clicked() {
+                        removed = Some(*id);
+                    }
+                });
+                let used = pile
+                    .tiles
+                    .iter()
+                    .filter(|pos| !item_index.items_at(**pos).is_empty())
+                    .count();
+                ui.label(format!("used: {}/{}", used, pile.tiles.len()));
+
+                let mut priority = pile.priority;
+                ui.add(egui::Slider::new(&mut priority, 1..=MAX_PRIORITY).text("priority"));
+                let mut categories = pile.categories.clone();
+                ui.horizontal(|ui| {
+                    for category in ITEM_CATEGORIES.iter() {
+                        let mut accepted = categories.contains(category);
+                        if ui
+                            .checkbox(&mut accepted, format!("{:?}", category))
+                            .changed()
+                        {
+                            if accepted {
+                                categories.insert(*category);
+                            } else {
+                                categories.remove(category);
+                            }
+                        }
+                    }
+                });
+                if priority != pile.priority || categories != pile.categories {
+                    changes.push((*id, priority, categories));
+                }
+            }
+        });
+
+    // only touch the resource when needed
+    for (id, priority, categories) in changes {
+        if let Some(pile) = stockpiles.get_mut(id) {
+            pile.priority = priority;
+            pile.categories = categories;
+        }
+    }
+    if let Some(id) = removed {
+        stockpiles.remove(id);
+    }
+}
+
+/// Haul jobs to tiles that aren't a stockpile anymore, or don't accept the item, are cancelled
+fn cancel_invalid_hauls(
+    stockpiles: Res<Stockpiles>,
+    mut job_board: ResMut<JobBoard>,
+    items: Query<&Item>,
+) {
+    if !stockpiles.is_changed() {
+        return;
+    }
+    let cancelled = job_board
+        .iter()
+        .filter(|(_, job)| match job.kind {
+            JobKind::Haul { item, destination } => {
+                match (stockpiles.at(destination), items.get(item)) {
+                    (Some(pile), Ok(item)) => !pile.accepts(item),
+                    _ => true,
+                }
+            }
+            _ => false,
+        })
+        .map(|(id, _)| *id)
+        .collect::<Vec<_>>();
+    for id in cancelled {
+        job_board.remove(id);
+    }
+}
+
+/// Loose items are hauled to the stockpile with the highest priority that accepts them, the
+/// closest free tile first. Items already stored only move to a stockpile with a higher priority.
+fn generate_haul_jobs(
+    simulation_time: Res<SimulationTime>,
+    map_data: Res<MapData>,
+    regions: Res<Regions>,
+    stockpiles: Res<Stockpiles>,
+    item_index: Res<ItemIndex>,
+    mut job_board: ResMut<JobBoard>,
+    items: Query<(Entity, &TilePos, &Item)>,
+) {
+    if simulation_time.tick % HAUL_INTERVAL != 0 || stockpiles.is_empty() {
+        return;
+    }
+    let reserved_items = job_board.reserved_items();
+    let mut reserved_tiles = job_board
+        .iter()
+        .filter_map(|(_, job)| match job.kind {
+            JobKind::Haul { destination, .. } => Some(destination),
+            _ => None,
+        })
+        .collect::<HashSet<_>>();
+
+    for (entity, pos, item) in items.iter() {
+        // carried items aren't in the index
+        if reserved_items.contains(&entity) || !item_index.items_at(pos.0).contains(&entity) {
+            continue;
+        }
+        let current_priority = stockpiles
+            .at(pos.0)
+            .filter(|pile| pile.accepts(item))
+            .map(|pile| pile.priority);
+        let destination = stockpiles
+            .iter()
+            .filter(|(_, pile)| {
+                pile.accepts(item) && current_priority.map_or(true, |p| pile.priority > p)
+            })
+            .flat_map(|(_, pile)| pile.tiles.iter().map(move |tile| (pile.priority, *tile)))
+            .filter(|(_, tile)| {
+                !reserved_tiles.contains(tile)
+                    && item_index.items_at(*tile).is_empty()
+                    && regions.is_reachable(pos.0, *tile)
+                    && is_walkable(&map_data, *tile)
+            })
+            .min_by_key(|(priority, tile)| (Reverse(*priority), distance(pos.0, *tile)))
+            .map(|(_, tile)| tile);
+        if let Some(destination) = destination {
+            job_board.add(
+                JobKind::Haul {
+                    item: entity,
+                    destination,
+                },
+                pos.0,
+            );
+            reserved_tiles.insert(destination);
+        }
+    }
+}
+
+/// Only the overlays of the tiles added to a stockpile are spawned, all of them are updated when
+/// the view changes. In top-down mode a tile change can also hide or show the overlays of its
+/// column.
+fn update_stockpile_overlays(
+    mut commands: Commands,
+    map_data: Res<MapData>,
+    stockpiles: Res<Stockpiles>,
+    changed_tiles: Res<ChangedTiles>,
+    current_z_level: Res<CurrentZLevel>,
+    render_mode: Res<RenderMode>,
+    rotation: Res<ViewRotation>,
+    materials: Res<StockpileMaterials>,
+    mut overlays: ResMut<StockpileOverlays>,
+    mut query: Query<
+        (
+            &mut Transform,
+            &mut Visible,
+            &mut Handle<ColorMaterial>,
+            &mut Sprite,
+        ),
+        With<StockpileOverlay>,
+    >,
+) {
+    let view_changed =
+        current_z_level.is_changed() || render_mode.is_changed() || rotation.is_changed();
+    let mut updated = HashSet::default();
+    if stockpiles.is_changed() || view_changed {
+        overlays.0.retain(|pos, entity| {
+            let is_stockpile = stockpiles.tiles.contains_key(pos);
+            if !is_stockpile {
+                commands.entity(*entity).despawn();
+            }
+            is_stockpile
+        });
+        updated.extend(
+            stockpiles
+                .tiles
+                .keys()
+                .filter(|pos| view_changed || !overlays.0.contains_key(pos))
+                .copied(),
+        );
+    }
+    if *render_mode == RenderMode::TopDown && !changed_tiles.0.is_empty() {
+        let columns: HashSet<UVec2> = changed_tiles.0.iter().map(|(pos, _)| pos.xy()).collect();
+        for column in columns {
+            updated.extend(
+                (0..Z_LEVELS as u32)
+                    .map(|z| column.extend(z))
+                    .filter(|pos| stockpiles.tiles.contains_key(pos)),
+            );
+        }
+    }
+    if updated.is_empty() {
+        return;
+    }
+
+    let (new_material, new_sprite) = match *render_mode {
+        RenderMode::Isometric => (materials.isometric.clone(), Sprite::default()),
+        RenderMode::TopDown => (
+            materials.top_down.clone(),
+            Sprite::new(Vec2::splat(TILE_WIDTH as f32)),
+        ),
+    };
+    for pos in updated {
+        // the overlay is drawn on the floor the items lie on
+        let floor = pos - UVec3::Z;
+        let translation = tile_to_world(floor, *render_mode, *rotation)
+            .extend(tile_z_order(floor.z, *render_mode));
+        let is_visible = is_floor_visible(&map_data, pos, current_z_level.0, *render_mode);
+
+        if let Some(entity) = overlays.0.get(&pos) {
+            if let Ok((mut transform, mut visible, mut material, mut sprite)) =
+                query.get_mut(*entity)
+            {
+                transform.translation = translation;
+                visible.is_visible = is_visible;
+                *material = new_material.clone();
+                *sprite = new_sprite.clone();
+            }
+        } else {
+            let entity = commands
+                .spawn_bundle(SpriteBundle {
+                    material: new_material.clone(),
+                    sprite: new_sprite.clone(),
+                    transform: Transform::from_translation(translation),
+                    visible: Visible {
+                        is_visible,
+                        is_transparent: true,
+                    },
+                    ..Default::default()
+                })
+                .insert(StockpileOverlay)
+                .id();
+            overlays.0.insert(pos, entity);
+        }
+    }
+}
@@ -0,0 +1,405 @@
+use std::{cmp::Reverse, collections::BinaryHeap};
+
+use bevy::{
+    math::Vec3Swizzles,
+    prelude::*,
+    utils::{HashMap, HashSet},
+};
+
+use crate::{
+    creature::{Creature, Health, Movement},
+    designation::is_overlay_visible,
+    items::ItemIndex,
+    map::{
+        generator::MAGMA_SEA_DEPTH, neighbors, ChangedTiles, CurrentZLevel, Designation,
+        DesignationsChangedEvent, MapData, MapLabel, RenderMode, Tile, TilePos, TileType,
+        ViewRotation, TILE_WIDTH,
+    },
+    utils::{tile_to_world, tile_z_order},
+};
+
+// TODO
+// * damage the creatures the tiles fall through, not only where they land
+// * let the falling tiles break into boulders
+// * items lying on the falling tiles should fall with them
+
+/// The bottom of the map holds up everything connected to it
+const ANCHOR_Z: u32 = MAGMA_SEA_DEPTH as u32;
+/// Clusters bigger than this are assumed to be anchored, it keeps the check cheap under
+/// mountains
+const MAX_CLUSTER_SIZE: usize = 5000;
+const CAVE_IN_DAMAGE: u32 = 60;
+
+pub struct StructurePlugin;
+
+impl Plugin for StructurePlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app.add_startup_system(structure_setup.system())
+            .insert_resource(RemovedTiles(Vec::new()))
+            .insert_resource(CollapseWarnings(HashMap::default()))
+            .add_system(mark_removed_tiles.system().before(MapLabel::UpdateTiles))
+            .add_system(collapse_unsupported.system().after(MapLabel::UpdateTiles))
+            .add_system(
+                update_collapse_warnings
+                    .system()
+                    .before(MapLabel::UpdateTiles),
+            );
+    }
+}
+
+/// Structural tiles removed this frame, their neighbors may have lost their support
+struct RemovedTiles(Vec<UVec3>);
+
+struct CollapseWarning;
+
+/// Warning sprite of each designation that would make tiles collapse
+struct CollapseWarnings(HashMap<UVec3, Entity>);
+
+struct WarningMaterials {
+    isometric: Handle<ColorMaterial>,
+    top_down: Handle<ColorMaterial>,
+}
+
+fn structure_setup(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    mut materials: ResMut<Assets<ColorMaterial>>,
+) {
+    let texture_handle = asset_server.load("iso_select.png");
+    commands.insert_resource(WarningMaterials {
+        isometric: materials.add(ColorMaterial::modulated_texture(
+            texture_handle,
+            Color::rgb(1.0, 0.0, 0.0),
+        )),
+        top_down: materials.add(Color::rgba(1.0, 0.0, 0.0, 0.6).into()),
+    });
+}
+
+/// Finds the clusters of structural tiles that aren't connected to the bottom of the map
+struct SupportCheck<'a> {
+    map_data: &'a MapData,
+    /// Tiles that are treated as already removed
+    removed: &'a HashSet<UVec3>,
+    /// Whether the cluster of each visited tile is anchored
+    anchored: HashMap<UVec3, bool>,
+}
+
+impl<'a> SupportCheck<'a> {
+    fn new(map_data: &'a MapData, removed: &'a HashSet<UVec3>) -> Self {
+        SupportCheck {
+            map_data,
+            removed,
+            anchored: HashMap::default(),
+        }
+    }
+
+    fn is_structural(&self, pos: UVec3) -> bool {
+        !self.removed.contains(&pos)
+            && self
+                .map_data
+                .get_tile(pos)
+                .map_or(false, |tile| tile.value.is_structural())
+    }
+
+    /// Flood fills the cluster of the tile, the lowest tiles are explored first so the ground is
+    /// found quickly. The cluster is only complete when it isn't anchored.
+    fn cluster(&mut self, start: UVec3) -> (bool, Vec<UVec3>) {
+        let mut visited = HashSet::default();
+        let mut heap = BinaryHeap::new();
+        visited.insert(start);
+        heap.push(Reverse((start.z, start.y, start.x)));
+        let mut anchored = false;
+        while let Some(Reverse((z, y, x))) = heap.pop() {
+            let pos = UVec3::new(x, y, z);
+            if z <= ANCHOR_Z || visited.len() > MAX_CLUSTER_SIZE {
+                anchored = true;
+                break;
+            }
+            // connected to a cluster that was already checked
+            if let Some(known) = self.anchored.get(&pos) {
+                anchored = *known;
+                break;
+            }
+            for neighbor in neighbors(pos) {
+                if self.is_structural(neighbor) && visited.insert(neighbor) {
+                    heap.push(Reverse((neighbor.z, neighbor.y, neighbor.x)));
+                }
+            }
+        }
+        for pos in visited.iter() {
+            self.anchored.insert(*pos, anchored);
+        }
+        (anchored, visited.into_iter().collect())
+    }
+
+    fn is_anchored(&mut self, pos: UVec3) -> bool {
+        if !self.is_structural(pos) {
+            return true;
+        }
+        match self.anchored.get(&pos) {
+            Some(anchored) => *anchored,
+            None => self.cluster(pos).0,
+        }
+    }
+
+    /// Tiles of the cluster that would fall, `None` if it's anchored or was already checked
+    fn unsupported(&mut self, pos: UVec3) -> Option<Vec<UVec3>> {
+        if !self.is_structural(pos) || self.anchored.contains_key(&pos) {
+            return None;
+        }
+        match self.cluster(pos) {
+            (false, tiles) => Some(tiles),
+            _ => None,
+        }
+    }
+}
+
+/// Where each tile of the cluster lands, the lowest tiles land first and the others pile on them
+fn fall(map_data: &MapData, cluster: Vec<UVec3>) -> Vec<(UVec3, UVec3)> {
+    let falling: HashSet<UVec3> = cluster.iter().copied().collect();
+    let mut landed = HashSet::default();
+    let mut tiles = cluster;
+    tiles.sort_by_key(|pos| pos.z);
+    let mut moves = Vec::new();
+    for pos in tiles {
+        let mut landing = pos;
+        while landing.z > 0 {
+            let below = landing - UVec3::Z;
+            let is_free = !landed.contains(&below)
+                && (falling.contains(&below)
+                    || map_data
+                        .get_tile(below)
+                        .map_or(false, |tile| tile.value.is_open()));
+            if !is_free {
+                break;
+            }
+            landing = below;
+        }
+        landed.insert(landing);
+        if landing != pos {
+            moves.push((pos, landing));
+        }
+    }
+    moves
+}
+
+fn mark_removed_tiles(
+    map_data: Res<MapData>,
+    changed_tiles: Res<ChangedTiles>,
+    mut removed: ResMut<RemovedTiles>,
+) {
+    for (pos, old_tile) in changed_tiles.0.iter() {
+        let is_structural = map_data
+            .get_tile(*pos)
+            .map_or(false, |tile| tile.value.is_structural());
+        if old_tile.value.is_structural() && !is_structural {
+            removed.0.push(*pos);
+        }
+    }
+}
+
+/// Clusters that lost their support fall down, crushing what they land on
+fn collapse_unsupported(
+    mut commands: Commands,
+    mut map_data: ResMut<MapData>,
+    mut removed: ResMut<RemovedTiles>,
+    mut changed_tiles: ResMut<ChangedTiles>,
+    mut item_index: ResMut<ItemIndex>,
+    mut creatures: Query<(&mut TilePos, &mut Movement, &mut Health, &Name), With<Creature>>,
+) {
+    if removed.0.is_empty() {
+        return;
+    }
+
+    let start = std::time::Instant::now();
+    let no_removed = HashSet::default();
+    let mut check = SupportCheck::new(&map_data, &no_removed);
+    let mut moves = Vec::new();
+    for pos in removed.0.drain(..) {
+        for neighbor in neighbors(pos) {
+            if let Some(cluster) = check.unsupported(neighbor) {
+                moves.extend(fall(&map_data, cluster));
+            }
+        }
+    }
+    if moves.is_empty() {
+        return;
+    }
+
+    // the tiles are emptied first so the landing tiles aren't overwritten
+    let falling: Vec<Tile> = moves
+        .iter()
+        .map(|(from, _)| *map_data.get_tile(*from).expect("tile out of bounds"))
+        .collect();
+    for ((from, _), tile) in moves.iter().zip(falling.iter()) {
+        let empty = Tile {
+            value: TileType::Air,
+            liquid: 0,
+            constructed: None,
+            burning: false,
+            ..*tile
+        };
+        map_data.update_tile(*from, empty, &mut changed_tiles);
+    }
+    let landed: HashSet<UVec3> = moves.iter().map(|(_, to)| *to).collect();
+    for ((_, to), tile) in moves.iter().zip(falling) {
+        // the fall puts out a fire
+        map_data.update_tile(
+            *to,
+            Tile {
+                liquid: 0,
+                burning: false,
+                ..tile
+            },
+            &mut changed_tiles,
+        );
+        for entity in item_index.items_at(*to).to_vec() {
+            item_index.remove(*to, entity);
+            commands.entity(entity).despawn();
+        }
+    }
+
+    for (mut pos, mut movement, mut health, name) in creatures.iter_mut() {
+        if !landed.contains(&pos.0) {
+            continue;
+        }
+        health.damage(CAVE_IN_DAMAGE);
+        info!("{} was caught in a cave-in", name.as_str());
+        // pushed out on top of the rubble
+        while landed.contains(&pos.0) {
+            pos.0 += UVec3::Z;
+        }
+        movement.stop();
+    }
+
+    info!(
+        "cave-in of {} tiles: {:?}",
+        moves.len(),
+        std::time::Instant::now() - start
+    );
+}
+
+/// Designations that leave nothing behind
+fn removes_tile(designation: Designation) -> bool {
+    matches!(
+        designation,
+        Designation::Dig | Designation::Channel | Designation::Deconstruct
+    )
+}
+
+/// Shows the designations that would make tiles collapse once all of them are done. The support
+/// is only checked again when the designations change or a tile gains or loses its support.
+fn update_collapse_warnings(
+    mut commands: Commands,
+    map_data: Res<MapData>,
+    mut designations_changed: EventReader<DesignationsChangedEvent>,
+    changed_tiles: Res<ChangedTiles>,
+    current_z_level: Res<CurrentZLevel>,
+    render_mode: Res<RenderMode>,
+    rotation: Res<ViewRotation>,
+    materials: Res<WarningMaterials>,
+    mut warnings: ResMut<CollapseWarnings>,
+    mut query: Query<
+        (
+            &mut Transform,
+            &mut Visible,
+            &mut Handle<ColorMaterial>,
+            &mut Sprite,
+        ),
+        With<CollapseWarning>,
+    >,
+) {
+    let structure_changed = changed_tiles.0.iter().any(|(pos, old_tile)| {
+        let is_structural = map_data
+            .get_tile(*pos)
+            .map_or(false, |tile| tile.value.is_structural());
+        old_tile.value.is_structural() != is_structural
+    });
+    let view_changed =
+        current_z_level.is_changed() || render_mode.is_changed() || rotation.is_changed();
+
+    let mut updated = HashSet::default();
+    if designations_changed.iter().count() > 0 || structure_changed {
+        let removed: HashSet<UVec3> = map_data
+            .designations
+            .iter()
+            .filter(|(_, designation)| removes_tile(**designation))
+            .map(|(pos, _)| *pos)
+            .collect();
+        let mut check = SupportCheck::new(&map_data, &removed);
+        let unsafe_tiles: HashSet<UVec3> = removed
+            .iter()
+            .copied()
+            .filter(|pos| neighbors(*pos).any(|neighbor| !check.is_anchored(neighbor)))
+            .collect();
+
+        warnings.0.retain(|pos, entity| {
+            let is_unsafe = unsafe_tiles.contains(pos);
+            if !is_unsafe {
+                commands.entity(*entity).despawn();
+            }
+            is_unsafe
+        });
+        updated.extend(
+            unsafe_tiles
+                .into_iter()
+                .filter(|pos| !warnings.0.contains_key(pos)),
+        );
+    }
+    if view_changed {
+        updated.extend(warnings.0.keys().copied());
+    } else if *render_mode == RenderMode::TopDown && !changed_tiles.0.is_empty() {
+        // a tile change can hide or show the warnings of its column
+        let columns: HashSet<UVec2> = changed_tiles.0.iter().map(|(pos, _)| pos.xy()).collect();
+        updated.extend(
+            warnings
+                .0
+                .keys()
+                .filter(|pos| columns.contains(&pos.xy()))
+                .copied(),
+        );
+    }
+    if updated.is_empty() {
+        return;
+    }
+
+    let (new_material, new_sprite) = match *render_mode {
+        RenderMode::Isometric => (materials.isometric.clone(), Sprite::default()),
+        RenderMode::TopDown => (
+            materials.top_down.clone(),
+            Sprite::new(Vec2::splat(TILE_WIDTH as f32)),
+        ),
+    };
+    for pos in updated {
+        // drawn above the designation overlay
+        let translation = tile_to_world(pos, *render_mode, *rotation)
+            .extend(tile_z_order(pos.z, *render_mode) + 0.1);
+        let is_visible = is_overlay_visible(&map_data, pos, current_z_level.0, *render_mode);
+
+        if let Some(entity) = warnings.0.get(&pos) {
+            if let Ok((mut transform, mut visible, mut material, mut sprite)) =
+                query.get_mut(*entity)
+            {
+                transform.translation = translation;
+                visible.is_visible = is_visible;
+                *material = new_material.clone();
+                *sprite = new_sprite.clone();
+            }
+        } else {
+            let entity = commands
+                .spawn_bundle(SpriteBundle {
+                    material: new_material.clone(),
+                    sprite: new_sprite.clone(),
+                    transform: Transform::from_translation(translation),
+                    visible: Visible {
+                        is_visible,
+                        is_transparent: true,
+                    },
+                    ..Default::default()
+                })
+                .insert(CollapseWarning)
+                .id();
+            warnings.0.insert(pos, entity);
+        }
+    }
+}
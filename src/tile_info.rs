@@ -0,0 +1,88 @@
+use bevy::{math::Vec3Swizzles, prelude::*};
+use bevy_egui::{egui, EguiContext};
+
+use crate::{
+    items::{Item, ItemIndex},
+    map::{MapData, TilePos, MAX_LIQUID_LEVEL, Z_LEVELS},
+    selector::HoveredTile,
+};
+
+pub struct TileInfoPlugin;
+
+impl Plugin for TileInfoPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app.add_system(tile_tooltip.system());
+    }
+}
+
+/// Shows the information of the hovered tile next to the cursor
+fn tile_tooltip(
+    egui_context: Res<EguiContext>,
+    hovered_tile: Res<HoveredTile>,
+    map_data: Res<MapData>,
+    item_index: Res<ItemIndex>,
+    entity_query: Query<(Entity, &TilePos, Option<&Name>), Without<Item>>,
+    item_query: Query<&Item>,
+) {
+    let tile_pos = match hovered_tile.0 {
+        Some(tile_pos) => tile_pos,
+        None => return,
+    };
+    let tile = match map_data.get_tile(tile_pos) {
+        Some(tile) => tile,
+        None => return,
+    };
+
+    egui::show_tooltip(egui_context.ctx(), egui::Id::new("tile_tooltip"), |ui| {
+        ui.label(format!("x: {} y: {}", tile_pos.x, tile_pos.y));
+        // the surface is the highest tile that isn't air
+        match map_data.find_top_tile(tile_pos.xy(), Z_LEVELS - 1) {
+            Some(surface) if surface.z > tile_pos.z => ui.label(format!(
+                "z-level: {} ({} below the surface)",
+                tile_pos.z,
+                surface.z - tile_pos.z
+            )),
+            _ => ui.label(format!("z-level: {}", tile_pos.z)),
+        };
+        ui.label(format!("type: {:?}", tile.value));
+        if tile.constructed.is_some() {
+            ui.label("constructed");
+        }
+        if tile.burning {
+            ui.label("burning");
+        }
+        ui.label(format!("visible: {}", tile.visible));
+        if let Some(biome) = map_data.get_biome(tile_pos.xy()) {
+            ui.label(format!("biome: {:?}", biome));
+        }
+        if tile.liquid > 0 {
+            ui.label(format!("liquid: {}/{}", tile.liquid, MAX_LIQUID_LEVEL));
+        }
+        if let Some(material) = tile.material() {
+            ui.label(format!("material: {:?}", material));
+        }
+        if let Some(designation) = map_data.designations.get(&tile_pos) {
+            ui.label(format!("designation: {}", designation.name()));
+        }
+
+        // creatures stand in the tile above the hovered floor
+        for (entity, pos, name) in entity_query.iter() {
+            if pos.0 != tile_pos && pos.0 != tile_pos + UVec3::Z {
+                continue;
+            }
+            match name {
+                Some(name) => ui.label(name.as_str()),
+                None => ui.label(format!("{:?}", entity)),
+            };
+        }
+        for entity in item_index
+            .items_at(tile_pos)
+            .iter()
+            .chain(item_index.items_at(tile_pos + UVec3::Z))
+        {
+            if let Ok(item) = item_query.get(*entity) {
+                ui.label(item.name());
+            }
+        }
+    });
+}
@@ -1,7 +1,13 @@
-use bevy::{math::Vec2, prelude::*, window::Window};
+use bevy::{
+    math::{Vec2, Vec3Swizzles},
+    prelude::*,
+    render::camera::Camera,
+    window::Window,
+};
 use std::ops::Sub;
 
-#[allow(unused)]
+use crate::map::{MapData, RenderMode, ViewRotation, TILE_HEIGHT, TILE_WIDTH, Z_LEVELS};
+
 pub fn lerp<T: num::Float + Sub>(a: T, b: T, v: T) -> T {
     (T::one() - v) * a + b * v
 }
@@ -12,38 +18,132 @@ pub fn inverse_lerp<T: num::Float + Sub>(a: T, b: T, v: T) -> T {
 }
 
 /// Transforms a point in world coordinates to an isometric projection
-/// WARN only works with a single layer, doesn't take into accound any z-levels
-pub fn world_to_iso(pos: Vec2, tile_width: f32, tile_height: f32) -> Vec2 {
+/// The returned position is in map coordinates, the view rotation is already undone
+/// WARN only works with a single layer, use `selector::pick_tile` to take the z-levels into account
+pub fn world_to_iso(pos: Vec2, tile_width: f32, tile_height: f32, rotation: ViewRotation) -> Vec2 {
     let x = (pos.x / tile_width) + (-pos.y / tile_height);
     let y = (-pos.y / tile_height) - (pos.x / tile_width);
-    Vec2::new(x.floor(), y.floor())
+    rotation.view_to_map(Vec2::new(x.floor(), y.floor()))
 }
 
 /// Transforms a point in isometric projection to world coordinates
-pub fn iso_to_world(pos: &Vec2, tile_width: f32, tile_height: f32) -> Vec2 {
+/// The position is in map coordinates and is rotated to match the current view
+pub fn iso_to_world(pos: &Vec2, tile_width: f32, tile_height: f32, rotation: ViewRotation) -> Vec2 {
+    let pos = rotation.map_to_view(*pos);
     let x = (pos.x - pos.y) * tile_width / 2.0;
     let y = (pos.x + pos.y) * tile_height / 2.0;
     Vec2::new(x, -y)
 }
 
-pub fn cursor_to_world(window: &Window, camera_transform: &Transform, scale: f32) -> Option<Vec4> {
-    if let Some(cursor_position) = window.cursor_position() {
-        let window_size = Vec2::new(window.width() as f32, window.height() as f32);
+/// Transforms a point in world coordinates to a tile position in the top down view
+/// The grid is flipped vertically to keep the same orientation as the isometric view
+pub fn world_to_grid(pos: Vec2, tile_size: f32, rotation: ViewRotation) -> Vec2 {
+    let view_pos = Vec2::new(pos.x / tile_size, -pos.y / tile_size);
+    rotation.view_to_map(view_pos.floor())
+}
+
+/// Transforms a tile position in the top down view to the world coordinates of the tile's center
+pub fn grid_to_world(pos: &Vec2, tile_size: f32, rotation: ViewRotation) -> Vec2 {
+    let pos = rotation.map_to_view(*pos);
+    Vec2::new(pos.x + 0.5, -pos.y - 0.5) * tile_size
+}
 
-        // the default orthographic projection is in pixels from the center;
-        // just undo the translation
-        let cursor_position = cursor_position - window_size / 2.0;
+/// Transforms a tile position in map coordinates to world coordinates for the given render mode.
+/// In the isometric view this includes the offset of the tile's layer
+pub fn tile_to_world(pos: UVec3, render_mode: RenderMode, rotation: ViewRotation) -> Vec2 {
+    let tile_pos = pos.xy().as_f32();
+    match render_mode {
+        RenderMode::Isometric => {
+            let layer_offset = Vec2::new(0.0, pos.z as f32 * TILE_HEIGHT as f32 / 2.0);
+            iso_to_world(
+                &tile_pos,
+                TILE_WIDTH as f32,
+                TILE_HEIGHT as f32 / 2.0,
+                rotation,
+            ) + layer_offset
+        }
+        RenderMode::TopDown => grid_to_world(&tile_pos, TILE_WIDTH as f32, rotation),
+    }
+}
 
-        let pos_world =
-            camera_transform.compute_matrix() * cursor_position.extend(0.0).extend(1.0) * scale; // The scale doesn't work properly if you move the camera
-        Some(pos_world)
-    } else {
-        None
+/// Depth of a sprite drawn on the tile, in the isometric view it needs to be above the tile's
+/// layer but below the next one
+pub fn tile_z_order(z: u32, render_mode: RenderMode) -> f32 {
+    match render_mode {
+        RenderMode::Isometric => z as f32 + 0.5,
+        RenderMode::TopDown => Z_LEVELS as f32 + 1.0,
     }
 }
 
+/// Position of a sprite of the given height standing in the tile, it's drawn on the floor below
+/// the tile
+pub fn floor_translation(
+    pos: UVec3,
+    height: f32,
+    render_mode: RenderMode,
+    rotation: ViewRotation,
+) -> Vec3 {
+    let floor = UVec3::new(pos.x, pos.y, pos.z.saturating_sub(1));
+    let offset = match render_mode {
+        RenderMode::Isometric => Vec2::new(0.0, (TILE_HEIGHT as f32 + height) / 4.0),
+        RenderMode::TopDown => Vec2::ZERO,
+    };
+    (tile_to_world(floor, render_mode, rotation) + offset)
+        .extend(tile_z_order(floor.z, render_mode) + 0.2)
+}
+
+/// Sprites standing in a tile are hidden with the z-level of their floor, like the chunks
+pub fn is_floor_visible(
+    map_data: &MapData,
+    pos: UVec3,
+    current_z_level: u16,
+    render_mode: RenderMode,
+) -> bool {
+    let floor = UVec3::new(pos.x, pos.y, pos.z.saturating_sub(1));
+    match render_mode {
+        RenderMode::Isometric => floor.z <= current_z_level as u32,
+        RenderMode::TopDown => map_data.find_top_tile(floor.xy(), current_z_level) == Some(floor),
+    }
+}
+
+/// Transforms a point in world coordinates to a tile position in map coordinates on the given z-level
+pub fn world_to_tile(
+    pos: Vec2,
+    z_level: u16,
+    render_mode: RenderMode,
+    rotation: ViewRotation,
+) -> Vec2 {
+    match render_mode {
+        RenderMode::Isometric => {
+            let layer_offset = Vec2::new(0.0, z_level as f32 * TILE_HEIGHT as f32 / 2.0);
+            world_to_iso(
+                pos - layer_offset,
+                TILE_WIDTH as f32,
+                TILE_HEIGHT as f32 / 2.0,
+                rotation,
+            )
+        }
+        RenderMode::TopDown => world_to_grid(pos, TILE_WIDTH as f32, rotation),
+    }
+}
+
+/// Converts the cursor position to world coordinates using the camera's projection,
+/// this works with any zoom level and camera position
+pub fn cursor_to_world(
+    window: &Window,
+    camera: &Camera,
+    camera_transform: &GlobalTransform,
+) -> Option<Vec2> {
+    let cursor_position = window.cursor_position()?;
+    let window_size = Vec2::new(window.width(), window.height());
+
+    // convert the cursor position to normalized device coordinates and undo the projection
+    let ndc = (cursor_position / window_size) * 2.0 - Vec2::ONE;
+    let ndc_to_world = camera_transform.compute_matrix() * camera.projection_matrix.inverse();
+    Some(ndc_to_world.project_point3(ndc.extend(0.0)).xy())
+}
+
 /// based on this gdc talk <https://www.youtube.com/watch?v=LWFzPP8ZbdU>
-#[allow(unused)]
 pub fn squirrel_noise(position: i32, seed: u32) -> u32 {
     const BIT_NOISE1: u32 = 0x68E31DA4; // 1101 0001 1100 0110 0011 1011 0100 1000
     const BIT_NOISE2: u32 = 0xB5297A4D; // 1011 0101 0010 1001 0111 1010 0100 1101
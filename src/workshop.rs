@@ -0,0 +1,678 @@
+use std::fs;
+
+use anyhow::Result;
+use bevy::{
+    prelude::*,
+    utils::{HashMap, HashSet},
+};
+use bevy_egui::{egui, EguiContext};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    creature::Labor,
+    designation::Tool,
+    items::{Item, ItemIndex, ItemKind},
+    jobs::{JobBoard, JobKind, JobLabel},
+    map::{
+        CurrentZLevel, MapData, MapGeneratedEvent, RenderMode, TilePos, ViewRotation, HEIGHT,
+        TILE_WIDTH, WIDTH,
+    },
+    pathfinding::{distance, is_walkable},
+    regions::Regions,
+    selector::SelectionCommittedEvent,
+    simulation::{SimulationStage, SimulationTime},
+    stockpile::Stockpiles,
+    utils::{is_floor_visible, tile_to_world, tile_z_order},
+};
+
+// TODO
+// * smelt ores once the map has them
+// * fuel for the smelter
+// * workshops blocking movement on some of their tiles
+
+const RECIPES_PATH: &str = "assets/recipes.ron";
+/// Craft jobs are only looked for every few ticks
+const CRAFT_INTERVAL: u64 = 10;
+/// Workshops are squares of this many tiles on each side
+const WORKSHOP_SIZE: u32 = 3;
+const MAX_ORDER_AMOUNT: u32 = 50;
+
+pub const WORKSHOP_KINDS: [WorkshopKind; 4] = [
+    WorkshopKind::Carpenter,
+    WorkshopKind::Mason,
+    WorkshopKind::Smelter,
+    WorkshopKind::Kitchen,
+];
+
+pub struct WorkshopPlugin;
+
+impl Plugin for WorkshopPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app.add_startup_system(workshop_setup.system())
+            .insert_resource(Recipes::load_or_default(RECIPES_PATH))
+            .add_system(despawn_workshops.system())
+            .add_system(place_workshops.system())
+            .add_system(update_workshop_sprites.system())
+            .add_system(workshop_panel.system())
+            .add_system_to_stage(
+                SimulationStage,
+                generate_craft_jobs.system().before(JobLabel::Claim),
+            );
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum WorkshopKind {
+    Carpenter,
+    Mason,
+    Smelter,
+    Kitchen,
+}
+
+impl WorkshopKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            WorkshopKind::Carpenter => "Carpenter",
+            WorkshopKind::Mason => "Mason",
+            WorkshopKind::Smelter => "Smelter",
+            WorkshopKind::Kitchen => "Kitchen",
+        }
+    }
+
+    fn color(&self) -> Color {
+        match self {
+            WorkshopKind::Carpenter => Color::rgb(0.7, 0.5, 0.3),
+            WorkshopKind::Mason => Color::rgb(0.6, 0.6, 0.65),
+            WorkshopKind::Smelter => Color::rgb(0.9, 0.35, 0.2),
+            WorkshopKind::Kitchen => Color::rgb(0.5, 0.75, 0.35),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct ItemAmount {
+    pub kind: ItemKind,
+    pub quantity: u32,
+}
+
+/// Turns input items into output items at a workshop, the outputs are made of the material of the
+/// first input
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Recipe {
+    pub name: String,
+    pub workshop: WorkshopKind,
+    pub inputs: Vec<ItemAmount>,
+    pub outputs: Vec<ItemAmount>,
+    pub labor: Labor,
+    /// Ticks needed to make it without any skill
+    pub ticks: u32,
+}
+
+/// Every recipe, they are referred to by their index
+#[derive(Default)]
+pub struct Recipes(Vec<Recipe>);
+
+impl Recipes {
+    pub fn load_or_default(path: &str) -> Self {
+        match Recipes::load(path) {
+            Ok(recipes) => recipes,
+            Err(err) => {
+                warn!("failed to load recipes: {:?}", err);
+                Recipes::default()
+            }
+        }
+    }
+
+    pub fn load(path: &str) -> Result<Self> {
+        let recipes: Vec<Recipe> = ron::de::from_str(&fs::read_to_string(path)?)?;
+        // the material of the outputs comes from the inputs
+        let (recipes, invalid): (Vec<_>, Vec<_>) = recipes
+            .into_iter()
+            .partition(|recipe| !recipe.inputs.is_empty());
+        for recipe in invalid {
+            warn!("recipe {} has no inputs, it's skipped", recipe.name);
+        }
+        Ok(Recipes(recipes))
+    }
+
+    pub fn get(&self, index: usize) -> Option<&Recipe> {
+        self.0.get(index)
+    }
+
+    pub fn for_workshop(&self, kind: WorkshopKind) -> impl Iterator<Item = (usize, &Recipe)> {
+        self.0
+            .iter()
+            .enumerate()
+            .filter(move |(_, recipe)| recipe.workshop == kind)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum OrderMode {
+    /// Makes this many more
+    Make(u32),
+    Repeat,
+    /// Makes more while there are less of the first output than this
+    UntilStock(u32),
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct WorkOrder {
+    pub recipe: usize,
+    pub mode: OrderMode,
+}
+
+impl WorkOrder {
+    fn describe(&self, recipes: &Recipes) -> String {
+        let name = recipes.get(self.recipe).map_or("?", |recipe| &recipe.name);
+        match self.mode {
+            OrderMode::Make(amount) => format!("{}: make {}", name, amount),
+            OrderMode::Repeat => format!("{}: repeat", name),
+            OrderMode::UntilStock(amount) => format!("{}: until {} in stock", name, amount),
+        }
+    }
+
+    /// Whether more items are still wanted, the items are only counted once an order needs it
+    pub fn needs_work(
+        &self,
+        recipes: &Recipes,
+        stock: &mut Option<HashMap<ItemKind, u32>>,
+        items: &Query<&Item>,
+    ) -> bool {
+        match self.mode {
+            OrderMode::Make(amount) => amount > 0,
+            OrderMode::Repeat => true,
+            OrderMode::UntilStock(amount) => {
+                let output = recipes
+                    .get(self.recipe)
+                    .and_then(|recipe| recipe.outputs.first());
+                let stock = stock.get_or_insert_with(|| {
+                    let mut stock = HashMap::default();
+                    for item in items.iter() {
+                        *stock.entry(item.kind).or_insert(0) += item.quantity;
+                    }
+                    stock
+                });
+                output.map_or(false, |output| {
+                    stock.get(&output.kind).copied().unwrap_or(0) < amount
+                })
+            }
+        }
+    }
+}
+
+/// Building where dwarves craft items, its `TilePos` is the center tile where the work is done
+pub struct Workshop {
+    pub kind: WorkshopKind,
+    /// Orders are worked on in order, the first one that can be done is picked
+    pub orders: Vec<WorkOrder>,
+}
+
+impl Workshop {
+    /// First order of the recipe, the one a craft job of the recipe works on
+    pub fn order(&self, recipe: usize) -> Option<&WorkOrder> {
+        self.orders.iter().find(|order| order.recipe == recipe)
+    }
+
+    /// Counts down the first order of the recipe, orders that are done are removed
+    pub fn complete_order(&mut self, recipe: usize) {
+        let index = match self.orders.iter().position(|order| order.recipe == recipe) {
+            Some(index) => index,
+            None => return,
+        };
+        if let OrderMode::Make(amount) = &mut self.orders[index].mode {
+            *amount = amount.saturating_sub(1);
+            if *amount == 0 {
+                self.orders.remove(index);
+            }
+        }
+    }
+}
+
+/// Tiles covered by a workshop centered on the tile, tiles outside of the map are left out
+pub fn footprint(center: UVec3) -> Vec<UVec3> {
+    let radius = (WORKSHOP_SIZE / 2) as i32;
+    let mut tiles = vec![];
+    for y in -radius..=radius {
+        for x in -radius..=radius {
+            let pos = center.as_i32() + IVec3::new(x, y, 0);
+            if pos.x >= 0 && pos.y >= 0 && (pos.x as usize) < WIDTH && (pos.y as usize) < HEIGHT {
+                tiles.push(pos.as_u32());
+            }
+        }
+    }
+    tiles
+}
+
+/// Closest loose items with enough of every input and their position, they have to be reachable
+/// from the worker. Stockpiled items are picked first, like the food in `needs::find_food`
+pub fn find_inputs(
+    recipe: &Recipe,
+    regions: &Regions,
+    stockpiles: &Stockpiles,
+    item_index: &ItemIndex,
+    items: &Query<&Item>,
+    reserved: &HashSet<Entity>,
+    pos: UVec3,
+) -> Option<Vec<(Entity, UVec3)>> {
+    let mut candidates = item_index
+        .iter()
+        .filter(|(item_pos, entity)| {
+            !reserved.contains(entity) && regions.is_reachable(pos, *item_pos)
+        })
+        .filter_map(|(item_pos, entity)| {
+            let item = items.get(entity).ok()?;
+            Some((entity, item_pos, item.kind, item.quantity))
+        })
+        .collect::<Vec<_>>();
+    candidates.sort_by_key(|(_, item_pos, _, _)| {
+        (stockpiles.at(*item_pos).is_none(), distance(pos, *item_pos))
+    });
+
+    let mut inputs: Vec<(Entity, UVec3)> = vec![];
+    for input in recipe.inputs.iter() {
+        let mut needed = input.quantity;
+        for (entity, item_pos, kind, quantity) in candidates.iter() {
+            if needed == 0 {
+                break;
+            }
+            if *kind == input.kind && !inputs.iter().any(|(other, _)| other == entity) {
+                needed = needed.saturating_sub(*quantity);
+                inputs.push((*entity, *item_pos));
+            }
+        }
+        if needed > 0 {
+            return None;
+        }
+    }
+    Some(inputs)
+}
+
+struct WorkshopMaterials {
+    isometric: HashMap<WorkshopKind, Handle<ColorMaterial>>,
+    top_down: HashMap<WorkshopKind, Handle<ColorMaterial>>,
+}
+
+impl WorkshopMaterials {
+    fn get(
+        &self,
+        kind: WorkshopKind,
+        render_mode: RenderMode,
+    ) -> (Handle<ColorMaterial>, Sprite, Vec3) {
+        match render_mode {
+            // the selection texture is a single tile, it's scaled to cover the workshop
+            RenderMode::Isometric => (
+                self.isometric[&kind].clone(),
+                Sprite::default(),
+                Vec3::new(WORKSHOP_SIZE as f32, WORKSHOP_SIZE as f32, 1.0),
+            ),
+            RenderMode::TopDown => (
+                self.top_down[&kind].clone(),
+                Sprite::new(Vec2::splat((TILE_WIDTH as u32 * WORKSHOP_SIZE) as f32)),
+                Vec3::ONE,
+            ),
+        }
+    }
+}
+
+fn workshop_setup(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    mut materials: ResMut<Assets<ColorMaterial>>,
+) {
+    let texture_handle = asset_server.load("iso_select.png");
+    let mut isometric = HashMap::default();
+    let mut top_down = HashMap::default();
+    for kind in WORKSHOP_KINDS.iter() {
+        let color = kind.color();
+        isometric.insert(
+            *kind,
+            materials.add(ColorMaterial::modulated_texture(
+                texture_handle.clone(),
+                color,
+            )),
+        );
+        let [r, g, b, _] = color.as_rgba_f32();
+        top_down.insert(*kind, materials.add(Color::rgba(r, g, b, 0.6).into()));
+    }
+    commands.insert_resource(WorkshopMaterials {
+        isometric,
+        top_down,
+    });
+}
+
+/// A new map starts without workshops
+fn despawn_workshops(
+    mut commands: Commands,
+    mut events: EventReader<MapGeneratedEvent>,
+    query: Query<Entity, With<Workshop>>,
+) {
+    if events.iter().count() == 0 {
+        return;
+    }
+    for entity in query.iter() {
+        commands.entity(entity).despawn();
+    }
+}
+
+fn workshop_transform(center: UVec3, render_mode: RenderMode, rotation: ViewRotation) -> Vec3 {
+    // drawn on the floor of the center tile
+    let floor = center - UVec3::Z;
+    tile_to_world(floor, render_mode, rotation).extend(tile_z_order(floor.z, render_mode))
+}
+
+/// Places a workshop centered on the tile above the first selected tile, every tile it covers has
+/// to be a free floor
+fn place_workshops(
+    mut commands: Commands,
+    mut events: EventReader<SelectionCommittedEvent>,
+    tool: Res<Tool>,
+    map_data: Res<MapData>,
+    current_z_level: Res<CurrentZLevel>,
+    render_mode: Res<RenderMode>,
+    rotation: Res<ViewRotation>,
+    materials: Res<WorkshopMaterials>,
+    query: Query<&TilePos, With<Workshop>>,
+) {
+    let kind = match *tool {
+        Tool::PlaceWorkshop(kind) => kind,
+        _ => return,
+    };
+    let occupied = query
+        .iter()
+        .flat_map(|pos| footprint(pos.0))
+        .collect::<HashSet<_>>();
+    for event in events.iter() {
+        let center = match event.tiles.first() {
+            Some(tile_pos) => *tile_pos + UVec3::Z,
+            None => continue,
+        };
+        let tiles = footprint(center);
+        let is_valid = tiles.len() == (WORKSHOP_SIZE * WORKSHOP_SIZE) as usize
+            && tiles.iter().all(|pos| {
+                !occupied.contains(pos)
+                    && is_walkable(&map_data, *pos)
+                    && map_data
+                        .get_tile(*pos)
+                        .map_or(false, |tile| !tile.value.is_liquid())
+            });
+        if !is_valid {
+            info!("can't place a {} at {}", kind.name(), center);
+            continue;
+        }
+
+        let (material, sprite, scale) = materials.get(kind, *render_mode);
+        commands
+            .spawn_bundle(SpriteBundle {
+                material,
+                sprite,
+                transform: Transform {
+                    translation: workshop_transform(center, *render_mode, *rotation),
+                    scale,
+                    ..Default::default()
+                },
+                visible: Visible {
+                    is_visible: is_floor_visible(
+                        &map_data,
+                        center,
+                        current_z_level.0,
+                        *render_mode,
+                    ),
+                    is_transparent: true,
+                },
+                ..Default::default()
+            })
+            .insert(Workshop {
+                kind,
+                orders: vec![],
+            })
+            .insert(TilePos(center));
+        info!("placed a {} at {}", kind.name(), center);
+    }
+}
+
+fn update_workshop_sprites(
+    map_data: Res<MapData>,
+    current_z_level: Res<CurrentZLevel>,
+    render_mode: Res<RenderMode>,
+    rotation: Res<ViewRotation>,
+    materials: Res<WorkshopMaterials>,
+    mut query: Query<(
+        &TilePos,
+        &Workshop,
+        &mut Transform,
+        &mut Visible,
+        &mut Handle<ColorMaterial>,
+        &mut Sprite,
+    )>,
+) {
+    if !render_mode.is_changed() && !rotation.is_changed() && !current_z_level.is_changed() {
+        return;
+    }
+    for (pos, workshop, mut transform, mut visible, mut material, mut sprite) in query.iter_mut() {
+        let (new_material, new_sprite, scale) = materials.get(workshop.kind, *render_mode);
+        transform.translation = workshop_transform(pos.0, *render_mode, *rotation);
+        transform.scale = scale;
+        visible.is_visible = is_floor_visible(&map_data, pos.0, current_z_level.0, *render_mode);
+        *material = new_material;
+        *sprite = new_sprite;
+    }
+}
+
+/// Workshops get a craft job for their first order that still needs work and has its inputs,
+/// an unclaimed job is replaced once its order isn't that one anymore
+fn generate_craft_jobs(
+    simulation_time: Res<SimulationTime>,
+    recipes: Res<Recipes>,
+    regions: Res<Regions>,
+    stockpiles: Res<Stockpiles>,
+    item_index: Res<ItemIndex>,
+    mut job_board: ResMut<JobBoard>,
+    workshops: Query<(Entity, &TilePos, &Workshop)>,
+    items: Query<&Item>,
+) {
+    if simulation_time.tick % CRAFT_INTERVAL != 0 {
+        return;
+    }
+    let mut busy = HashSet::default();
+    let mut unclaimed = HashMap::default();
+    for (id, job) in job_board.iter() {
+        if let JobKind::Craft {
+            workshop, recipe, ..
+        } = job.kind
+        {
+            if job.claimed_by.is_some() {
+                busy.insert(workshop);
+            } else {
+                unclaimed.insert(workshop, (*id, recipe));
+            }
+        }
+    }
+    // the inputs found for a workshop aren't counted for the next ones
+    let mut reserved = job_board.reserved_items();
+    let mut stock = None;
+
+    for (entity, pos, workshop) in workshops.iter() {
+        if busy.contains(&entity) {
+            continue;
+        }
+        let order = workshop.orders.iter().find_map(|order| {
+            let recipe = recipes.get(order.recipe)?;
+            if !order.needs_work(&recipes, &mut stock, &items) {
+                return None;
+            }
+            let inputs = find_inputs(
+                recipe,
+                &regions,
+                &stockpiles,
+                &item_index,
+                &items,
+                &reserved,
+                pos.0,
+            )?;
+            Some((order.recipe, recipe.labor, inputs))
+        });
+        let current = unclaimed.get(&entity).copied();
+        if let Some((recipe, _, inputs)) = &order {
+            reserved.extend(inputs.iter().map(|(item, _)| *item));
+            if matches!(current, Some((_, current)) if current == *recipe) {
+                continue;
+            }
+        }
+        if let Some((id, _)) = current {
+            job_board.remove(id);
+        }
+        if let Some((recipe, labor, _)) = order {
+            job_board.add(
+                JobKind::Craft {
+                    workshop: entity,
+                    recipe,
+                    labor,
+                },
+                pos.0,
+            );
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum NewOrderMode {
+    Make,
+    Repeat,
+    UntilStock,
+}
+
+/// Settings used for the orders added from the panel
+struct OrderForm {
+    mode: NewOrderMode,
+    amount: u32,
+}
+
+impl Default for OrderForm {
+    fn default() -> Self {
+        Self {
+            mode: NewOrderMode::Make,
+            amount: 1,
+        }
+    }
+}
+
+impl OrderForm {
+    fn order(&self, recipe: usize) -> WorkOrder {
+        let mode = match self.mode {
+            NewOrderMode::Make => OrderMode::Make(self.amount),
+            NewOrderMode::Repeat => OrderMode::Repeat,
+            NewOrderMode::UntilStock => OrderMode::UntilStock(self.amount),
+        };
+        WorkOrder { recipe, mode }
+    }
+}
+
+fn workshop_panel(
+    mut commands: Commands,
+    egui_context: Res<EguiContext>,
+    recipes: Res<Recipes>,
+    mut form: Local<OrderForm>,
+    mut job_board: ResMut<JobBoard>,
+    mut query: Query<(Entity, &TilePos, &mut Workshop)>,
+) {
+    // reading through `Mut` doesn't mark the workshops as changed
+    let workshops = query
+        .iter_mut()
+        .map(|(entity, pos, workshop)| (entity, pos.0, workshop.kind, workshop.orders.clone()))
+        .collect::<Vec<_>>();
+    if workshops.is_empty() {
+        return;
+    }
+
+    let mut added = None;
+    let mut cancelled = None;
+    let mut removed = None;
+    egui::Window::new("Workshops")
+        .anchor(egui::Align2::RIGHT_CENTER, [0., 0.])
+        .resizable(false)
+        .show(egui_context.ctx(), |ui| {
+            ui.horizontal(|ui| {
+                ui.radio_value(&mut form.mode, NewOrderMode::Make, "Make");
+                ui.radio_value(&mut form.mode, NewOrderMode::Repeat, "Repeat");
+                ui.radio_value(&mut form.mode, NewOrderMode::UntilStock, "Until stock");
+            });
+            if form.mode != NewOrderMode::Repeat {
+                ui.add(egui::Slider::new(&mut form.amount, 1..=MAX_ORDER_AMOUNT).text("amount"));
+            }
+
+            for (entity, pos, kind, orders) in workshops.iter() {
+                ui.separator();
+                ui.horizontal(|ui| {
+                    ui.label(format!(
+                        "{} at x: {} y: {} z: {}",
+                        kind.name(),
+                        pos.x,
+                        pos.y,
+                        pos.z
+                    ));
+                    if ui.small_button("Remove").clicked() {
+                        removed = Some(*entity);
+                    }
+                });
+                for (index, order) in orders.iter().enumerate() {
+                    ui.horizontal(|ui| {
+                        ui.label(order.describe(&recipes));
+                        if ui.small_button("Cancel").clicked() {
+                            cancelled = Some((*entity, index));
+                        }
+                    });
+                }
+                ui.horizontal(|ui| {
+                    for (index, recipe) in recipes.for_workshop(*kind) {
+                        if ui.small_button(format!("+ {}", recipe.name)).clicked() {
+                            added = Some((*entity, index));
+                        }
+                    }
+                });
+            }
+        });
+
+    if let Some((entity, recipe)) = added {
+        if let Ok((_, _, mut workshop)) = query.get_mut(entity) {
+            workshop.orders.push(form.order(recipe));
+        }
+    }
+    if let Some((entity, index)) = cancelled {
+        if let Ok((_, _, mut workshop)) = query.get_mut(entity) {
+            let order = workshop.orders.remove(index);
+            // the job is kept for another order of the same recipe
+            if !workshop
+                .orders
+                .iter()
+                .any(|other| other.recipe == order.recipe)
+            {
+                remove_craft_jobs(&mut job_board, entity, Some(order.recipe));
+            }
+        }
+    }
+    if let Some(entity) = removed {
+        commands.entity(entity).despawn();
+        remove_craft_jobs(&mut job_board, entity, None);
+    }
+}
+
+/// Removes the craft jobs of the workshop, only the ones of the recipe if one is given.
+/// The workers notice their job is gone and drop what they carry
+fn remove_craft_jobs(job_board: &mut JobBoard, entity: Entity, recipe: Option<usize>) {
+    let jobs = job_board
+        .iter()
+        .filter(|(_, job)| match job.kind {
+            JobKind::Craft {
+                workshop,
+                recipe: job_recipe,
+                ..
+            } => workshop == entity && recipe.map_or(true, |recipe| recipe == job_recipe),
+            _ => false,
+        })
+        .map(|(id, _)| *id)
+        .collect::<Vec<_>>();
+    for id in jobs {
+        job_board.remove(id);
+    }
+}
@@ -0,0 +1,64 @@
+use bevy::{math::Vec3Swizzles, prelude::*};
+use bevy_egui::{egui, EguiContext};
+
+use crate::{
+    camera::MainCamera,
+    map::{CurrentZLevel, MapData, RenderMode, ViewRotation, HEIGHT, WIDTH, Z_LEVELS},
+    utils::world_to_tile,
+};
+
+pub struct ZLevelPlugin;
+
+impl Plugin for ZLevelPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app.add_system(z_level_indicator.system());
+    }
+}
+
+/// Slider showing the current z-level and its depth relative to the surface at the center of the
+/// screen
+fn z_level_indicator(
+    egui_context: Res<EguiContext>,
+    map_data: Res<MapData>,
+    render_mode: Res<RenderMode>,
+    rotation: Res<ViewRotation>,
+    mut current_z_level: ResMut<CurrentZLevel>,
+    camera_query: Query<&Transform, With<MainCamera>>,
+) {
+    let camera_transform = camera_query.single().expect("main camera not found");
+    let mut z_level = current_z_level.0;
+
+    let center = world_to_tile(
+        camera_transform.translation.xy(),
+        z_level,
+        *render_mode,
+        *rotation,
+    );
+    let max = Vec2::new((WIDTH - 1) as f32, (HEIGHT - 1) as f32);
+    let surface = map_data
+        .find_top_tile(center.max(Vec2::ZERO).min(max).as_u32(), Z_LEVELS - 1)
+        .map(|surface| surface.z as i32);
+
+    egui::Window::new("Z-level")
+        .anchor(egui::Align2::RIGHT_BOTTOM, [0., 0.])
+        .resizable(false)
+        .show(egui_context.ctx(), |ui| {
+            ui.add(egui::Slider::new(&mut z_level, 0..=Z_LEVELS - 1).vertical());
+            let depth = match surface {
+                Some(surface) if surface > z_level as i32 => {
+                    format!("{} below the surface", surface - z_level as i32)
+                }
+                Some(surface) if surface < z_level as i32 => {
+                    format!("{} above the surface", z_level as i32 - surface)
+                }
+                Some(_) => String::from("surface"),
+                None => String::from("no surface"),
+            };
+            ui.label(depth);
+        });
+
+    // only touch the resource when needed to avoid updating the layers every frame
+    if z_level != current_z_level.0 {
+        current_z_level.0 = z_level;
+    }
+}